@@ -15,6 +15,7 @@ enum Address {
 
 struct Args {
     pub address: Address,
+    pub track_calls: bool,
 }
 
 fn pattern_regex() -> &'static Regex {
@@ -25,9 +26,13 @@ fn pattern_regex() -> &'static Regex {
 impl Args {
     fn new(args: AttributeArgs) -> Result<Self> {
         let mut address = None;
+        let mut track_calls = false;
 
         for arg in args {
             match arg {
+                NestedMeta::Meta(Meta::Path(path)) if path.is_ident("track_calls") => {
+                    track_calls = true;
+                }
                 NestedMeta::Meta(Meta::NameValue(nv)) => {
                     if nv.path.is_ident("pattern") {
                         if address.is_some() {
@@ -90,10 +95,16 @@ impl Args {
 
         Ok(Self {
             address: address.expect("missing `address` attribute"),
+            track_calls,
         })
     }
 }
 
+/// Declares a detour of the function at `address` or found with `pattern`, along with a
+/// `<NAME>_BINDER` to enable it through a `HookLibrary`.
+///
+/// With `track_calls`, calls inside the detour are counted in `<NAME>_IN_FLIGHT`, and disabling
+/// the `HookLibrary` waits for them to return so that the payload can be unloaded safely.
 #[proc_macro_attribute]
 pub fn detour(
     args: proc_macro::TokenStream,
@@ -106,12 +117,14 @@ pub fn detour(
     };
 
     // Extract input
-    let detour = parse_macro_input!(input as ItemFn);
+    let mut detour = parse_macro_input!(input as ItemFn);
     let visibility = detour.vis.clone();
     let signature = detour.sig.clone();
     let function_name = Ident::new(&signature.ident.to_string(), Span::call_site());
     let detour_name = Ident::new(&function_name.to_string().to_uppercase(), Span::call_site());
     let binder_name = Ident::new(&format!("{}_BINDER", detour_name), Span::call_site());
+    let address_name = Ident::new(&format!("{}_ADDRESS", detour_name), Span::call_site());
+    let in_flight_name = Ident::new(&format!("{}_IN_FLIGHT", detour_name), Span::call_site());
     let detour_type = TypeBareFn {
         lifetimes: None,
         unsafety: signature.unsafety,
@@ -147,7 +160,7 @@ pub fn detour(
             );
             quote! {
                 use anyhow::Context;
-                let address = module.scan(#addr_sig).context(#error_string)?;
+                let address = module.scan(#addr_sig).context(#error_string)? as usize;
             }
         }
         Address::Address(address) => quote! {
@@ -155,8 +168,28 @@ pub fn detour(
         },
     };
 
+    // Count calls for as long as they are inside the detour, including any calls it makes to the
+    // original function
+    let (in_flight_static, in_flight) = if args.track_calls {
+        let block = &detour.block;
+        *detour.block = syn::parse_quote!({
+            let _in_flight = #in_flight_name.enter();
+            #block
+        });
+        (
+            quote! {
+                #visibility static #in_flight_name: ::re_utilities::detour_binder::InFlight = ::re_utilities::detour_binder::InFlight::new();
+            },
+            quote! { Some(&#in_flight_name) },
+        )
+    } else {
+        (quote! {}, quote! { None })
+    };
+
     quote! {
+        #in_flight_static
         #visibility static #detour_name: std::sync::OnceLock<::re_utilities::retour::GenericDetour<#detour_type>> = std::sync::OnceLock::new();
+        static #address_name: std::sync::OnceLock<usize> = std::sync::OnceLock::new();
         #visibility static #binder_name: ::re_utilities::detour_binder::CompiletimeDetourBinder = ::re_utilities::detour_binder::CompiletimeDetourBinder {
             enable: &|| {
                 unsafe {
@@ -168,20 +201,93 @@ pub fn detour(
                                 #function_name
                             )?
                         ).expect("detour already bound");
+                        #address_name.set(address).expect("detour already bound");
                     }
+                    ::re_utilities::detour_binder::vacate_detour_target(
+                        *#address_name.get().expect("detour not bound")
+                    )?;
                     #detour_name.get().expect("detour not bound").enable()?;
                 }
                 Ok(())
             },
             disable: &|| {
                 unsafe {
+                    ::re_utilities::detour_binder::vacate_detour_target(
+                        *#address_name.get().expect("detour not bound")
+                    )?;
                     #detour_name.get().expect("detour not bound").disable()?;
                 }
                 Ok(())
             },
+            in_flight: #in_flight,
         };
 
         #detour
     }
     .into()
 }
+
+/// Makes the function the entry point of a payload: it is run on a new thread once the payload
+/// has been loaded, as a DLL or a shared library.
+///
+/// The function must take no arguments and return an `anyhow::Result<()>`. When the payload is
+/// unloaded, anything registered with `re_utilities::payload::on_shutdown` while it ran, such as
+/// singletons and hooks, is torn down in reverse order. See `re_utilities::payload` for details.
+///
+/// On Windows, that teardown runs in `DllMain` under the loader lock; payloads that need to do
+/// more than disable hooks should call `re_utilities::payload::shutdown` before being unloaded.
+///
+/// On Linux, glibc keeps a library loaded once any of its threads have used thread-locals with
+/// destructors, which Rust's standard library does; the teardown then happens when the process
+/// exits instead.
+#[proc_macro_attribute]
+pub fn payload_main(
+    args: proc_macro::TokenStream,
+    input: proc_macro::TokenStream,
+) -> proc_macro::TokenStream {
+    if !args.is_empty() {
+        return Error::new(Span::call_site(), "`payload_main` takes no arguments")
+            .to_compile_error()
+            .into();
+    }
+    let main = parse_macro_input!(input as ItemFn);
+    if !main.sig.inputs.is_empty() {
+        return Error::new_spanned(&main.sig.inputs, "`payload_main` must take no arguments")
+            .to_compile_error()
+            .into();
+    }
+    let main_name = &main.sig.ident;
+
+    quote! {
+        #main
+
+        #[cfg(target_os = "windows")]
+        #[no_mangle]
+        #[allow(non_snake_case)]
+        unsafe extern "system" fn DllMain(
+            module: *mut ::std::ffi::c_void,
+            reason: u32,
+            reserved: *mut ::std::ffi::c_void,
+        ) -> i32 {
+            ::re_utilities::payload::dll_main(module as usize, reason, reserved, #main_name) as i32
+        }
+
+        #[cfg(target_os = "linux")]
+        const _: () = {
+            extern "C" fn payload_init() {
+                ::re_utilities::payload::attach(payload_init as usize, #main_name);
+            }
+            extern "C" fn payload_fini() {
+                ::re_utilities::payload::detach();
+            }
+
+            #[used]
+            #[link_section = ".init_array"]
+            static PAYLOAD_INIT: extern "C" fn() = payload_init;
+            #[used]
+            #[link_section = ".fini_array"]
+            static PAYLOAD_FINI: extern "C" fn() = payload_fini;
+        };
+    }
+    .into()
+}
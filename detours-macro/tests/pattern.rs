@@ -0,0 +1,34 @@
+#![cfg(windows)]
+use std::hint::black_box;
+
+use detours_macro::detour;
+use re_utilities::detour_binder::DetourBinder;
+
+#[inline(never)]
+fn original() -> u32 {
+    black_box(1)
+}
+
+/// Stands in for a `Module`, finding `original` for the pattern it is given.
+struct Scanner;
+impl Scanner {
+    fn scan(&self, pattern: &str) -> anyhow::Result<*mut u8> {
+        assert_eq!(pattern, "DE ? BE EF");
+        Ok(original as *const () as *mut u8)
+    }
+}
+#[allow(non_upper_case_globals)]
+const module: Scanner = Scanner;
+
+#[detour(pattern = "DE ? BE EF")]
+fn replacement() -> u32 {
+    2
+}
+
+#[test]
+fn pattern_detour_binds_to_the_scanned_address() {
+    REPLACEMENT_BINDER.enable().unwrap();
+    assert_eq!(black_box(original as fn() -> u32)(), 2);
+    REPLACEMENT_BINDER.disable().unwrap();
+    assert_eq!(black_box(original as fn() -> u32)(), 1);
+}
@@ -0,0 +1,207 @@
+//! Working out which architecture a binary was built for, so that a payload that cannot be loaded
+//! into a process is refused before anything is written into it. Nothing here depends on the
+//! platform.
+use std::{fmt, fs::File, io::Read, path::Path};
+
+use anyhow::Context;
+
+use crate::pe;
+
+/// How much of a file is read to find its architecture, which covers the headers of any binary
+/// built by a common toolchain.
+const HEADER_SIZE: usize = 0x1000;
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+const ELF_DATA_LITTLE_ENDIAN: u8 = 1;
+const ELF_DATA_BIG_ENDIAN: u8 = 2;
+
+const ELF_MACHINE_386: u16 = 3;
+const ELF_MACHINE_ARM: u16 = 40;
+const ELF_MACHINE_X86_64: u16 = 62;
+const ELF_MACHINE_AARCH64: u16 = 183;
+
+/// The instruction set a process or binary runs on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Architecture {
+    X86,
+    X86_64,
+    Arm,
+    Arm64,
+    /// An architecture this crate does not know about.
+    Unknown,
+}
+impl Architecture {
+    /// The architecture the injector was built for, which the target process and payload must
+    /// share.
+    pub const CURRENT: Architecture = if cfg!(target_arch = "x86_64") {
+        Architecture::X86_64
+    } else if cfg!(target_arch = "x86") {
+        Architecture::X86
+    } else if cfg!(target_arch = "aarch64") {
+        Architecture::Arm64
+    } else if cfg!(target_arch = "arm") {
+        Architecture::Arm
+    } else {
+        Architecture::Unknown
+    };
+
+    /// The architecture of a PE image with the given machine type.
+    pub fn from_pe_machine(machine: u16) -> Architecture {
+        match machine {
+            pe::MACHINE_I386 => Architecture::X86,
+            pe::MACHINE_AMD64 => Architecture::X86_64,
+            pe::MACHINE_ARMNT => Architecture::Arm,
+            pe::MACHINE_ARM64 => Architecture::Arm64,
+            _ => Architecture::Unknown,
+        }
+    }
+
+    /// The architecture of an ELF file with the given `e_machine`.
+    pub fn from_elf_machine(machine: u16) -> Architecture {
+        match machine {
+            ELF_MACHINE_386 => Architecture::X86,
+            ELF_MACHINE_X86_64 => Architecture::X86_64,
+            ELF_MACHINE_ARM => Architecture::Arm,
+            ELF_MACHINE_AARCH64 => Architecture::Arm64,
+            _ => Architecture::Unknown,
+        }
+    }
+
+    /// Reads the architecture from the header of a PE or ELF file, of which `data` must contain
+    /// at least the headers.
+    pub fn of_binary(data: &[u8]) -> anyhow::Result<Architecture> {
+        if data.starts_with(b"MZ") {
+            return Ok(Architecture::from_pe_machine(pe::Pe::parse(data)?.machine));
+        }
+        if data.starts_with(ELF_MAGIC) {
+            let machine: [u8; 2] = data
+                .get(18..20)
+                .context("not an ELF file: truncated header")?
+                .try_into()?;
+            let machine = match data[5] {
+                ELF_DATA_LITTLE_ENDIAN => u16::from_le_bytes(machine),
+                ELF_DATA_BIG_ENDIAN => u16::from_be_bytes(machine),
+                encoding => anyhow::bail!("unknown ELF data encoding {encoding}"),
+            };
+            return Ok(Architecture::from_elf_machine(machine));
+        }
+        anyhow::bail!("not a PE or ELF file")
+    }
+
+    /// Reads the architecture from the header of the PE or ELF file at `path`, reading only the
+    /// start of the file.
+    pub fn of_file(path: &Path) -> anyhow::Result<Architecture> {
+        let read_header = || {
+            let mut file = File::open(path)?;
+            let mut header = [0u8; HEADER_SIZE];
+            let mut len = 0;
+            while len < header.len() {
+                match file.read(&mut header[len..])? {
+                    0 => break,
+                    read => len += read,
+                }
+            }
+            std::io::Result::Ok((header, len))
+        };
+        let (header, len) =
+            read_header().with_context(|| format!("failed to read {}", path.display()))?;
+        Architecture::of_binary(&header[..len])
+            .with_context(|| format!("failed to read the architecture of {}", path.display()))
+    }
+}
+impl fmt::Display for Architecture {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Architecture::X86 => "x86",
+            Architecture::X86_64 => "x86-64",
+            Architecture::Arm => "ARM",
+            Architecture::Arm64 => "ARM64",
+            Architecture::Unknown => "unknown",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The start of an ELF header with the given data encoding and machine.
+    fn elf_header(encoding: u8, machine: [u8; 2]) -> Vec<u8> {
+        let mut header = vec![0u8; 64];
+        header[..4].copy_from_slice(ELF_MAGIC);
+        header[4] = 2;
+        header[5] = encoding;
+        header[18..20].copy_from_slice(&machine);
+        header
+    }
+
+    #[test]
+    fn of_binary_reads_pe_machines() {
+        let fixture_64 = include_bytes!("../tests/fixtures/pe/fixture64.dll");
+        let fixture_32 = include_bytes!("../tests/fixtures/pe/fixture32.dll");
+        assert_eq!(
+            Architecture::of_binary(fixture_64).unwrap(),
+            Architecture::X86_64
+        );
+        assert_eq!(
+            Architecture::of_binary(fixture_32).unwrap(),
+            Architecture::X86
+        );
+        // Only the headers are needed
+        assert_eq!(
+            Architecture::of_binary(&fixture_64[..0x400]).unwrap(),
+            Architecture::X86_64
+        );
+
+        let mut unknown = fixture_64.to_vec();
+        let machine = u32::from_le_bytes(unknown[0x3C..0x40].try_into().unwrap()) as usize + 4;
+        unknown[machine..machine + 2].copy_from_slice(&0x1234u16.to_le_bytes());
+        assert_eq!(
+            Architecture::of_binary(&unknown).unwrap(),
+            Architecture::Unknown
+        );
+        assert!(Architecture::of_binary(&fixture_64[..0x40]).is_err());
+    }
+
+    #[test]
+    fn of_binary_reads_elf_machines_in_either_byte_order() {
+        let little = |machine: u16| elf_header(ELF_DATA_LITTLE_ENDIAN, machine.to_le_bytes());
+        let big = |machine: u16| elf_header(ELF_DATA_BIG_ENDIAN, machine.to_be_bytes());
+        let of_binary = |data: Vec<u8>| Architecture::of_binary(&data).unwrap();
+
+        assert_eq!(of_binary(little(ELF_MACHINE_386)), Architecture::X86);
+        assert_eq!(of_binary(little(ELF_MACHINE_X86_64)), Architecture::X86_64);
+        assert_eq!(of_binary(little(ELF_MACHINE_ARM)), Architecture::Arm);
+        assert_eq!(of_binary(little(ELF_MACHINE_AARCH64)), Architecture::Arm64);
+        assert_eq!(of_binary(big(ELF_MACHINE_ARM)), Architecture::Arm);
+        assert_eq!(of_binary(big(ELF_MACHINE_AARCH64)), Architecture::Arm64);
+        // PowerPC
+        assert_eq!(of_binary(big(20)), Architecture::Unknown);
+        assert_eq!(of_binary(little(0xFFFF)), Architecture::Unknown);
+    }
+
+    #[test]
+    fn of_binary_refuses_truncated_and_unknown_files() {
+        let header = elf_header(ELF_DATA_LITTLE_ENDIAN, ELF_MACHINE_X86_64.to_le_bytes());
+        assert!(Architecture::of_binary(&header[..19]).is_err());
+        assert!(Architecture::of_binary(&header[..4]).is_err());
+        assert!(Architecture::of_binary(&elf_header(3, [62, 0])).is_err());
+        assert!(Architecture::of_binary(b"").is_err());
+        assert!(Architecture::of_binary(b"MZ").is_err());
+        assert!(Architecture::of_binary(b"#!/bin/sh\n").is_err());
+    }
+
+    #[test]
+    fn of_file_reads_the_start_of_the_file() {
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/pe");
+        let fixture = fixtures.join("fixture32.dll");
+        assert_eq!(Architecture::of_file(&fixture).unwrap(), Architecture::X86);
+        // The test binary is larger than the header that is read
+        let current_exe = std::env::current_exe().unwrap();
+        assert_eq!(
+            Architecture::of_file(&current_exe).unwrap(),
+            Architecture::CURRENT
+        );
+        assert!(Architecture::of_file(&fixture.with_extension("missing")).is_err());
+    }
+}
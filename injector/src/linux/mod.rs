@@ -0,0 +1,276 @@
+use std::{
+    ffi::{CStr, CString},
+    fmt, fs,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::Context;
+
+use crate::{payload, Architecture};
+
+mod ptrace;
+pub(crate) mod query;
+
+use ptrace::Tracee;
+
+/// The ways in which loading or unloading the payload in the target process can fail.
+///
+/// [`inject`] and [`eject`] return these wrapped in an [`anyhow::Error`]; use `downcast_ref` to
+/// tell them apart from other failures.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InjectError {
+    /// `dlopen` returned null in the target process, with the message from `dlerror`.
+    DlopenFailed(String),
+    /// `dlclose` failed in the target process, with the message from `dlerror`.
+    DlcloseFailed(String),
+    /// The target process exited while the payload was loading or unloading.
+    ProcessExited { exit_code: i32 },
+    /// The target process was killed by a signal while the payload was loading or unloading,
+    /// such as when one of the payload's constructors crashes.
+    ProcessKilled { signal: i32 },
+    /// The payload or the target process was built for a different architecture than the
+    /// injector. Nothing was written into the target process.
+    ArchitectureMismatch {
+        injector: Architecture,
+        target: Architecture,
+        payload: Architecture,
+    },
+}
+impl fmt::Display for InjectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InjectError::DlopenFailed(message) => {
+                write!(f, "dlopen failed in the target process: {message}")
+            }
+            InjectError::DlcloseFailed(message) => {
+                write!(f, "dlclose failed in the target process: {message}")
+            }
+            InjectError::ProcessExited { exit_code } => write!(
+                f,
+                "the target process exited with code {exit_code} before the payload was loaded or unloaded"
+            ),
+            InjectError::ProcessKilled { signal } => write!(
+                f,
+                "the target process was killed by signal {signal} before the payload was loaded or unloaded"
+            ),
+            InjectError::ArchitectureMismatch {
+                injector,
+                target,
+                payload,
+            } => write!(
+                f,
+                "the payload ({payload}), target process ({target}) and injector ({injector}) must have the same architecture"
+            ),
+        }
+    }
+}
+impl std::error::Error for InjectError {}
+
+/// Injects a shared library into a process by calling `dlopen` in it, returning the handle that
+/// `dlopen` returned.
+///
+/// The library is loaded from a copy named `<stem>_loaded.<ext>` next to it, so that it can be
+/// rebuilt while the process is running. See [`reload`] to load a rebuilt library.
+///
+/// The process's main thread is stopped under ptrace and made to call `dlopen`, which requires
+/// permission to ptrace the process and for it to have loaded the same `libc` as the injector.
+/// If the main thread was stopped while holding a lock that `dlopen` needs, such as in the middle
+/// of `malloc`, this will not return. If the payload fails to load, or it or the process were
+/// built for a different architecture than the injector, the error will be an [`InjectError`].
+pub fn inject(pid: u32, payload_path: &Path) -> anyhow::Result<usize> {
+    check_architecture(pid, Architecture::of_file(payload_path)?)?;
+    let injected_payload_path = payload::copy(payload_path, false)?;
+    Loader::attach(pid)?.open(&injected_payload_path, libc::RTLD_NOW)
+}
+
+/// Unloads a library from a process by calling `dlclose` on its handle there, in the same way
+/// that [`inject`] calls `dlopen`. A library that was loaded more than once stays loaded until
+/// it has been ejected as many times, and libraries that have been marked as not deletable are
+/// never unloaded.
+///
+/// If `dlclose` fails, the error will be an [`InjectError`].
+pub fn eject(pid: u32, module: usize) -> anyhow::Result<()> {
+    Loader::attach(pid)?.close(module)
+}
+
+/// Ejects any copies of a library that were injected into a process, then injects it again
+/// from a fresh copy, returning its new handle.
+///
+/// Each reload loads the library from a copy with a unique name, as `dlopen` returns the library
+/// that is already loaded when given the same path, and copies may stay loaded. Copies left
+/// behind by earlier reloads are deleted.
+pub fn reload(pid: u32, payload_path: &Path) -> anyhow::Result<usize> {
+    check_architecture(pid, Architecture::of_file(payload_path)?)?;
+    let mut loader = Loader::attach(pid)?;
+    for (_, path) in mapped_files(pid)? {
+        let is_copy = path
+            .file_name()
+            .is_some_and(|name| payload::is_copy(payload_path, &name.to_string_lossy()));
+        if !is_copy {
+            continue;
+        }
+
+        // Opening an already loaded library adds a reference to it, which is dropped again
+        // straight away
+        let Ok(module) = loader.open(&path, libc::RTLD_NOW | libc::RTLD_NOLOAD) else {
+            continue;
+        };
+        loader.close(module)?;
+        loader
+            .close(module)
+            .with_context(|| format!("failed to eject {}", path.display()))?;
+    }
+
+    let injected_payload_path = payload::copy(payload_path, true)?;
+    loader.open(&injected_payload_path, libc::RTLD_NOW)
+}
+
+/// Returns the architecture of a process, read from the header of its executable.
+pub fn process_architecture(pid: u32) -> anyhow::Result<Architecture> {
+    Architecture::of_file(Path::new(&format!("/proc/{pid}/exe")))
+        .with_context(|| format!("failed to get the architecture of process {pid}"))
+}
+
+/// Fails with [`InjectError::ArchitectureMismatch`] unless the process and payload have the same
+/// architecture as the injector.
+fn check_architecture(pid: u32, payload: Architecture) -> anyhow::Result<()> {
+    let target = process_architecture(pid)?;
+    if target != Architecture::CURRENT || payload != Architecture::CURRENT {
+        return Err(InjectError::ArchitectureMismatch {
+            injector: Architecture::CURRENT,
+            target,
+            payload,
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// Waits for a process to map a library whose file name matches `pattern`, and returns the lowest
+/// address it is mapped at, or `None` if it has not been mapped after `timeout`. Waits forever if
+/// `timeout` is `None`. The pattern is matched as in [`crate::ProcessQuery::with_module`].
+///
+/// Fails if the process exits first.
+pub fn wait_for_module(
+    pid: u32,
+    pattern: &str,
+    timeout: Option<Duration>,
+) -> anyhow::Result<Option<usize>> {
+    crate::query::poll(timeout, || {
+        let files = mapped_files(pid)
+            .with_context(|| format!("process {pid} exited before loading {pattern}"))?;
+        Ok(files
+            .into_iter()
+            .find(|(_, path)| {
+                path.file_name().is_some_and(|name| {
+                    crate::query::glob_matches(pattern, &name.to_string_lossy())
+                })
+            })
+            .map(|(base, _)| base))
+    })
+}
+
+/// Calls the dynamic loader's functions in a traced process.
+struct Loader {
+    tracee: Tracee,
+    dlopen: usize,
+    dlclose: usize,
+    dlerror: usize,
+}
+
+impl Loader {
+    fn attach(pid: u32) -> anyhow::Result<Loader> {
+        Ok(Loader {
+            dlopen: remote_symbol(pid, c"dlopen")?,
+            dlclose: remote_symbol(pid, c"dlclose")?,
+            dlerror: remote_symbol(pid, c"dlerror")?,
+            tracee: Tracee::attach(pid)?,
+        })
+    }
+
+    fn open(&mut self, path: &Path, flags: i32) -> anyhow::Result<usize> {
+        let path = CString::new(path.as_os_str().as_bytes())?;
+        let path = self.tracee.push(path.as_bytes_with_nul())?;
+        let module = self.tracee.call(self.dlopen, &[path, flags as usize])?;
+        if module == 0 {
+            return Err(InjectError::DlopenFailed(self.error()?).into());
+        }
+        Ok(module)
+    }
+
+    fn close(&mut self, module: usize) -> anyhow::Result<()> {
+        if self.tracee.call(self.dlclose, &[module])? as i32 != 0 {
+            return Err(InjectError::DlcloseFailed(self.error()?).into());
+        }
+        Ok(())
+    }
+
+    fn error(&mut self) -> anyhow::Result<String> {
+        match self.tracee.call(self.dlerror, &[])? {
+            0 => Ok("unknown error".to_owned()),
+            message => self.tracee.read_c_string(message),
+        }
+    }
+}
+
+/// Finds the address of a function in another process, from where the library that provides it
+/// in this process is loaded in that process.
+fn remote_symbol(pid: u32, name: &CStr) -> anyhow::Result<usize> {
+    let local = unsafe { libc::dlsym(libc::RTLD_DEFAULT, name.as_ptr()) };
+    let mut info: libc::Dl_info = unsafe { std::mem::zeroed() };
+    if local.is_null() || unsafe { libc::dladdr(local, &mut info) } == 0 {
+        anyhow::bail!("failed to find {name:?} in the injector");
+    }
+    let library = PathBuf::from(unsafe { CStr::from_ptr(info.dli_fname) }.to_str()?);
+    let library = fs::canonicalize(&library).unwrap_or(library);
+
+    // The target may see the library at another path, such as when it runs in a container
+    let files = mapped_files(pid)?;
+    let base = files
+        .iter()
+        .find(|(_, path)| **path == library)
+        .or_else(|| {
+            files
+                .iter()
+                .find(|(_, path)| path.file_name() == library.file_name())
+        })
+        .map(|(base, _)| *base)
+        .with_context(|| {
+            format!(
+                "process {pid} has not loaded {}, which provides {name:?}",
+                library.display()
+            )
+        })?;
+    Ok(base + (local as usize - info.dli_fbase as usize))
+}
+
+/// Returns the files mapped into a process along with the lowest address each is mapped at, in
+/// address order.
+fn mapped_files(pid: u32) -> anyhow::Result<Vec<(usize, PathBuf)>> {
+    let maps = fs::read_to_string(format!("/proc/{pid}/maps"))
+        .with_context(|| format!("failed to read memory map of process {pid}"))?;
+
+    let mut files: Vec<(usize, PathBuf)> = vec![];
+    for line in maps.lines() {
+        // address perms offset dev inode path
+        let mut fields = line.splitn(6, ' ');
+        let start = fields
+            .next()
+            .and_then(|range| range.split('-').next())
+            .and_then(|start| usize::from_str_radix(start, 16).ok())
+            .with_context(|| format!("malformed memory map line: {line}"))?;
+        let Some(path) = fields.nth(4).map(str::trim_start) else {
+            continue;
+        };
+        if !path.starts_with('/') {
+            continue;
+        }
+        let path = PathBuf::from(path.strip_suffix(" (deleted)").unwrap_or(path));
+        if !files.iter().any(|(_, p)| *p == path) {
+            files.push((start, path));
+        }
+    }
+    Ok(files)
+}
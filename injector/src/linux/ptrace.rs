@@ -0,0 +1,209 @@
+use std::{
+    ffi::c_void,
+    fs::{File, OpenOptions},
+    io, mem,
+    os::unix::fs::FileExt,
+    ptr,
+};
+
+use anyhow::Context;
+
+use super::InjectError;
+
+/// The area below the stack pointer that the x86-64 System V ABI lets functions use freely.
+const RED_ZONE: u64 = 128;
+
+/// A process whose main thread is stopped under ptrace, so that functions can be called on it.
+///
+/// The thread's registers are restored and the process is detached when this is dropped.
+/// Other threads keep running throughout.
+pub(crate) struct Tracee {
+    pid: libc::pid_t,
+    mem: File,
+    saved: libc::user_regs_struct,
+    // Where the next data pushed onto the stack will end.
+    stack_top: u64,
+    // A signal that arrived while attaching, to be delivered when detaching.
+    pending_signal: i32,
+    exited: bool,
+}
+
+impl Tracee {
+    /// Attaches to the process and stops its main thread. This requires permission to ptrace
+    /// the process, which may be limited to its ancestors by `kernel.yama.ptrace_scope`.
+    pub fn attach(pid: u32) -> anyhow::Result<Tracee> {
+        let pid = pid as libc::pid_t;
+        let mem = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(format!("/proc/{pid}/mem"))
+            .with_context(|| format!("failed to open memory of process {pid}"))?;
+        ptrace(libc::PTRACE_ATTACH, pid, 0)
+            .with_context(|| format!("failed to attach to process {pid}"))?;
+
+        let mut tracee = Tracee {
+            pid,
+            mem,
+            saved: unsafe { mem::zeroed() },
+            stack_top: 0,
+            pending_signal: 0,
+            exited: false,
+        };
+        // The thread may stop for another signal before the one sent by attaching.
+        loop {
+            match tracee.wait()? {
+                libc::SIGSTOP => break,
+                signal => {
+                    tracee.pending_signal = signal;
+                    ptrace(libc::PTRACE_CONT, pid, 0)?;
+                }
+            }
+        }
+
+        tracee.saved = tracee.registers()?;
+        tracee.stack_top = tracee.saved.rsp - RED_ZONE;
+        Ok(tracee)
+    }
+
+    /// Copies `data` onto the thread's stack, below anything it was using, and returns its
+    /// address. It stays there until the process is detached.
+    pub fn push(&mut self, data: &[u8]) -> anyhow::Result<usize> {
+        self.stack_top = (self.stack_top - data.len() as u64) & !0xF;
+        self.write(self.stack_top as usize, data)?;
+        Ok(self.stack_top as usize)
+    }
+
+    /// Calls `function` with up to six integer or pointer arguments on the stopped thread, and
+    /// returns the value it returns.
+    pub fn call(&mut self, function: usize, args: &[usize]) -> anyhow::Result<usize> {
+        assert!(args.len() <= 6, "too many arguments");
+
+        // Return to address 0, so that the thread faults once the function returns
+        let rsp = (self.stack_top & !0xF) - mem::size_of::<u64>() as u64;
+        self.write(rsp as usize, &0u64.to_ne_bytes())?;
+
+        let mut regs = self.saved;
+        regs.rip = function as u64;
+        regs.rsp = rsp;
+        regs.rax = 0;
+        // Stop the kernel from restarting a system call that the thread was stopped in
+        regs.orig_rax = u64::MAX;
+        let registers = [
+            &mut regs.rdi,
+            &mut regs.rsi,
+            &mut regs.rdx,
+            &mut regs.rcx,
+            &mut regs.r8,
+            &mut regs.r9,
+        ];
+        for (register, arg) in registers.into_iter().zip(args) {
+            *register = *arg as u64;
+        }
+        self.set_registers(&regs)?;
+
+        let mut signal = 0;
+        loop {
+            ptrace(libc::PTRACE_CONT, self.pid, signal as usize)?;
+            signal = match self.wait()? {
+                libc::SIGSEGV => {
+                    let regs = self.registers()?;
+                    if regs.rip == 0 {
+                        return Ok(regs.rax as usize);
+                    }
+                    // The function crashed, which the process has to handle
+                    libc::SIGSEGV
+                }
+                // Attaching already stopped the thread, so further stops are not passed on
+                libc::SIGSTOP => 0,
+                signal => signal,
+            };
+        }
+    }
+
+    /// Reads the NUL-terminated string at `address`, up to a limit.
+    pub fn read_c_string(&self, address: usize) -> anyhow::Result<String> {
+        let mut buffer = vec![0u8; 4096];
+        let read = self
+            .mem
+            .read_at(&mut buffer, address as u64)
+            .with_context(|| format!("failed to read string at {address:#x}"))?;
+        buffer.truncate(read);
+        if let Some(end) = buffer.iter().position(|b| *b == 0) {
+            buffer.truncate(end);
+        }
+        Ok(String::from_utf8_lossy(&buffer).into_owned())
+    }
+
+    fn write(&self, address: usize, data: &[u8]) -> anyhow::Result<()> {
+        self.mem
+            .write_all_at(data, address as u64)
+            .with_context(|| format!("failed to write {} bytes at {address:#x}", data.len()))
+    }
+
+    fn registers(&self) -> anyhow::Result<libc::user_regs_struct> {
+        let mut regs: libc::user_regs_struct = unsafe { mem::zeroed() };
+        ptrace(
+            libc::PTRACE_GETREGS,
+            self.pid,
+            &mut regs as *mut libc::user_regs_struct as usize,
+        )
+        .context("failed to get registers")?;
+        Ok(regs)
+    }
+
+    fn set_registers(&self, regs: &libc::user_regs_struct) -> anyhow::Result<()> {
+        ptrace(
+            libc::PTRACE_SETREGS,
+            self.pid,
+            regs as *const libc::user_regs_struct as usize,
+        )
+        .context("failed to set registers")
+    }
+
+    /// Waits for the thread to stop, returning the signal it stopped for.
+    fn wait(&mut self) -> anyhow::Result<i32> {
+        let mut status = 0;
+        if unsafe { libc::waitpid(self.pid, &mut status, libc::__WALL) } == -1 {
+            return Err(io::Error::last_os_error())
+                .with_context(|| format!("failed to wait for process {}", self.pid));
+        }
+
+        if libc::WIFEXITED(status) {
+            self.exited = true;
+            return Err(InjectError::ProcessExited {
+                exit_code: libc::WEXITSTATUS(status),
+            }
+            .into());
+        }
+        if libc::WIFSIGNALED(status) {
+            self.exited = true;
+            return Err(InjectError::ProcessKilled {
+                signal: libc::WTERMSIG(status),
+            }
+            .into());
+        }
+        Ok(libc::WSTOPSIG(status))
+    }
+}
+
+impl Drop for Tracee {
+    fn drop(&mut self) {
+        if self.exited {
+            return;
+        }
+        // The registers are only saved once attaching has finished.
+        if self.saved.rsp != 0 {
+            let _ = self.set_registers(&self.saved);
+        }
+        let _ = ptrace(libc::PTRACE_DETACH, self.pid, self.pending_signal as usize);
+    }
+}
+
+fn ptrace(request: libc::c_uint, pid: libc::pid_t, data: usize) -> anyhow::Result<()> {
+    let result =
+        unsafe { libc::ptrace(request, pid, ptr::null_mut::<c_void>(), data as *mut c_void) };
+    if result == -1 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(())
+}
@@ -0,0 +1,77 @@
+use std::{collections::HashMap, fs, fs::OpenOptions, path::PathBuf};
+
+use anyhow::Context;
+
+use super::mapped_files;
+use crate::query::ProcessEntry;
+
+/// The ID of a process that was checked to be accessible to the injector, which is what
+/// [`crate::inject`] takes on Linux.
+pub type ProcessHandle = u32;
+
+pub(crate) fn processes() -> anyhow::Result<Vec<ProcessEntry>> {
+    let mut processes = vec![];
+    for entry in fs::read_dir("/proc").context("failed to list processes")? {
+        let Some(pid) = entry?
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse().ok())
+        else {
+            continue;
+        };
+        // The process may have exited since it was listed
+        let Ok(stat) = fs::read_to_string(format!("/proc/{pid}/stat")) else {
+            continue;
+        };
+        // pid (comm) state ppid ..., where comm may itself contain spaces and parentheses
+        let Some((comm, rest)) = stat
+            .split_once('(')
+            .and_then(|(_, rest)| rest.rsplit_once(')'))
+        else {
+            continue;
+        };
+        let parent_pid = rest
+            .split_whitespace()
+            .nth(1)
+            .and_then(|ppid| ppid.parse().ok())
+            .unwrap_or(0);
+
+        // The command name is truncated, so the executable's name is used where possible
+        let name = executable_path(pid)
+            .ok()
+            .and_then(|path| Some(path.file_name()?.to_string_lossy().into_owned()))
+            .unwrap_or_else(|| comm.to_owned());
+        processes.push(ProcessEntry {
+            pid,
+            parent_pid,
+            name,
+        });
+    }
+    Ok(processes)
+}
+
+pub(crate) fn executable_path(pid: u32) -> anyhow::Result<PathBuf> {
+    fs::read_link(format!("/proc/{pid}/exe"))
+        .with_context(|| format!("failed to get executable of process {pid}"))
+}
+
+pub(crate) fn module_names(pid: u32) -> anyhow::Result<Vec<String>> {
+    Ok(mapped_files(pid)?
+        .into_iter()
+        .filter_map(|(_, path)| Some(path.file_name()?.to_string_lossy().into_owned()))
+        .collect())
+}
+
+pub(crate) fn window_titles() -> anyhow::Result<HashMap<u32, Vec<String>>> {
+    anyhow::bail!("matching processes by window title is not supported on Linux")
+}
+
+/// Checks that the injector can write to the process's memory, as injecting requires.
+pub(crate) fn open(pid: u32) -> anyhow::Result<ProcessHandle> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(format!("/proc/{pid}/mem"))
+        .with_context(|| format!("failed to open memory of process {pid}"))?;
+    Ok(pid)
+}
@@ -0,0 +1,94 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+
+fn decompose_filename(path: &Path) -> anyhow::Result<(String, String)> {
+    let decompose = |filename: &Path| {
+        Some((
+            filename.file_stem()?.to_str()?.to_owned(),
+            filename.extension()?.to_str()?.to_owned(),
+        ))
+    };
+    decompose(path).context("failed to decompose filename")
+}
+
+/// Copies a payload next to itself and returns the absolute path of the copy, so that the
+/// payload can be rebuilt while the copy is loaded.
+///
+/// The copy is named `<stem>_loaded.<ext>`, and is only replaced when the payload has changed.
+/// With `unique`, it is instead named `<stem>_loaded_<timestamp>.<ext>` so that it can be loaded
+/// while earlier copies still are; any earlier copies are deleted where possible.
+pub(crate) fn copy(payload_path: &Path, unique: bool) -> anyhow::Result<PathBuf> {
+    let (stem, extension) = decompose_filename(payload_path)?;
+
+    let injected_payload_path = if unique {
+        // Copies that are still loaded cannot always be deleted, so failures are ignored
+        let directory = payload_path
+            .parent()
+            .filter(|p| !p.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        for entry in fs::read_dir(directory)?.flatten() {
+            if is_copy(payload_path, &entry.file_name().to_string_lossy()) {
+                let _ = fs::remove_file(entry.path());
+            }
+        }
+
+        let mut timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+        loop {
+            let path =
+                payload_path.with_file_name(format!("{stem}_loaded_{timestamp}.{extension}"));
+            if !path.exists() {
+                break path;
+            }
+            timestamp += 1;
+        }
+    } else {
+        payload_path.with_file_name(format!("{stem}_loaded.{extension}"))
+    };
+
+    if !injected_payload_path.exists()
+        || fs::read(payload_path)? != fs::read(&injected_payload_path)?
+    {
+        fs::copy(payload_path, &injected_payload_path)?;
+    }
+    dunce::canonicalize(&injected_payload_path).with_context(|| {
+        format!(
+            "failed to get absolute path of {}",
+            injected_payload_path.display()
+        )
+    })
+}
+
+/// Whether `filename` is that of a copy of the payload made by [`copy`].
+pub(crate) fn is_copy(payload_path: &Path, filename: &str) -> bool {
+    let Ok((stem, extension)) = decompose_filename(payload_path) else {
+        return false;
+    };
+    let (filename, prefix, suffix) = (
+        filename.to_owned(),
+        format!("{stem}_loaded"),
+        format!(".{extension}"),
+    );
+    // Windows filenames are case-insensitive
+    #[cfg(target_os = "windows")]
+    let (filename, prefix, suffix) = (
+        filename.to_lowercase(),
+        prefix.to_lowercase(),
+        suffix.to_lowercase(),
+    );
+
+    let Some(rest) = filename
+        .strip_prefix(&prefix)
+        .and_then(|rest| rest.strip_suffix(&suffix))
+    else {
+        return false;
+    };
+    rest.is_empty()
+        || rest.strip_prefix('_').is_some_and(|timestamp| {
+            !timestamp.is_empty() && timestamp.bytes().all(|b| b.is_ascii_digit())
+        })
+}
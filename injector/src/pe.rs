@@ -0,0 +1,782 @@
+//! Reading and laying out PE images, so that a DLL can be mapped into a process without the
+//! system loader. Nothing here depends on Windows.
+//!
+//! A [`Pe`] is parsed from a DLL's file, which is then [mapped](Pe::map) into an image laid out
+//! as it will be in memory. The other functions work on that image, as the data they read is
+//! addressed by its offset in memory (its RVA).
+use anyhow::Context;
+
+/// The machine type of an x86 image.
+pub const MACHINE_I386: u16 = 0x14C;
+/// The machine type of an x86-64 image.
+pub const MACHINE_AMD64: u16 = 0x8664;
+/// The machine type of a 32-bit ARM (Thumb-2) image.
+pub const MACHINE_ARMNT: u16 = 0x1C4;
+/// The machine type of an ARM64 image.
+pub const MACHINE_ARM64: u16 = 0xAA64;
+
+const IMAGE_FILE_DLL: u16 = 0x2000;
+const MAGIC_PE32: u16 = 0x10B;
+const MAGIC_PE32_PLUS: u16 = 0x20B;
+
+const SECTION_EXECUTE: u32 = 0x2000_0000;
+const SECTION_READ: u32 = 0x4000_0000;
+const SECTION_WRITE: u32 = 0x8000_0000;
+
+const RELOCATION_ABSOLUTE: u16 = 0;
+const RELOCATION_HIGHLOW: u16 = 3;
+const RELOCATION_DIR64: u16 = 10;
+
+/// The index of each data directory in the optional header.
+pub mod directory {
+    pub const EXPORT: usize = 0;
+    pub const IMPORT: usize = 1;
+    pub const EXCEPTION: usize = 3;
+    pub const BASE_RELOCATION: usize = 5;
+    pub const TLS: usize = 9;
+}
+
+/// The headers of a PE image.
+#[derive(Debug, Clone)]
+pub struct Pe<'a> {
+    data: &'a [u8],
+    pub machine: u16,
+    pub is_dll: bool,
+    /// Whether this is a PE32+ image, which uses 64-bit addresses.
+    pub is_64: bool,
+    pub entry_point: u32,
+    pub size_of_image: u32,
+    pub size_of_headers: u32,
+    pub sections: Vec<Section>,
+    pub data_directories: Vec<DataDirectory>,
+    // Where the image base is stored, which changes when the image is relocated
+    image_base_offset: usize,
+}
+
+/// A section of a PE image.
+#[derive(Debug, Clone)]
+pub struct Section {
+    pub name: String,
+    pub virtual_address: u32,
+    pub virtual_size: u32,
+    pub raw_offset: u32,
+    pub raw_size: u32,
+    pub characteristics: u32,
+}
+impl Section {
+    pub fn is_readable(&self) -> bool {
+        self.characteristics & SECTION_READ != 0
+    }
+    pub fn is_writable(&self) -> bool {
+        self.characteristics & SECTION_WRITE != 0
+    }
+    pub fn is_executable(&self) -> bool {
+        self.characteristics & SECTION_EXECUTE != 0
+    }
+    /// The number of bytes the section takes up in the image.
+    pub fn mapped_size(&self) -> u32 {
+        self.virtual_size.max(self.raw_size)
+    }
+}
+
+/// The location of a data directory, such as the import table, in the image.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DataDirectory {
+    pub rva: u32,
+    pub size: u32,
+}
+impl DataDirectory {
+    pub fn is_empty(&self) -> bool {
+        self.rva == 0 || self.size == 0
+    }
+    pub fn contains(&self, rva: u32) -> bool {
+        (self.rva..self.rva.saturating_add(self.size)).contains(&rva)
+    }
+}
+
+/// How a function is imported from or exported by a module.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ImportName {
+    Name(String),
+    Ordinal(u16),
+}
+impl std::fmt::Display for ImportName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ImportName::Name(name) => write!(f, "{name}"),
+            ImportName::Ordinal(ordinal) => write!(f, "#{ordinal}"),
+        }
+    }
+}
+
+/// A function the image imports, and where in the image its address is to be written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Import {
+    pub module: String,
+    pub name: ImportName,
+    pub address_rva: u32,
+}
+
+/// What an exported function resolves to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Export {
+    /// The function is at this RVA in the module.
+    Rva(u32),
+    /// The function is exported by another module, as `<module>.<function>` or
+    /// `<module>.#<ordinal>`. See [`parse_forwarder`].
+    Forwarder(String),
+}
+
+/// The image's thread-local storage directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tls {
+    /// The RVAs of the callbacks to run when the image is loaded, in order.
+    pub callbacks: Vec<u32>,
+    /// Whether the image has static thread-local data, which the loader sets up for every
+    /// thread.
+    pub has_data: bool,
+}
+
+impl<'a> Pe<'a> {
+    /// Parses the headers at the start of `data`, which may be a whole file or just the headers
+    /// of an image.
+    pub fn parse(data: &'a [u8]) -> anyhow::Result<Pe<'a>> {
+        if data.get(..2) != Some(b"MZ") {
+            anyhow::bail!("not a PE image: missing DOS header");
+        }
+        let nt = read_u32(data, 0x3C)? as usize;
+        if data.get(nt..nt + 4) != Some(b"PE\0\0") {
+            anyhow::bail!("not a PE image: missing PE signature");
+        }
+
+        let file_header = nt + 4;
+        let machine = read_u16(data, file_header)?;
+        let section_count = read_u16(data, file_header + 2)? as usize;
+        let optional_header_size = read_u16(data, file_header + 16)? as usize;
+        let characteristics = read_u16(data, file_header + 18)?;
+
+        let optional = file_header + 20;
+        let (is_64, image_base_offset, directory_count_offset) = match read_u16(data, optional)? {
+            MAGIC_PE32 => (false, optional + 28, optional + 92),
+            MAGIC_PE32_PLUS => (true, optional + 24, optional + 108),
+            magic => anyhow::bail!("unknown optional header magic {magic:#x}"),
+        };
+        let directory_count = read_u32(data, directory_count_offset)? as usize;
+        let data_directories = (0..directory_count.min(16))
+            .map(|index| {
+                let offset = directory_count_offset + 4 + index * 8;
+                Ok(DataDirectory {
+                    rva: read_u32(data, offset)?,
+                    size: read_u32(data, offset + 4)?,
+                })
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        let section_table = optional + optional_header_size;
+        let sections = (0..section_count)
+            .map(|index| {
+                let offset = section_table + index * 40;
+                let name = read_bytes(data, offset, 8)?;
+                let name_len = name.iter().position(|b| *b == 0).unwrap_or(name.len());
+                Ok(Section {
+                    name: String::from_utf8_lossy(&name[..name_len]).into_owned(),
+                    virtual_size: read_u32(data, offset + 8)?,
+                    virtual_address: read_u32(data, offset + 12)?,
+                    raw_size: read_u32(data, offset + 16)?,
+                    raw_offset: read_u32(data, offset + 20)?,
+                    characteristics: read_u32(data, offset + 36)?,
+                })
+            })
+            .collect::<anyhow::Result<_>>()
+            .context("section table is truncated")?;
+
+        Ok(Pe {
+            data,
+            machine,
+            is_dll: characteristics & IMAGE_FILE_DLL != 0,
+            is_64,
+            entry_point: read_u32(data, optional + 16)?,
+            size_of_image: read_u32(data, optional + 56)?,
+            size_of_headers: read_u32(data, optional + 60)?,
+            sections,
+            data_directories,
+            image_base_offset,
+        })
+    }
+
+    /// The data directory at `index`, which is empty if the image does not have it. See
+    /// [`directory`] for the indices.
+    pub fn data_directory(&self, index: usize) -> DataDirectory {
+        self.data_directories
+            .get(index)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// The size of an address in the image.
+    pub fn pointer_size(&self) -> usize {
+        if self.is_64 {
+            8
+        } else {
+            4
+        }
+    }
+
+    /// The address the image was linked to be loaded at, as recorded in `image`.
+    pub fn image_base(&self, image: &[u8]) -> anyhow::Result<u64> {
+        read_pointer(image, self.image_base_offset, self.is_64)
+    }
+
+    /// Lays out the image as it will be in memory: the headers, followed by each section at its
+    /// RVA, with anything not in the file zeroed.
+    pub fn map(&self) -> anyhow::Result<Vec<u8>> {
+        let mut image = vec![0u8; self.size_of_image as usize];
+        let headers = (self.size_of_headers as usize).min(self.data.len());
+        image
+            .get_mut(..headers)
+            .context("headers do not fit in the image")?
+            .copy_from_slice(&self.data[..headers]);
+
+        for section in &self.sections {
+            let size = section.raw_size as usize;
+            if size == 0 {
+                continue;
+            }
+            let raw = read_bytes(self.data, section.raw_offset as usize, size)
+                .with_context(|| format!("section {} is truncated", section.name))?;
+            let start = section.virtual_address as usize;
+            image
+                .get_mut(start..start + size)
+                .with_context(|| format!("section {} does not fit in the image", section.name))?
+                .copy_from_slice(raw);
+        }
+        Ok(image)
+    }
+
+    /// Applies the image's base relocations to move it to `base`, and records the new base in
+    /// its headers.
+    pub fn relocate(&self, image: &mut [u8], base: u64) -> anyhow::Result<()> {
+        let delta = base.wrapping_sub(self.image_base(image)?);
+        let relocations = self.data_directory(directory::BASE_RELOCATION);
+        if delta != 0 {
+            if relocations.is_empty() {
+                anyhow::bail!("the image cannot be moved as it has no relocations");
+            }
+            let mut block = relocations.rva as usize;
+            let end = block + relocations.size as usize;
+            while block + 8 <= end {
+                let page = read_u32(image, block)? as usize;
+                let block_size = read_u32(image, block + 4)? as usize;
+                if block_size < 8 {
+                    anyhow::bail!("malformed relocation block at {block:#x}");
+                }
+                for entry in (block + 8..block + block_size).step_by(2) {
+                    let entry = read_u16(image, entry)?;
+                    let offset = page + (entry & 0xFFF) as usize;
+                    match entry >> 12 {
+                        RELOCATION_ABSOLUTE => {}
+                        RELOCATION_HIGHLOW => {
+                            let value = read_u32(image, offset)?.wrapping_add(delta as u32);
+                            write_bytes(image, offset, &value.to_le_bytes())?;
+                        }
+                        RELOCATION_DIR64 => {
+                            let value = read_u64(image, offset)?.wrapping_add(delta);
+                            write_bytes(image, offset, &value.to_le_bytes())?;
+                        }
+                        kind => anyhow::bail!("unsupported relocation type {kind} at {offset:#x}"),
+                    }
+                }
+                block += block_size;
+            }
+        }
+
+        let base = if self.is_64 {
+            base.to_le_bytes().to_vec()
+        } else {
+            (base as u32).to_le_bytes().to_vec()
+        };
+        write_bytes(image, self.image_base_offset, &base)
+    }
+
+    /// Lists the functions the image imports, in the order they appear in its import table.
+    pub fn imports(&self, image: &[u8]) -> anyhow::Result<Vec<Import>> {
+        let directory = self.data_directory(directory::IMPORT);
+        let mut imports = vec![];
+        if directory.is_empty() {
+            return Ok(imports);
+        }
+
+        let ordinal_flag = if self.is_64 { 1 << 63 } else { 1 << 31 };
+        for descriptor in (directory.rva as usize..).step_by(20) {
+            let lookup_table = read_u32(image, descriptor)?;
+            let name = read_u32(image, descriptor + 12)?;
+            let address_table = read_u32(image, descriptor + 16)?;
+            if name == 0 && address_table == 0 {
+                break;
+            }
+            let module = read_c_string(image, name as usize)?;
+
+            // The lookup table is missing from some images, in which case the address table
+            // holds the same entries until it is bound
+            let lookup_table = if lookup_table == 0 {
+                address_table
+            } else {
+                lookup_table
+            };
+            for index in 0.. {
+                let entry_offset = index * self.pointer_size();
+                let entry = read_pointer(image, lookup_table as usize + entry_offset, self.is_64)?;
+                if entry == 0 {
+                    break;
+                }
+                let name = if entry & ordinal_flag != 0 {
+                    ImportName::Ordinal(entry as u16)
+                } else {
+                    // Skip the hint that precedes the name
+                    ImportName::Name(read_c_string(image, (entry & 0x7FFF_FFFF) as usize + 2)?)
+                };
+                imports.push(Import {
+                    module: module.clone(),
+                    name,
+                    address_rva: address_table + entry_offset as u32,
+                });
+            }
+        }
+        Ok(imports)
+    }
+
+    /// Writes the address an import resolved to into the image.
+    pub fn bind_import(
+        &self,
+        image: &mut [u8],
+        import: &Import,
+        address: u64,
+    ) -> anyhow::Result<()> {
+        let offset = import.address_rva as usize;
+        if self.is_64 {
+            write_bytes(image, offset, &address.to_le_bytes())
+        } else {
+            write_bytes(image, offset, &(address as u32).to_le_bytes())
+        }
+    }
+
+    /// Reads the image's thread-local storage directory, if it has one.
+    pub fn tls(&self, image: &[u8]) -> anyhow::Result<Option<Tls>> {
+        let directory = self.data_directory(directory::TLS);
+        if directory.is_empty() {
+            return Ok(None);
+        }
+
+        let offset = directory.rva as usize;
+        let size = self.pointer_size();
+        let start = read_pointer(image, offset, self.is_64)?;
+        let end = read_pointer(image, offset + size, self.is_64)?;
+        let callbacks = read_pointer(image, offset + 3 * size, self.is_64)?;
+        let zero_fill = read_u32(image, offset + 4 * size)?;
+
+        // The directory holds addresses rather than RVAs
+        let image_base = self.image_base(image)?;
+        let to_rva = |address: u64| {
+            address
+                .checked_sub(image_base)
+                .and_then(|rva| u32::try_from(rva).ok())
+                .with_context(|| format!("TLS address {address:#x} is outside the image"))
+        };
+        let mut callback_rvas = vec![];
+        if callbacks != 0 {
+            let table = to_rva(callbacks)? as usize;
+            for index in 0.. {
+                let callback = read_pointer(image, table + index * size, self.is_64)?;
+                if callback == 0 {
+                    break;
+                }
+                callback_rvas.push(to_rva(callback)?);
+            }
+        }
+
+        Ok(Some(Tls {
+            callbacks: callback_rvas,
+            has_data: end > start || zero_fill > 0,
+        }))
+    }
+}
+
+/// Finds an export in a module's export directory, without needing the rest of its image.
+///
+/// `window` is the part of the image starting at `window_rva`, which must include the export
+/// directory; names that are outside it are not matched.
+pub fn find_export(
+    window: &[u8],
+    window_rva: u32,
+    directory: DataDirectory,
+    name: &ImportName,
+) -> anyhow::Result<Option<Export>> {
+    if directory.is_empty() {
+        return Ok(None);
+    }
+    let offset = |rva: u32| {
+        rva.checked_sub(window_rva)
+            .map(|offset| offset as usize)
+            .with_context(|| format!("RVA {rva:#x} is before the export directory"))
+    };
+
+    let header = offset(directory.rva)?;
+    let ordinal_base = read_u32(window, header + 16)?;
+    let function_count = read_u32(window, header + 20)?;
+    let name_count = read_u32(window, header + 24)?;
+    let functions = offset(read_u32(window, header + 28)?)?;
+    let names = offset(read_u32(window, header + 32)?)?;
+    let name_ordinals = offset(read_u32(window, header + 36)?)?;
+
+    let index = match name {
+        ImportName::Ordinal(ordinal) => (*ordinal as u32).checked_sub(ordinal_base),
+        ImportName::Name(name) => {
+            let mut index = None;
+            for name_index in 0..name_count as usize {
+                let name_rva = read_u32(window, names + name_index * 4)?;
+                let Some(export_name) = offset(name_rva)
+                    .ok()
+                    .and_then(|offset| read_c_string(window, offset).ok())
+                else {
+                    continue;
+                };
+                if export_name == *name {
+                    index = Some(read_u16(window, name_ordinals + name_index * 2)? as u32);
+                    break;
+                }
+            }
+            index
+        }
+    };
+    let Some(index) = index.filter(|index| *index < function_count) else {
+        return Ok(None);
+    };
+
+    let rva = read_u32(window, functions + index as usize * 4)?;
+    if rva == 0 {
+        return Ok(None);
+    }
+    // Exports that point into the export directory are the names of functions elsewhere
+    if directory.contains(rva) {
+        return Ok(Some(Export::Forwarder(read_c_string(
+            window,
+            offset(rva)?,
+        )?)));
+    }
+    Ok(Some(Export::Rva(rva)))
+}
+
+/// Splits a forwarder into the name of the module it forwards to, with `.dll` added, and the
+/// function in that module.
+pub fn parse_forwarder(forwarder: &str) -> Option<(String, ImportName)> {
+    let (module, function) = forwarder.rsplit_once('.')?;
+    let name = match function.strip_prefix('#') {
+        Some(ordinal) => ImportName::Ordinal(ordinal.parse().ok()?),
+        None => ImportName::Name(function.to_owned()),
+    };
+    Some((format!("{module}.dll"), name))
+}
+
+fn read_bytes(data: &[u8], offset: usize, len: usize) -> anyhow::Result<&[u8]> {
+    data.get(offset..offset.checked_add(len).context("offset overflowed")?)
+        .with_context(|| format!("{len} bytes at {offset:#x} are out of bounds"))
+}
+
+fn write_bytes(data: &mut [u8], offset: usize, bytes: &[u8]) -> anyhow::Result<()> {
+    data.get_mut(offset..offset + bytes.len())
+        .with_context(|| format!("{} bytes at {offset:#x} are out of bounds", bytes.len()))?
+        .copy_from_slice(bytes);
+    Ok(())
+}
+
+fn read_u16(data: &[u8], offset: usize) -> anyhow::Result<u16> {
+    Ok(u16::from_le_bytes(read_bytes(data, offset, 2)?.try_into()?))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> anyhow::Result<u32> {
+    Ok(u32::from_le_bytes(read_bytes(data, offset, 4)?.try_into()?))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> anyhow::Result<u64> {
+    Ok(u64::from_le_bytes(read_bytes(data, offset, 8)?.try_into()?))
+}
+
+fn read_pointer(data: &[u8], offset: usize, is_64: bool) -> anyhow::Result<u64> {
+    if is_64 {
+        read_u64(data, offset)
+    } else {
+        read_u32(data, offset).map(u64::from)
+    }
+}
+
+fn read_c_string(data: &[u8], offset: usize) -> anyhow::Result<String> {
+    let bytes = data
+        .get(offset..)
+        .with_context(|| format!("string at {offset:#x} is out of bounds"))?;
+    let len = bytes
+        .iter()
+        .position(|b| *b == 0)
+        .with_context(|| format!("string at {offset:#x} is not terminated"))?;
+    Ok(String::from_utf8_lossy(&bytes[..len]).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Built from the sources next to them by `tests/fixtures/pe/build.sh`
+    const FIXTURE_64: &[u8] = include_bytes!("../tests/fixtures/pe/fixture64.dll");
+    const FIXTURE_32: &[u8] = include_bytes!("../tests/fixtures/pe/fixture32.dll");
+
+    /// What differs between the two fixtures.
+    struct Expected {
+        data: &'static [u8],
+        machine: u16,
+        is_64: bool,
+        image_base: u64,
+        answer_rva: u32,
+        unnamed_rva: u32,
+        tls_callback_rva: u32,
+        import_address_table: u32,
+    }
+
+    const EXPECTED: [Expected; 2] = [
+        Expected {
+            data: FIXTURE_64,
+            machine: MACHINE_AMD64,
+            is_64: true,
+            image_base: 0x1_8000_0000,
+            answer_rva: 0x1006,
+            unnamed_rva: 0x100C,
+            tls_callback_rva: 0x1012,
+            import_address_table: 0x2150,
+        },
+        Expected {
+            data: FIXTURE_32,
+            machine: MACHINE_I386,
+            is_64: false,
+            image_base: 0x1000_0000,
+            answer_rva: 0x1008,
+            unnamed_rva: 0x100E,
+            tls_callback_rva: 0x1014,
+            import_address_table: 0x2124,
+        },
+    ];
+
+    /// The RVA of the pointer to `answer` at the start of the fixtures' `.data`.
+    const ANSWER_POINTER_RVA: usize = 0x3000;
+
+    #[test]
+    fn parse_reads_the_headers() {
+        for expected in &EXPECTED {
+            let pe = Pe::parse(expected.data).unwrap();
+            assert_eq!(pe.machine, expected.machine);
+            assert_eq!(pe.is_64, expected.is_64);
+            assert_eq!(pe.pointer_size(), if expected.is_64 { 8 } else { 4 });
+            assert!(pe.is_dll);
+            assert_eq!(pe.entry_point, 0x1000);
+            assert_eq!(pe.size_of_image, 0x6000);
+            assert_eq!(pe.size_of_headers, 0x400);
+
+            let names: Vec<_> = pe.sections.iter().map(|s| s.name.as_str()).collect();
+            assert_eq!(names, [".text", ".rdata", ".data", ".tls", ".reloc"]);
+            let text = &pe.sections[0];
+            assert!(text.is_executable() && text.is_readable() && !text.is_writable());
+            assert!(pe.sections[2].is_writable());
+
+            assert!(!pe.data_directory(directory::EXPORT).is_empty());
+            assert!(pe.data_directory(directory::EXCEPTION).is_empty());
+            assert!(pe.data_directory(16).is_empty());
+            assert_eq!(pe.image_base(expected.data).unwrap(), expected.image_base);
+        }
+    }
+
+    #[test]
+    fn parse_refuses_other_files() {
+        assert!(Pe::parse(b"").is_err());
+        assert!(Pe::parse(b"\x7FELF").is_err());
+        let mut no_signature = FIXTURE_64.to_vec();
+        no_signature[read_u32(FIXTURE_64, 0x3C).unwrap() as usize] = b'X';
+        assert!(Pe::parse(&no_signature).is_err());
+        assert!(Pe::parse(&FIXTURE_64[..0x100]).is_err());
+    }
+
+    #[test]
+    fn map_lays_out_sections_at_their_rvas() {
+        for expected in &EXPECTED {
+            let pe = Pe::parse(expected.data).unwrap();
+            let image = pe.map().unwrap();
+            assert_eq!(image.len(), 0x6000);
+            assert_eq!(&image[..2], b"MZ");
+            // `entry` returns TRUE, and `answer` returns 42
+            assert_eq!(image[0x1000..0x1005], [0xB8, 1, 0, 0, 0]);
+            let answer = expected.answer_rva as usize;
+            assert_eq!(image[answer..answer + 5], [0xB8, 42, 0, 0, 0]);
+            // The TLS template data, followed by the zeroed rest of the page
+            assert_eq!(image[0x4000..0x4004], 0x11223344u32.to_le_bytes());
+            assert!(image[0x4004..0x5000].iter().all(|b| *b == 0));
+
+            assert!(Pe::parse(&expected.data[..0x800]).unwrap().map().is_err());
+        }
+    }
+
+    #[test]
+    fn relocate_moves_absolute_addresses() {
+        for expected in &EXPECTED {
+            let pe = Pe::parse(expected.data).unwrap();
+            let answer_pointer =
+                |image: &[u8]| read_pointer(image, ANSWER_POINTER_RVA, expected.is_64).unwrap();
+
+            let mut image = pe.map().unwrap();
+            let original = image.clone();
+            assert_eq!(
+                answer_pointer(&image),
+                expected.image_base + expected.answer_rva as u64
+            );
+            pe.relocate(&mut image, expected.image_base).unwrap();
+            assert_eq!(image, original);
+
+            let base = 0x2345_0000;
+            pe.relocate(&mut image, base).unwrap();
+            assert_eq!(pe.image_base(&image).unwrap(), base);
+            assert_eq!(answer_pointer(&image), base + expected.answer_rva as u64);
+            // The TLS directory's addresses are relocated too
+            let tls = pe.tls(&image).unwrap().unwrap();
+            assert_eq!(tls.callbacks, [expected.tls_callback_rva]);
+
+            // Without relocations, the image can only stay where it is
+            let mut headers = expected.data.to_vec();
+            let directory_offset = {
+                let nt = read_u32(&headers, 0x3C).unwrap() as usize;
+                nt + 24 + if expected.is_64 { 112 } else { 96 }
+            };
+            let relocations = directory_offset + directory::BASE_RELOCATION * 8;
+            headers[relocations..relocations + 8].fill(0);
+            let pe = Pe::parse(&headers).unwrap();
+            let mut image = pe.map().unwrap();
+            pe.relocate(&mut image, expected.image_base).unwrap();
+            assert!(pe.relocate(&mut image, base).is_err());
+        }
+    }
+
+    #[test]
+    fn imports_lists_names_and_ordinals() {
+        for expected in &EXPECTED {
+            let pe = Pe::parse(expected.data).unwrap();
+            let mut image = pe.map().unwrap();
+            let imports = pe.imports(&image).unwrap();
+            let address_table = expected.import_address_table;
+            let pointer_size = pe.pointer_size() as u32;
+            assert_eq!(
+                imports,
+                [
+                    Import {
+                        module: "kernel32.dll".to_owned(),
+                        name: ImportName::Name("GetTickCount".to_owned()),
+                        address_rva: address_table,
+                    },
+                    Import {
+                        module: "ws2_32.dll".to_owned(),
+                        name: ImportName::Ordinal(23),
+                        // Each module's entries end with a null one
+                        address_rva: address_table + 2 * pointer_size,
+                    },
+                ]
+            );
+
+            pe.bind_import(&mut image, &imports[1], 0x1234_5678)
+                .unwrap();
+            let bound = read_pointer(&image, imports[1].address_rva as usize, pe.is_64);
+            assert_eq!(bound.unwrap(), 0x1234_5678);
+            // Binding does not change what is imported
+            assert_eq!(pe.imports(&image).unwrap(), imports);
+        }
+    }
+
+    #[test]
+    fn find_export_resolves_names_ordinals_and_forwarders() {
+        for expected in &EXPECTED {
+            let pe = Pe::parse(expected.data).unwrap();
+            let image = pe.map().unwrap();
+            let directory = pe.data_directory(directory::EXPORT);
+            let find = |name: ImportName| find_export(&image, 0, directory, &name).unwrap();
+            let name = |name: &str| ImportName::Name(name.to_owned());
+
+            assert_eq!(find(name("answer")), Some(Export::Rva(expected.answer_rva)));
+            assert_eq!(
+                find(ImportName::Ordinal(1)),
+                Some(Export::Rva(expected.answer_rva))
+            );
+            assert_eq!(
+                find(ImportName::Ordinal(2)),
+                Some(Export::Rva(expected.unnamed_rva))
+            );
+            assert_eq!(
+                find(name("forwarded")),
+                Some(Export::Forwarder("kernel32.GetTickCount".to_owned()))
+            );
+            assert_eq!(
+                find(ImportName::Ordinal(4)),
+                Some(Export::Forwarder("ws2_32.#23".to_owned()))
+            );
+            assert_eq!(find(name("unnamed")), None);
+            assert_eq!(find(name("Answer")), None);
+            assert_eq!(find(ImportName::Ordinal(0)), None);
+            assert_eq!(find(ImportName::Ordinal(5)), None);
+            assert_eq!(
+                find_export(&image, 0, DataDirectory::default(), &name("answer")).unwrap(),
+                None
+            );
+
+            // Only the export directory is needed
+            let window = &image[directory.rva as usize..][..directory.size as usize];
+            assert_eq!(
+                find_export(window, directory.rva, directory, &name("forwarded")).unwrap(),
+                Some(Export::Forwarder("kernel32.GetTickCount".to_owned()))
+            );
+            assert!(find_export(&image[0x100..], 0x100, directory, &name("answer")).is_ok());
+            assert!(find_export(window, directory.rva + 1, directory, &name("answer")).is_err());
+        }
+    }
+
+    #[test]
+    fn parse_forwarder_splits_module_and_function() {
+        assert_eq!(
+            parse_forwarder("kernel32.GetTickCount"),
+            Some((
+                "kernel32.dll".to_owned(),
+                ImportName::Name("GetTickCount".to_owned())
+            ))
+        );
+        assert_eq!(
+            parse_forwarder("ws2_32.#23"),
+            Some(("ws2_32.dll".to_owned(), ImportName::Ordinal(23)))
+        );
+        // The module name may itself contain dots
+        assert_eq!(
+            parse_forwarder("api-ms-win-core-1.0.Function"),
+            Some((
+                "api-ms-win-core-1.0.dll".to_owned(),
+                ImportName::Name("Function".to_owned())
+            ))
+        );
+        assert_eq!(parse_forwarder("GetTickCount"), None);
+        assert_eq!(parse_forwarder("ws2_32.#x"), None);
+        assert_eq!(parse_forwarder("ws2_32.#70000"), None);
+    }
+
+    #[test]
+    fn tls_reads_callbacks_and_data() {
+        for expected in &EXPECTED {
+            let pe = Pe::parse(expected.data).unwrap();
+            let image = pe.map().unwrap();
+            assert_eq!(
+                pe.tls(&image).unwrap(),
+                Some(Tls {
+                    callbacks: vec![expected.tls_callback_rva],
+                    has_data: true,
+                })
+            );
+        }
+    }
+}
@@ -0,0 +1,282 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+use crate::linux::query as sys;
+#[cfg(target_os = "windows")]
+use crate::windows::query as sys;
+
+pub use sys::ProcessHandle;
+
+/// How often [`wait_for_process`] and `wait_for_module` check again.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A running process, as listed by the platform.
+pub(crate) struct ProcessEntry {
+    pub pid: u32,
+    pub parent_pid: u32,
+    pub name: String,
+}
+
+/// Finds running processes that match all of the given criteria, and opens them for injection.
+///
+/// Names, module names and window titles are matched against glob patterns, where `*` matches
+/// any number of characters and `?` matches one; on Windows, they are matched case-insensitively,
+/// as are paths. Processes whose path, modules or windows cannot be read do not match criteria
+/// on them.
+///
+/// ```no_run
+/// # use re_utilities_injector::ProcessQuery;
+/// let matches = ProcessQuery::new()
+///     .with_name("game*.exe")
+///     .with_module("d3d11.dll")
+///     .run()?;
+/// # anyhow::Ok(())
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ProcessQuery {
+    name: Option<String>,
+    path: Option<PathBuf>,
+    parent_pid: Option<u32>,
+    module: Option<String>,
+    window_title: Option<String>,
+}
+
+/// A process that matched a [`ProcessQuery`].
+#[derive(Debug)]
+pub struct ProcessMatch {
+    pub pid: u32,
+    pub parent_pid: u32,
+    pub name: String,
+    /// The full path of the process's executable, if it could be read.
+    pub path: Option<PathBuf>,
+    /// The process opened for injection, or why it could not be.
+    pub handle: anyhow::Result<ProcessHandle>,
+}
+
+impl ProcessQuery {
+    pub fn new() -> ProcessQuery {
+        ProcessQuery::default()
+    }
+
+    /// Matches processes whose executable's file name matches `pattern`.
+    pub fn with_name(mut self, pattern: &str) -> Self {
+        self.name = Some(pattern.to_owned());
+        self
+    }
+    /// Matches processes whose executable is at `path`.
+    pub fn with_path(mut self, path: impl AsRef<Path>) -> Self {
+        let path = path.as_ref();
+        self.path = Some(dunce::canonicalize(path).unwrap_or_else(|_| path.to_owned()));
+        self
+    }
+    /// Matches processes started by the process with the given ID.
+    pub fn with_parent_pid(mut self, parent_pid: u32) -> Self {
+        self.parent_pid = Some(parent_pid);
+        self
+    }
+    /// Matches processes that have loaded a module whose file name matches `pattern`.
+    pub fn with_module(mut self, pattern: &str) -> Self {
+        self.module = Some(pattern.to_owned());
+        self
+    }
+    /// Matches processes with a top-level window whose title matches `pattern`. This is only
+    /// supported on Windows.
+    pub fn with_window_title(mut self, pattern: &str) -> Self {
+        self.window_title = Some(pattern.to_owned());
+        self
+    }
+
+    /// Lists the processes that match, and tries to open each of them.
+    pub fn run(&self) -> anyhow::Result<Vec<ProcessMatch>> {
+        // Windows are listed once for all processes, rather than for each of them
+        let window_titles = match &self.window_title {
+            Some(_) => sys::window_titles()?,
+            None => HashMap::new(),
+        };
+
+        let mut matches = vec![];
+        for entry in sys::processes()? {
+            if self
+                .name
+                .as_ref()
+                .is_some_and(|pattern| !glob_matches(pattern, &entry.name))
+            {
+                continue;
+            }
+            if self
+                .parent_pid
+                .is_some_and(|parent_pid| entry.parent_pid != parent_pid)
+            {
+                continue;
+            }
+            if let Some(pattern) = &self.window_title {
+                let titles = window_titles.get(&entry.pid).map_or(&[][..], Vec::as_slice);
+                if !titles.iter().any(|title| glob_matches(pattern, title)) {
+                    continue;
+                }
+            }
+
+            let path = sys::executable_path(entry.pid).ok();
+            if let Some(query_path) = &self.path {
+                if !path
+                    .as_ref()
+                    .is_some_and(|path| paths_equal(path, query_path))
+                {
+                    continue;
+                }
+            }
+            if let Some(pattern) = &self.module {
+                let modules = sys::module_names(entry.pid).unwrap_or_default();
+                if !modules.iter().any(|module| glob_matches(pattern, module)) {
+                    continue;
+                }
+            }
+
+            matches.push(ProcessMatch {
+                pid: entry.pid,
+                parent_pid: entry.parent_pid,
+                name: entry.name,
+                path,
+                handle: sys::open(entry.pid),
+            });
+        }
+        Ok(matches)
+    }
+}
+
+/// Waits for a process that matches `query` to start, and returns the first match, or `None` if
+/// none has started after `timeout`. Waits forever if `timeout` is `None`.
+///
+/// This is useful when a game is started by a launcher, which starts the process to inject into
+/// later on.
+pub fn wait_for_process(
+    query: &ProcessQuery,
+    timeout: Option<Duration>,
+) -> anyhow::Result<Option<ProcessMatch>> {
+    poll(timeout, || Ok(query.run()?.into_iter().next()))
+}
+
+/// Calls `check` until it returns a value, or `timeout` has passed.
+pub(crate) fn poll<T>(
+    timeout: Option<Duration>,
+    mut check: impl FnMut() -> anyhow::Result<Option<T>>,
+) -> anyhow::Result<Option<T>> {
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    loop {
+        if let Some(value) = check()? {
+            return Ok(Some(value));
+        }
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Ok(None);
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+fn paths_equal(a: &Path, b: &Path) -> bool {
+    if cfg!(target_os = "windows") {
+        a.to_string_lossy().to_lowercase() == b.to_string_lossy().to_lowercase()
+    } else {
+        a == b
+    }
+}
+
+/// Matches `text` against a pattern where `*` matches any number of characters and `?` matches
+/// one.
+pub(crate) fn glob_matches(pattern: &str, text: &str) -> bool {
+    let (pattern, text) = if cfg!(target_os = "windows") {
+        (pattern.to_lowercase(), text.to_lowercase())
+    } else {
+        (pattern.to_owned(), text.to_owned())
+    };
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    // Backtrack to the last `*` on a mismatch, letting it match one more character
+    let (mut p, mut t) = (0, 0);
+    let mut star = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    p = star_p + 1;
+                    t = star_t + 1;
+                    star = Some((star_p, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_matches_literals() {
+        assert!(glob_matches("game.exe", "game.exe"));
+        assert!(!glob_matches("game.exe", "game.exe2"));
+        assert!(!glob_matches("game.exe", "game.ex"));
+        assert!(glob_matches("", ""));
+        assert!(!glob_matches("", "game.exe"));
+        assert!(!glob_matches("game.exe", ""));
+    }
+
+    #[test]
+    fn glob_matches_stars() {
+        assert!(glob_matches("*", ""));
+        assert!(glob_matches("*", "game.exe"));
+        assert!(glob_matches("**", "game.exe"));
+        assert!(glob_matches("*.exe", "game.exe"));
+        assert!(glob_matches("*.exe", ".exe"));
+        assert!(!glob_matches("*.exe", "game.dll"));
+        assert!(glob_matches("game*", "game.exe"));
+        assert!(glob_matches("game*", "game"));
+        assert!(!glob_matches("game*", "a game"));
+        assert!(glob_matches("g*e.exe", "game.exe"));
+        assert!(glob_matches("g*e.exe", "ge.exe"));
+        assert!(!glob_matches("g*e.exe", "game.dll"));
+        // A star has to backtrack past earlier partial matches
+        assert!(glob_matches("*ab", "aab"));
+        assert!(glob_matches("a*b*c", "abbbxbc"));
+        assert!(!glob_matches("a*b*c", "abbbxb"));
+        assert!(!glob_matches("", "*"));
+    }
+
+    #[test]
+    fn glob_matches_question_marks() {
+        assert!(glob_matches("?ame.exe", "game.exe"));
+        assert!(glob_matches("g?me.exe", "game.exe"));
+        assert!(glob_matches("game.ex?", "game.exe"));
+        assert!(!glob_matches("game.ex?", "game.ex"));
+        assert!(!glob_matches("?", ""));
+        assert!(glob_matches("???", "abc"));
+        assert!(!glob_matches("???", "abcd"));
+        assert!(glob_matches("?*", "a"));
+        assert!(!glob_matches("?*", ""));
+        assert!(glob_matches("é?", "éa"));
+    }
+
+    #[test]
+    fn glob_matches_case_insensitively_on_windows() {
+        let windows = cfg!(target_os = "windows");
+        assert_eq!(glob_matches("Game*.EXE", "game64.exe"), windows);
+        assert_eq!(glob_matches("game.exe", "GAME.EXE"), windows);
+        assert_eq!(glob_matches("ÉTÉ?", "été1"), windows);
+        assert!(glob_matches("GAME.exe", "GAME.exe"));
+    }
+}
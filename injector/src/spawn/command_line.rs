@@ -0,0 +1,197 @@
+/// The characters that make `CommandLineToArgvW` split or unquote an argument.
+const SPECIAL_CHARACTERS: [char; 5] = [' ', '\t', '\n', '\x0B', '"'];
+
+/// Quotes an argument so that `CommandLineToArgvW` and the C runtime parse it back unchanged.
+///
+/// Arguments without whitespace or quotes are left as they are. Otherwise, the argument is
+/// wrapped in quotes, its quotes are escaped with a backslash, and the backslashes before them or
+/// before the closing quote are doubled, as a run of backslashes is only special before a quote.
+pub fn quote_arg(arg: &str) -> String {
+    if !arg.is_empty() && !arg.contains(SPECIAL_CHARACTERS) {
+        return arg.to_owned();
+    }
+
+    let mut quoted = String::with_capacity(arg.len() + 2);
+    quoted.push('"');
+    let mut backslashes = 0;
+    for c in arg.chars() {
+        match c {
+            '\\' => backslashes += 1,
+            '"' => {
+                quoted.push_str(&"\\".repeat(backslashes * 2 + 1));
+                quoted.push('"');
+                backslashes = 0;
+            }
+            _ => {
+                quoted.push_str(&"\\".repeat(backslashes));
+                quoted.push(c);
+                backslashes = 0;
+            }
+        }
+    }
+    quoted.push_str(&"\\".repeat(backslashes * 2));
+    quoted.push('"');
+    quoted
+}
+
+/// Builds the command line that runs `executable_path` with `args`, for `CreateProcessW`.
+///
+/// The executable's path is parsed differently from the other arguments, as everything up to the
+/// next quote or whitespace is taken as is, so it is only quoted if it contains whitespace, and
+/// cannot contain quotes.
+pub fn command_line<'a>(
+    executable_path: &str,
+    args: impl IntoIterator<Item = &'a str>,
+) -> anyhow::Result<String> {
+    if executable_path.contains('"') {
+        anyhow::bail!("the executable path {executable_path:?} cannot contain quotes");
+    }
+
+    let mut command_line = if executable_path.is_empty() || executable_path.contains([' ', '\t']) {
+        format!("\"{executable_path}\"")
+    } else {
+        executable_path.to_owned()
+    };
+    for arg in args {
+        command_line.push(' ');
+        command_line.push_str(&quote_arg(arg));
+    }
+    Ok(command_line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Splits a command line as `CommandLineToArgvW` does.
+    fn parse(command_line: &str) -> Vec<String> {
+        let mut chars = command_line.chars().peekable();
+        let mut args = vec![];
+
+        // The executable's path ends at the closing quote, or else at the first whitespace
+        let mut executable = String::new();
+        if chars.next_if_eq(&'"').is_some() {
+            executable.extend(chars.by_ref().take_while(|c| *c != '"'));
+        } else {
+            while let Some(c) = chars.next_if(|c| *c != ' ' && *c != '\t') {
+                executable.push(c);
+            }
+        }
+        args.push(executable);
+
+        loop {
+            while chars.next_if(|c| *c == ' ' || *c == '\t').is_some() {}
+            if chars.peek().is_none() {
+                break;
+            }
+            let mut arg = String::new();
+            let mut in_quotes = false;
+            let mut backslashes = 0;
+            while let Some(&c) = chars.peek() {
+                if !in_quotes && (c == ' ' || c == '\t') {
+                    break;
+                }
+                chars.next();
+                match c {
+                    '\\' => backslashes += 1,
+                    '"' => {
+                        arg.push_str(&"\\".repeat(backslashes / 2));
+                        if backslashes % 2 == 1 {
+                            arg.push('"');
+                        } else if in_quotes && chars.next_if_eq(&'"').is_some() {
+                            // A doubled quote within quotes is a literal quote
+                            arg.push('"');
+                        } else {
+                            in_quotes = !in_quotes;
+                        }
+                        backslashes = 0;
+                    }
+                    _ => {
+                        arg.push_str(&"\\".repeat(backslashes));
+                        arg.push(c);
+                        backslashes = 0;
+                    }
+                }
+            }
+            arg.push_str(&"\\".repeat(backslashes));
+            args.push(arg);
+        }
+        args
+    }
+
+    fn round_trip(executable_path: &str, args: &[&str]) {
+        let command_line = command_line(executable_path, args.iter().copied()).unwrap();
+        let mut expected = vec![executable_path];
+        expected.extend(args);
+        assert_eq!(parse(&command_line), expected, "{command_line}");
+    }
+
+    #[test]
+    fn plain_arguments_are_left_alone() {
+        assert_eq!(quote_arg("-windowed"), "-windowed");
+        assert_eq!(quote_arg(r"C:\Games\game.exe"), r"C:\Games\game.exe");
+        assert_eq!(quote_arg(r"trailing\"), r"trailing\");
+    }
+
+    #[test]
+    fn special_arguments_are_quoted() {
+        assert_eq!(quote_arg(""), r#""""#);
+        assert_eq!(quote_arg("two words"), r#""two words""#);
+        assert_eq!(quote_arg("tab\tand\nnewline"), "\"tab\tand\nnewline\"");
+        assert_eq!(quote_arg(r#"say "hi""#), r#""say \"hi\"""#);
+        // Backslashes are only doubled before a quote, including the closing one
+        assert_eq!(quote_arg(r"C:\Program Files\"), r#""C:\Program Files\\""#);
+        assert_eq!(quote_arg(r#"a\"b"#), r#""a\\\"b""#);
+        assert_eq!(quote_arg(r"a\b c"), r#""a\b c""#);
+    }
+
+    #[test]
+    fn arguments_round_trip() {
+        let args = [
+            "",
+            "plain",
+            "two words",
+            r#"embedded"quote"#,
+            r#""quoted""#,
+            r"trailing\",
+            r"trailing space\ ",
+            r"\\server\share\",
+            r#"backslash\"quote"#,
+            r#"backslashes\\\"quote"#,
+            "tab\tseparated",
+            "line\nbreak",
+            "vertical\x0Btab",
+            "\"",
+            "\\",
+            "ünïcödé",
+        ];
+        round_trip(r"C:\Games\game.exe", &args);
+        for arg in args {
+            round_trip("game.exe", &[arg, "next"]);
+        }
+    }
+
+    #[test]
+    fn executable_paths_are_quoted_only_for_whitespace() {
+        assert_eq!(
+            command_line(r"C:\Games\game.exe", ["-a"]).unwrap(),
+            r"C:\Games\game.exe -a"
+        );
+        assert_eq!(
+            command_line(r"C:\Program Files\Game\game.exe", []).unwrap(),
+            r#""C:\Program Files\Game\game.exe""#
+        );
+        // Backslashes are not special in the executable's path
+        assert_eq!(
+            command_line(r"C:\Program Files\Game\", []).unwrap(),
+            r#""C:\Program Files\Game\""#
+        );
+        assert_eq!(command_line("", ["a"]).unwrap(), r#""" a"#);
+
+        round_trip(r"C:\Program Files\Game\game.exe", &["a b"]);
+        round_trip("C:\\Games\\tab\tgame.exe", &["x"]);
+        round_trip(r"C:\Games\", &["x"]);
+        round_trip("", &["x"]);
+        assert!(command_line(r#"C:\"game".exe"#, []).is_err());
+    }
+}
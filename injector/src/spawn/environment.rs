@@ -0,0 +1,213 @@
+use std::collections::BTreeMap;
+
+/// The environment variables to start a process with, as changes to the injector's own
+/// environment or from an empty one.
+///
+/// Variables are treated as Windows treats them: names are compared case-insensitively, with the
+/// last change to a name winning, and the block passed to `CreateProcessW` is sorted by name.
+///
+/// ```
+/// # use re_utilities_injector::spawn::Environment;
+/// let environment = Environment::new()
+///     .with_var("SteamAppId", "480")
+///     .without_var("WINEDEBUG");
+/// ```
+#[derive(Debug, Clone)]
+pub struct Environment {
+    inherit: bool,
+    // `None` removes the variable
+    changes: Vec<(String, Option<String>)>,
+}
+impl Default for Environment {
+    fn default() -> Self {
+        Environment {
+            inherit: true,
+            changes: vec![],
+        }
+    }
+}
+impl Environment {
+    /// Starts from the injector's environment.
+    pub fn new() -> Environment {
+        Environment::default()
+    }
+    /// Starts from an empty environment, so that the process only gets the variables that are
+    /// set here.
+    pub fn empty() -> Environment {
+        Environment {
+            inherit: false,
+            changes: vec![],
+        }
+    }
+
+    /// Sets a variable, replacing any inherited value.
+    pub fn with_var(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.changes.push((name.into(), Some(value.into())));
+        self
+    }
+    /// Removes a variable, if it would otherwise be inherited or set.
+    pub fn without_var(mut self, name: impl Into<String>) -> Self {
+        self.changes.push((name.into(), None));
+        self
+    }
+
+    /// Applies the changes to `inherited`, which is ignored for an [empty](Environment::empty)
+    /// environment, and returns the variables sorted by name.
+    pub fn resolve(
+        &self,
+        inherited: impl IntoIterator<Item = (String, String)>,
+    ) -> Vec<(String, String)> {
+        // Windows sorts names by their uppercase UTF-16 code units
+        let key = |name: &str| -> Vec<u16> { name.to_uppercase().encode_utf16().collect() };
+
+        let mut vars = BTreeMap::new();
+        if self.inherit {
+            for (name, value) in inherited {
+                vars.insert(key(&name), (name, value));
+            }
+        }
+        for (name, value) in &self.changes {
+            match value {
+                Some(value) => vars.insert(key(name), (name.clone(), value.clone())),
+                None => vars.remove(&key(name)),
+            };
+        }
+        vars.into_values().collect()
+    }
+
+    /// Builds the environment block for `CreateProcessW` with `CREATE_UNICODE_ENVIRONMENT`: each
+    /// variable as `name=value` in UTF-16 followed by a null, then another null.
+    pub fn to_block(
+        &self,
+        inherited: impl IntoIterator<Item = (String, String)>,
+    ) -> anyhow::Result<Vec<u16>> {
+        let mut block = vec![];
+        for (name, value) in self.resolve(inherited) {
+            // Names may only start with `=`, as the per-drive working directories like `=C:` do
+            if name.is_empty() || name.contains('\0') || name.chars().skip(1).any(|c| c == '=') {
+                anyhow::bail!("invalid environment variable name {name:?}");
+            }
+            if value.contains('\0') {
+                anyhow::bail!("the value of environment variable {name} contains a null");
+            }
+            block.extend(format!("{name}={value}\0").encode_utf16());
+        }
+        // An empty block still needs both nulls
+        if block.is_empty() {
+            block.push(0);
+        }
+        block.push(0);
+        Ok(block)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn block(vars: &[&str]) -> Vec<u16> {
+        let mut block: Vec<u16> = vars
+            .iter()
+            .flat_map(|var| format!("{var}\0").encode_utf16().collect::<Vec<_>>())
+            .collect();
+        block.push(0);
+        block
+    }
+
+    #[test]
+    fn changes_apply_case_insensitively() {
+        let inherited = vars(&[("Path", r"C:\Windows"), ("TEMP", r"C:\Temp")]);
+        let environment = Environment::new()
+            .with_var("PATH", r"C:\Game")
+            .without_var("temp")
+            .with_var("SteamAppId", "480");
+        assert_eq!(
+            environment.resolve(inherited),
+            vars(&[("PATH", r"C:\Game"), ("SteamAppId", "480")])
+        );
+    }
+
+    #[test]
+    fn the_last_change_wins() {
+        let environment = Environment::new()
+            .with_var("A", "1")
+            .without_var("a")
+            .with_var("B", "1")
+            .with_var("b", "2");
+        assert_eq!(
+            environment.resolve(vars(&[("A", "0")])),
+            vars(&[("b", "2")])
+        );
+        assert_eq!(
+            Environment::new()
+                .without_var("A")
+                .with_var("a", "1")
+                .resolve(vec![]),
+            vars(&[("a", "1")])
+        );
+    }
+
+    #[test]
+    fn empty_ignores_the_inherited_environment() {
+        let inherited = vars(&[("PATH", r"C:\Windows")]);
+        assert_eq!(Environment::empty().resolve(inherited.clone()), vars(&[]));
+        assert_eq!(
+            Environment::empty().with_var("A", "1").resolve(inherited),
+            vars(&[("A", "1")])
+        );
+    }
+
+    #[test]
+    fn variables_are_sorted_by_uppercase_name() {
+        let inherited = vars(&[
+            ("b", "1"),
+            ("_", "2"),
+            ("A", "3"),
+            ("=C:", r"C:\"),
+            ("a_b", "4"),
+        ]);
+        let names: Vec<String> = Environment::new()
+            .resolve(inherited)
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        // `=` sorts before letters, and `_` after uppercase ones
+        assert_eq!(names, ["=C:", "A", "a_b", "b", "_"]);
+    }
+
+    #[test]
+    fn blocks_are_null_separated_and_terminated() {
+        let environment = Environment::empty()
+            .with_var("B", "2")
+            .with_var("=C:", r"C:\Games")
+            .with_var("A", "");
+        assert_eq!(
+            environment.to_block(vec![]).unwrap(),
+            block(&[r"=C:=C:\Games", "A=", "B=2"])
+        );
+        // An empty block is two nulls
+        assert_eq!(Environment::empty().to_block(vec![]).unwrap(), [0, 0]);
+        assert_eq!(
+            Environment::new().to_block(vars(&[("A", "1")])).unwrap(),
+            block(&["A=1"])
+        );
+    }
+
+    #[test]
+    fn invalid_variables_are_refused() {
+        let to_block =
+            |name: &str, value: &str| Environment::empty().with_var(name, value).to_block(vec![]);
+        assert!(to_block("", "1").is_err());
+        assert!(to_block("A=B", "1").is_err());
+        assert!(to_block("A\0", "1").is_err());
+        assert!(to_block("A", "1\0").is_err());
+        assert!(to_block("=D:", r"D:\").is_ok());
+        assert!(to_block("A", "1=2").is_ok());
+    }
+}
@@ -0,0 +1,325 @@
+use anyhow::Context;
+use std::{
+    ffi::OsString,
+    fs, io,
+    path::{Path, PathBuf},
+    process::{Child, Command},
+    thread::JoinHandle,
+};
+
+use super::{stdio, StdioOptions};
+
+/// What runs a Windows executable on Linux.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Runner {
+    /// A Proton install, as the directory containing its `proton` script. The executable is
+    /// started with `proton run`.
+    Proton(PathBuf),
+    /// A Wine binary, such as `wine` or `wine64`.
+    Wine(PathBuf),
+}
+
+/// A payload that is loaded by the game in place of one of the DLLs it imports, through a DLL
+/// override.
+///
+/// The payload is copied next to the executable as `<name>.dll`, and Wine is told to prefer it over
+/// its own builtin version. It is then loaded by the game's loader before its entry point runs, so
+/// it must export what the game imports from the DLL it replaces, usually by forwarding to the
+/// original.
+///
+/// The copy is marked by a `<name>.dll.proxy` file next to it, so that later launches can replace
+/// it; a `<name>.dll` that is already there for any other reason, such as one the game ships
+/// with, is left alone and the launch fails.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProxyDll {
+    pub payload_path: PathBuf,
+    /// The name of the DLL to replace, without its extension, such as `version` or `dinput8`.
+    pub name: String,
+}
+impl ProxyDll {
+    pub fn new(payload_path: impl Into<PathBuf>, name: &str) -> ProxyDll {
+        ProxyDll {
+            payload_path: payload_path.into(),
+            name: name.to_owned(),
+        }
+    }
+}
+
+/// Spawns a Windows executable with Proton or Wine, using the prefix in `compat_data_path`.
+///
+/// For Proton, `compat_data_path` is what Steam passes as `STEAM_COMPAT_DATA_PATH`, whose `pfx`
+/// directory is the Wine prefix; Proton may also need `STEAM_COMPAT_CLIENT_INSTALL_PATH` to be set
+/// to Steam's directory in `env_vars`. For Wine, `compat_data_path/pfx` is used as `WINEPREFIX`,
+/// so that both can share Steam's prefixes.
+///
+/// With a `proxy_dll`, its override is added to any `WINEDLLOVERRIDES` in `env_vars`, or else to
+/// the one this process was started with. `stdio` sets where the process's streams go and which
+/// console it runs in.
+pub fn compat_process<'a>(
+    runner: &Runner,
+    compat_data_path: &Path,
+    game_path: &Path,
+    executable_path: &Path,
+    env_vars: impl IntoIterator<Item = (String, String)>,
+    args: impl IntoIterator<Item = &'a str>,
+    proxy_dll: Option<&ProxyDll>,
+    stdio: &StdioOptions,
+) -> anyhow::Result<Child> {
+    let mut command = match runner {
+        Runner::Proton(proton_path) => {
+            let mut command = Command::new(proton_path.join("proton"));
+            command
+                .arg("run")
+                .env("STEAM_COMPAT_DATA_PATH", compat_data_path);
+            command
+        }
+        Runner::Wine(wine_path) => {
+            let mut command = Command::new(wine_path);
+            command.env("WINEPREFIX", compat_data_path.join("pfx"));
+            command
+        }
+    };
+    // An override passed in is merged with the proxy's, rather than replacing it
+    let (mut overrides, env_vars): (Vec<_>, Vec<_>) = env_vars
+        .into_iter()
+        .partition(|(name, _)| name == "WINEDLLOVERRIDES");
+    let overrides = overrides.pop().map(|(_, value)| OsString::from(value));
+    command
+        .args(stdio.wine_start_args())
+        .arg(executable_path)
+        .args(args)
+        .current_dir(game_path)
+        .envs(env_vars);
+    stdio.apply(&mut command)?;
+
+    if let Some(proxy_dll) = proxy_dll {
+        let directory = executable_path
+            .parent()
+            .context("failed to get the executable's directory")?;
+        copy_proxy_dll(proxy_dll, directory)?;
+
+        // Keep any overrides the user has set up themselves
+        let overrides = overrides.or_else(|| std::env::var_os("WINEDLLOVERRIDES"));
+        command.env(
+            "WINEDLLOVERRIDES",
+            merge_overrides(overrides, &proxy_dll.name),
+        );
+    } else if let Some(overrides) = overrides {
+        command.env("WINEDLLOVERRIDES", overrides);
+    }
+
+    command.spawn().context("failed to spawn process")
+}
+
+/// Copies a proxy DLL's payload into `directory` as `<name>.dll`, next to a `<name>.dll.proxy`
+/// file that marks it as a copy. A file of that name that was not put there as a proxy DLL, such
+/// as the game's own copy of the DLL, is never replaced.
+fn copy_proxy_dll(proxy_dll: &ProxyDll, directory: &Path) -> anyhow::Result<()> {
+    let proxy_path = directory.join(format!("{}.dll", proxy_dll.name));
+    let marker_path = directory.join(format!("{}.dll.proxy", proxy_dll.name));
+    if proxy_path.exists()
+        && !marker_path.exists()
+        && fs::read(&proxy_path).ok() != fs::read(&proxy_dll.payload_path).ok()
+    {
+        anyhow::bail!(
+            "refusing to replace {}, which is not a proxy DLL copied there earlier",
+            proxy_path.display()
+        );
+    }
+
+    fs::write(
+        &marker_path,
+        proxy_dll.payload_path.as_os_str().as_encoded_bytes(),
+    )
+    .with_context(|| format!("failed to write {}", marker_path.display()))?;
+    fs::copy(&proxy_dll.payload_path, &proxy_path).with_context(|| {
+        format!(
+            "failed to copy {} to {}",
+            proxy_dll.payload_path.display(),
+            proxy_path.display()
+        )
+    })?;
+    Ok(())
+}
+
+/// Adds an override that makes Wine prefer the native `<name>.dll` to `overrides`, a
+/// `WINEDLLOVERRIDES` value.
+fn merge_overrides(overrides: Option<OsString>, name: &str) -> OsString {
+    let mut overrides = overrides.unwrap_or_default();
+    if !overrides.is_empty() {
+        overrides.push(";");
+    }
+    overrides.push(format!("{name}=n,b"));
+    overrides
+}
+
+/// Copies the output from a process's stdout and stderr pipes to the launcher's stdout and
+/// stderr, a line at a time, on threads that finish once the process closes them. Streams that
+/// were not redirected to pipes are left alone.
+pub fn forward_output(child: &mut Child) -> Vec<JoinHandle<io::Result<()>>> {
+    stdio::forward_output(child.stdout.take(), child.stderr.take())
+}
+
+/// Spawns a Windows game for the given Steam app ID, with the compatibility tool and prefix that
+/// Steam uses for it. The tool is the one set for the app in Steam, or the default one if none
+/// is.
+pub fn steam_process<'a>(
+    app_id: u32,
+    executable_path_builder: impl Fn(&Path) -> PathBuf + Copy,
+    args: impl IntoIterator<Item = &'a str>,
+    proxy_dll: Option<&ProxyDll>,
+    stdio: &StdioOptions,
+) -> anyhow::Result<Child> {
+    let steam_dir = steamlocate::SteamDir::locate()?;
+
+    let (app, library) = steam_dir
+        .find_app(app_id)?
+        .context("failed to locate app")?;
+    let game_path = library.resolve_app_dir(&app);
+    let executable_path = executable_path_builder(&game_path);
+    let compat_data_path = library
+        .path()
+        .join("steamapps")
+        .join("compatdata")
+        .join(app_id.to_string());
+    let runner = steam_runner(&steam_dir, app_id)?;
+
+    let env_vars = ["SteamGameId", "SteamAppId"]
+        .iter()
+        .map(|s| (s.to_string(), app_id.to_string()))
+        .chain(std::iter::once((
+            "STEAM_COMPAT_CLIENT_INSTALL_PATH".to_string(),
+            steam_dir.path().to_string_lossy().into_owned(),
+        )));
+
+    compat_process(
+        &runner,
+        &compat_data_path,
+        &game_path,
+        &executable_path,
+        env_vars,
+        args,
+        proxy_dll,
+        stdio,
+    )
+}
+
+/// Finds the Proton install that Steam runs the app with.
+fn steam_runner(steam_dir: &steamlocate::SteamDir, app_id: u32) -> anyhow::Result<Runner> {
+    // The mapping for app 0 is the default for all apps
+    let mapping = steam_dir.compat_tool_mapping()?;
+    let name = [app_id, 0]
+        .iter()
+        .find_map(|id| mapping.get(id)?.name.clone())
+        .with_context(|| format!("no compatibility tool is set for app {app_id}"))?;
+
+    // Tools installed by hand live in their own directory, which declares the tool's name
+    if let Ok(entries) = fs::read_dir(steam_dir.path().join("compatibilitytools.d")) {
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let declares_name = fs::read_to_string(path.join("compatibilitytool.vdf"))
+                .is_ok_and(|vdf| vdf.contains(&format!("\"{name}\"")));
+            if (entry.file_name() == name.as_str() || declares_name)
+                && path.join("proton").is_file()
+            {
+                return Ok(Runner::Proton(path));
+            }
+        }
+    }
+
+    // Official releases are installed as apps, in directories named after the release
+    let directory_names = proton_directory_names(&name);
+    for library in steam_dir.libraries()? {
+        let common = library?.path().join("steamapps").join("common");
+        for directory_name in &directory_names {
+            let path = common.join(directory_name);
+            if path.join("proton").is_file() {
+                return Ok(Runner::Proton(path));
+            }
+        }
+    }
+
+    anyhow::bail!("failed to find compatibility tool {name} for app {app_id}")
+}
+
+/// Returns the directories that an official Proton release with the given tool name may be
+/// installed in, such as `Proton 9.0` for `proton_9` and `Proton 6.3` for `proton_63`.
+fn proton_directory_names(name: &str) -> Vec<String> {
+    match name {
+        "proton_experimental" => return vec!["Proton - Experimental".to_owned()],
+        "proton_hotfix" => return vec!["Proton Hotfix".to_owned()],
+        _ => {}
+    }
+    let Some(version) = name
+        .strip_prefix("proton_")
+        .filter(|version| !version.is_empty() && version.chars().all(|c| c.is_ascii_digit()))
+    else {
+        return vec![];
+    };
+
+    // The version's dot is dropped from the name, so `proton_10` may be 10.0 or 1.0
+    let mut names = vec![format!("Proton {version}.0")];
+    if version.len() > 1 {
+        let (major, minor) = version.split_at(1);
+        names.push(format!("Proton {major}.{minor}"));
+    }
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh directory for one test, removed when it is dropped.
+    struct TempDir(PathBuf);
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let path = std::env::temp_dir().join(format!("{name}-{}", std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+    }
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn proxy_dlls_only_replace_earlier_copies() {
+        let dir = TempDir::new("re-utilities-proxy-dll");
+        let payload_path = dir.0.join("payload.dll");
+        fs::write(&payload_path, b"payload").unwrap();
+        let game_path = dir.0.join("game");
+        fs::create_dir(&game_path).unwrap();
+        let proxy_dll = ProxyDll::new(&payload_path, "version");
+        let proxy_path = game_path.join("version.dll");
+
+        copy_proxy_dll(&proxy_dll, &game_path).unwrap();
+        assert_eq!(fs::read(&proxy_path).unwrap(), b"payload");
+
+        // A rebuilt payload replaces the earlier copy
+        fs::write(&payload_path, b"rebuilt payload").unwrap();
+        copy_proxy_dll(&proxy_dll, &game_path).unwrap();
+        assert_eq!(fs::read(&proxy_path).unwrap(), b"rebuilt payload");
+
+        // The game's own DLL does not
+        fs::remove_file(game_path.join("version.dll.proxy")).unwrap();
+        fs::write(&proxy_path, b"original").unwrap();
+        let error = copy_proxy_dll(&proxy_dll, &game_path).unwrap_err();
+        assert!(error.to_string().contains("refusing"), "{error}");
+        assert_eq!(fs::read(&proxy_path).unwrap(), b"original");
+        assert!(!game_path.join("version.dll.proxy").exists());
+    }
+
+    #[test]
+    fn overrides_are_merged() {
+        assert_eq!(merge_overrides(None, "version"), "version=n,b");
+        assert_eq!(merge_overrides(Some("".into()), "version"), "version=n,b");
+        assert_eq!(
+            merge_overrides(Some("d3d11=n".into()), "dinput8"),
+            "d3d11=n;dinput8=n,b"
+        );
+    }
+}
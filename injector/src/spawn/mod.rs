@@ -0,0 +1,19 @@
+//! Starting games so that payloads can be loaded into them. On Windows, processes are created
+//! directly; on Linux, Windows games are run through Proton or Wine.
+mod command_line;
+mod environment;
+mod stdio;
+
+pub use command_line::{command_line, quote_arg};
+pub use environment::Environment;
+pub use stdio::{Console, Redirect, StdioOptions};
+
+#[cfg(target_os = "windows")]
+mod windows;
+#[cfg(target_os = "windows")]
+pub use self::windows::*;
+
+#[cfg(target_os = "linux")]
+mod linux;
+#[cfg(target_os = "linux")]
+pub use self::linux::*;
@@ -0,0 +1,146 @@
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    path::PathBuf,
+    thread::JoinHandle,
+};
+
+#[cfg(target_os = "linux")]
+mod linux;
+#[cfg(target_os = "windows")]
+mod windows;
+
+/// Where one of a spawned process's standard streams goes.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Redirect {
+    /// The launcher's own stream, so that the process reads from or writes to the launcher's
+    /// terminal, even if it has no console of its own.
+    #[default]
+    Inherit,
+    /// Nowhere: output is discarded, and input is empty.
+    Null,
+    /// A pipe, whose other end is returned with the spawned process: in `ProcessInformation` on
+    /// Windows, and in the `Child` on Linux.
+    Pipe,
+    /// A file, which output is appended to.
+    File(PathBuf),
+}
+
+/// Which console a spawned process runs in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Console {
+    /// Console programs share the launcher's console, if it has one, and other programs get none,
+    /// as Windows does by default.
+    #[default]
+    Inherit,
+    /// A new console window, which payloads that write to the process's console also write to.
+    /// On Linux, the executable is started with Wine's `start`, which opens one.
+    New,
+    /// No console at all. Redirected streams still work. On Linux, the runner is started in a
+    /// new session, without the launcher's terminal.
+    Detached,
+}
+
+/// Where a spawned process's standard streams go, and which console it runs in.
+///
+/// By default, the streams are left as Windows sets them up, so a game without a console has
+/// nowhere to write its output, and neither do its payloads. Redirecting any of the streams hands
+/// the process all three, with the ones that are not redirected set to the launcher's own. To see
+/// the output of a game and its payloads in the launcher's terminal, redirect stdout and stderr
+/// to [`Redirect::Inherit`], or to [`Redirect::Pipe`] and forward them with `forward_output`.
+///
+/// On Linux, the options apply to Proton or Wine, which passes its streams on to the game.
+/// Streams that are not redirected are inherited from the launcher, as with
+/// [`std::process::Command`].
+///
+/// ```no_run
+/// # use re_utilities_injector::spawn::{Console, Redirect, StdioOptions};
+/// let stdio = StdioOptions::new()
+///     .with_stdout(Redirect::Pipe)
+///     .with_stderr(Redirect::Pipe)
+///     .with_console(Console::Detached);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct StdioOptions {
+    stdin: Option<Redirect>,
+    stdout: Option<Redirect>,
+    stderr: Option<Redirect>,
+    console: Console,
+}
+impl StdioOptions {
+    pub fn new() -> StdioOptions {
+        StdioOptions::default()
+    }
+
+    pub fn with_stdin(mut self, redirect: Redirect) -> Self {
+        self.stdin = Some(redirect);
+        self
+    }
+    pub fn with_stdout(mut self, redirect: Redirect) -> Self {
+        self.stdout = Some(redirect);
+        self
+    }
+    pub fn with_stderr(mut self, redirect: Redirect) -> Self {
+        self.stderr = Some(redirect);
+        self
+    }
+    pub fn with_console(mut self, console: Console) -> Self {
+        self.console = console;
+        self
+    }
+}
+
+/// Copies `from` to `to` a line at a time, so that lines from different streams are not mixed
+/// together, until `from` is closed.
+fn forward_lines(from: impl Read, mut to: impl Write) -> io::Result<()> {
+    let mut from = BufReader::new(from);
+    let mut line = vec![];
+    while from.read_until(b'\n', &mut line)? > 0 {
+        to.write_all(&line)?;
+        to.flush()?;
+        line.clear();
+    }
+    Ok(())
+}
+
+/// Copies a process's `stdout` and `stderr` pipes to the launcher's stdout and stderr, a line at
+/// a time, on threads that finish once the process closes them. Streams that were not redirected
+/// to pipes are passed as `None`, and left alone.
+pub(super) fn forward_output(
+    stdout: Option<impl Read + Send + 'static>,
+    stderr: Option<impl Read + Send + 'static>,
+) -> Vec<JoinHandle<io::Result<()>>> {
+    let mut threads = vec![];
+    if let Some(stdout) = stdout {
+        threads.push(std::thread::spawn(move || {
+            forward_lines(stdout, io::stdout())
+        }));
+    }
+    if let Some(stderr) = stderr {
+        threads.push(std::thread::spawn(move || {
+            forward_lines(stderr, io::stderr())
+        }));
+    }
+    threads
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn forward_lines_copies_everything() {
+        let mut to = vec![];
+        forward_lines(&b"first\nsecond\n\nlast without a newline"[..], &mut to).unwrap();
+        assert_eq!(to, b"first\nsecond\n\nlast without a newline");
+    }
+
+    #[test]
+    fn forward_output_skips_missing_streams() {
+        assert!(forward_output(None::<&[u8]>, None::<&[u8]>).is_empty());
+        let threads = forward_output(None::<&[u8]>, Some(&b""[..]));
+        assert_eq!(threads.len(), 1);
+        for thread in threads {
+            thread.join().unwrap().unwrap();
+        }
+    }
+}
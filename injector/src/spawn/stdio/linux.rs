@@ -0,0 +1,110 @@
+use anyhow::Context;
+use std::{
+    fs::{File, OpenOptions},
+    os::unix::process::CommandExt,
+    process::{Command, Stdio},
+};
+
+use super::{Console, Redirect, StdioOptions};
+
+impl StdioOptions {
+    /// The arguments to Wine that come before the executable, which start it in a new console
+    /// with Wine's `start`, waiting for it to exit so that the spawned process lasts as long as
+    /// it does.
+    pub(in crate::spawn) fn wine_start_args(&self) -> &'static [&'static str] {
+        match self.console {
+            Console::New => &["start", "/wait", "/unix"],
+            Console::Inherit | Console::Detached => &[],
+        }
+    }
+
+    /// Sets where the streams of the runner, and so of the executable it runs, go, and detaches
+    /// it from the launcher's terminal for [`Console::Detached`].
+    pub(in crate::spawn) fn apply(&self, command: &mut Command) -> anyhow::Result<()> {
+        if let Some(redirect) = &self.stdin {
+            command.stdin(open_stream(redirect, true).context("failed to open stdin")?);
+        }
+        if let Some(redirect) = &self.stdout {
+            command.stdout(open_stream(redirect, false).context("failed to open stdout")?);
+        }
+        if let Some(redirect) = &self.stderr {
+            command.stderr(open_stream(redirect, false).context("failed to open stderr")?);
+        }
+        if self.console == Console::Detached {
+            // A new session has no controlling terminal
+            unsafe {
+                command.pre_exec(|| {
+                    if libc::setsid() == -1 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                    Ok(())
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+fn open_stream(redirect: &Redirect, is_input: bool) -> anyhow::Result<Stdio> {
+    Ok(match redirect {
+        Redirect::Inherit => Stdio::inherit(),
+        Redirect::Null => Stdio::null(),
+        Redirect::Pipe => Stdio::piped(),
+        Redirect::File(path) => if is_input {
+            File::open(path)
+        } else {
+            OpenOptions::new().create(true).append(true).open(path)
+        }
+        .with_context(|| format!("failed to open {}", path.display()))?
+        .into(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn streams_are_redirected() {
+        let log_path =
+            std::env::temp_dir().join(format!("re-utilities-stdio-{}", std::process::id()));
+        let _ = std::fs::remove_file(&log_path);
+
+        let stdio = StdioOptions::new()
+            .with_stdin(Redirect::Null)
+            .with_stdout(Redirect::Pipe)
+            .with_stderr(Redirect::File(log_path.clone()))
+            .with_console(Console::Detached);
+        assert!(stdio.wine_start_args().is_empty());
+        let mut command = Command::new("sh");
+        command.args([
+            "-c",
+            "cat; echo out; echo err >&2; cut -d' ' -f6 /proc/$$/stat",
+        ]);
+        stdio.apply(&mut command).unwrap();
+        let mut child = command.spawn().unwrap();
+
+        let mut stdout = String::new();
+        child
+            .stdout
+            .take()
+            .unwrap()
+            .read_to_string(&mut stdout)
+            .unwrap();
+        assert!(child.wait().unwrap().success());
+        let mut lines = stdout.lines();
+        assert_eq!(lines.next(), Some("out"));
+        // The shell leads its own session
+        let session: u32 = lines.next().unwrap().trim().parse().unwrap();
+        assert_eq!(session, child.id());
+        assert_eq!(std::fs::read_to_string(&log_path).unwrap(), "err\n");
+        std::fs::remove_file(&log_path).unwrap();
+    }
+
+    #[test]
+    fn new_consoles_are_started_by_wine() {
+        let stdio = StdioOptions::new().with_console(Console::New);
+        assert_eq!(stdio.wine_start_args(), ["start", "/wait", "/unix"]);
+    }
+}
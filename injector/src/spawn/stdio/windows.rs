@@ -0,0 +1,214 @@
+use anyhow::Context;
+use std::{
+    fs::{File, OpenOptions},
+    os::windows::io::{AsRawHandle, BorrowedHandle, FromRawHandle, OwnedHandle},
+};
+
+use windows::Win32::{
+    Foundation::{SetHandleInformation, HANDLE, HANDLE_FLAG_INHERIT},
+    System::{
+        Console::{
+            GetStdHandle, STD_ERROR_HANDLE, STD_HANDLE, STD_INPUT_HANDLE, STD_OUTPUT_HANDLE,
+        },
+        Pipes::CreatePipe,
+        Threading::{
+            DeleteProcThreadAttributeList, InitializeProcThreadAttributeList,
+            UpdateProcThreadAttribute, CREATE_NEW_CONSOLE, DETACHED_PROCESS,
+            LPPROC_THREAD_ATTRIBUTE_LIST, PROCESS_CREATION_FLAGS,
+            PROC_THREAD_ATTRIBUTE_HANDLE_LIST, STARTF_USESTDHANDLES, STARTUPINFOW,
+        },
+    },
+};
+
+use super::{Console, Redirect, StdioOptions};
+
+impl StdioOptions {
+    pub(in crate::spawn) fn creation_flags(&self) -> PROCESS_CREATION_FLAGS {
+        match self.console {
+            Console::Inherit => PROCESS_CREATION_FLAGS(0),
+            Console::New => CREATE_NEW_CONSOLE,
+            Console::Detached => DETACHED_PROCESS,
+        }
+    }
+
+    /// Opens the streams to hand to the process, if any are redirected.
+    pub(in crate::spawn) fn open(&self) -> anyhow::Result<Option<ChildStdio>> {
+        if self.stdin.is_none() && self.stdout.is_none() && self.stderr.is_none() {
+            return Ok(None);
+        }
+        let inherit = Redirect::Inherit;
+        let (stdin, stdin_pipe) =
+            open_stream(self.stdin.as_ref().unwrap_or(&inherit), STD_INPUT_HANDLE)
+                .context("failed to open stdin")?;
+        let (stdout, stdout_pipe) =
+            open_stream(self.stdout.as_ref().unwrap_or(&inherit), STD_OUTPUT_HANDLE)
+                .context("failed to open stdout")?;
+        let (stderr, stderr_pipe) =
+            open_stream(self.stderr.as_ref().unwrap_or(&inherit), STD_ERROR_HANDLE)
+                .context("failed to open stderr")?;
+        Ok(Some(ChildStdio {
+            handles: [stdin, stdout, stderr],
+            stdin_pipe,
+            stdout_pipe,
+            stderr_pipe,
+        }))
+    }
+}
+
+/// The inheritable handles given to a spawned process as its standard streams, and the
+/// launcher's ends of any pipes to it. The process's handles must be dropped once it has been
+/// created, so that the pipes close when it exits.
+pub(in crate::spawn) struct ChildStdio {
+    handles: [Option<OwnedHandle>; 3],
+    pub stdin_pipe: Option<File>,
+    pub stdout_pipe: Option<File>,
+    pub stderr_pipe: Option<File>,
+}
+impl ChildStdio {
+    pub fn apply(&self, startup_info: &mut STARTUPINFOW) {
+        let [stdin, stdout, stderr] = self.handles.each_ref().map(|handle| {
+            handle
+                .as_ref()
+                .map_or(HANDLE::default(), |handle| HANDLE(handle.as_raw_handle()))
+        });
+        startup_info.dwFlags |= STARTF_USESTDHANDLES;
+        startup_info.hStdInput = stdin;
+        startup_info.hStdOutput = stdout;
+        startup_info.hStdError = stderr;
+    }
+
+    /// Builds the attribute list that limits what the process inherits to these handles, or
+    /// `None` if there are none to inherit.
+    pub fn handle_list(&self) -> anyhow::Result<Option<HandleList>> {
+        let handles: Vec<HANDLE> = self
+            .handles
+            .iter()
+            .flatten()
+            .map(|handle| HANDLE(handle.as_raw_handle()))
+            .collect();
+        if handles.is_empty() {
+            return Ok(None);
+        }
+        HandleList::new(handles).map(Some)
+    }
+}
+
+/// A process attribute list with `PROC_THREAD_ATTRIBUTE_HANDLE_LIST`, so that a process created
+/// with it inherits only the given handles, rather than every inheritable handle the launcher
+/// has open.
+pub(in crate::spawn) struct HandleList {
+    // The attribute list points to the handles, so both must outlive its use
+    buffer: Vec<usize>,
+    handles: Vec<HANDLE>,
+}
+impl HandleList {
+    fn new(handles: Vec<HANDLE>) -> anyhow::Result<HandleList> {
+        // The first call only reports the size the list needs
+        let mut size = 0;
+        let _ = unsafe {
+            InitializeProcThreadAttributeList(
+                LPPROC_THREAD_ATTRIBUTE_LIST::default(),
+                1,
+                0,
+                &mut size,
+            )
+        };
+        let mut buffer = vec![0usize; size.div_ceil(std::mem::size_of::<usize>())];
+        unsafe {
+            InitializeProcThreadAttributeList(
+                LPPROC_THREAD_ATTRIBUTE_LIST(buffer.as_mut_ptr() as _),
+                1,
+                0,
+                &mut size,
+            )
+        }
+        .context("failed to create an attribute list")?;
+
+        // From here on, the list is deleted when it is dropped
+        let mut list = HandleList { buffer, handles };
+        unsafe {
+            UpdateProcThreadAttribute(
+                list.as_raw(),
+                0,
+                PROC_THREAD_ATTRIBUTE_HANDLE_LIST as usize,
+                Some(list.handles.as_ptr() as _),
+                std::mem::size_of_val(list.handles.as_slice()),
+                None,
+                None,
+            )
+        }
+        .context("failed to set the handles to inherit")?;
+        Ok(list)
+    }
+
+    pub fn as_raw(&mut self) -> LPPROC_THREAD_ATTRIBUTE_LIST {
+        LPPROC_THREAD_ATTRIBUTE_LIST(self.buffer.as_mut_ptr() as _)
+    }
+}
+impl Drop for HandleList {
+    fn drop(&mut self) {
+        unsafe { DeleteProcThreadAttributeList(self.as_raw()) };
+    }
+}
+
+/// Returns the handle to give the process for one of its streams, and the launcher's end of the
+/// pipe if it is redirected to one.
+fn open_stream(
+    redirect: &Redirect,
+    std_handle: STD_HANDLE,
+) -> anyhow::Result<(Option<OwnedHandle>, Option<File>)> {
+    let is_input = std_handle == STD_INPUT_HANDLE;
+    match redirect {
+        Redirect::Inherit => {
+            // A launcher without a console may not have the stream at all
+            let handle = unsafe { GetStdHandle(std_handle) }.unwrap_or_default();
+            if handle.is_invalid() {
+                return Ok((None, None));
+            }
+            let handle = unsafe { BorrowedHandle::borrow_raw(handle.0) }.try_clone_to_owned()?;
+            Ok((Some(inheritable(handle)?), None))
+        }
+        Redirect::Null => {
+            let file = OpenOptions::new().read(true).write(true).open("NUL")?;
+            Ok((Some(inheritable(file.into())?), None))
+        }
+        Redirect::File(path) => {
+            let file = if is_input {
+                File::open(path)
+            } else {
+                OpenOptions::new().create(true).append(true).open(path)
+            }
+            .with_context(|| format!("failed to open {}", path.display()))?;
+            Ok((Some(inheritable(file.into())?), None))
+        }
+        Redirect::Pipe => {
+            let (mut read, mut write) = (HANDLE::default(), HANDLE::default());
+            unsafe { CreatePipe(&mut read, &mut write, None, 0) }
+                .context("failed to create a pipe")?;
+            let (read, write) = unsafe {
+                (
+                    OwnedHandle::from_raw_handle(read.0),
+                    OwnedHandle::from_raw_handle(write.0),
+                )
+            };
+            let (child, launcher) = if is_input {
+                (read, write)
+            } else {
+                (write, read)
+            };
+            Ok((Some(inheritable(child)?), Some(launcher.into())))
+        }
+    }
+}
+
+fn inheritable(handle: OwnedHandle) -> anyhow::Result<OwnedHandle> {
+    unsafe {
+        SetHandleInformation(
+            HANDLE(handle.as_raw_handle()),
+            HANDLE_FLAG_INHERIT.0,
+            HANDLE_FLAG_INHERIT,
+        )
+    }
+    .context("failed to make a handle inheritable")?;
+    Ok(handle)
+}
@@ -0,0 +1,160 @@
+use anyhow::Context;
+use std::{
+    fs::File,
+    io,
+    path::{Path, PathBuf},
+    thread::JoinHandle,
+};
+
+use windows::{
+    core::{Owned, HSTRING, PWSTR},
+    Win32::{Foundation::HANDLE, System::Threading},
+};
+
+use super::{command_line, stdio, Environment, StdioOptions};
+
+/// An owned variant of `Threading::PROCESS_INFORMATION`.
+pub struct ProcessInformation {
+    pub process: Owned<HANDLE>,
+    pub thread: Owned<HANDLE>,
+    pub process_id: u32,
+    pub thread_id: u32,
+    /// The launcher's ends of the pipes to the process's streams, for those that were redirected
+    /// to [`Redirect::Pipe`](super::Redirect::Pipe).
+    pub stdin: Option<File>,
+    pub stdout: Option<File>,
+    pub stderr: Option<File>,
+}
+impl From<Threading::PROCESS_INFORMATION> for ProcessInformation {
+    fn from(info: Threading::PROCESS_INFORMATION) -> Self {
+        Self {
+            process: unsafe { Owned::new(info.hProcess) },
+            thread: unsafe { Owned::new(info.hThread) },
+            process_id: info.dwProcessId,
+            thread_id: info.dwThreadId,
+            stdin: None,
+            stdout: None,
+            stderr: None,
+        }
+    }
+}
+impl ProcessInformation {
+    /// Copies the output from the process's stdout and stderr pipes to the launcher's stdout and
+    /// stderr, a line at a time, on threads that finish once the process closes them. Streams
+    /// that were not redirected to pipes are left alone.
+    pub fn forward_output(&mut self) -> Vec<JoinHandle<io::Result<()>>> {
+        stdio::forward_output(self.stdout.take(), self.stderr.take())
+    }
+}
+
+/// Spawns a process with the given executable, environment and arguments. The arguments are
+/// quoted with [`quote_arg`](super::quote_arg), so that the process receives them unchanged.
+///
+/// With `create_suspended`, the process's main thread does not run until it is resumed, and
+/// payloads can be loaded before its entry point with [`crate::inject_before_start`]. `stdio`
+/// sets where the process's streams go and which console it runs in.
+pub fn arbitrary_process<'a>(
+    game_path: &Path,
+    executable_path: &Path,
+    environment: &Environment,
+    args: impl IntoIterator<Item = &'a str>,
+    create_suspended: bool,
+    stdio: &StdioOptions,
+) -> anyhow::Result<ProcessInformation> {
+    let mut startup_info = Threading::STARTUPINFOEXW {
+        StartupInfo: Threading::STARTUPINFOW {
+            cb: std::mem::size_of::<Threading::STARTUPINFOW>() as u32,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let mut process_info = Threading::PROCESS_INFORMATION::default();
+
+    // The process's ends of any pipes are closed once it has its own copies, so that the pipes
+    // close when it exits. It inherits only those, and none of the launcher's other inheritable
+    // handles.
+    let child_stdio = stdio.open()?;
+    let mut handle_list = match &child_stdio {
+        Some(child_stdio) => {
+            child_stdio.apply(&mut startup_info.StartupInfo);
+            child_stdio.handle_list()?
+        }
+        None => None,
+    };
+
+    let mut creation_flags = Threading::CREATE_UNICODE_ENVIRONMENT | stdio.creation_flags();
+    if create_suspended {
+        creation_flags |= Threading::CREATE_SUSPENDED;
+    }
+    if let Some(handle_list) = &mut handle_list {
+        startup_info.StartupInfo.cb = std::mem::size_of::<Threading::STARTUPINFOEXW>() as u32;
+        startup_info.lpAttributeList = handle_list.as_raw();
+        creation_flags |= Threading::EXTENDED_STARTUPINFO_PRESENT;
+    }
+
+    // Variables that are not valid Unicode cannot be represented, and are not inherited
+    let inherited = std::env::vars_os()
+        .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)));
+    let environment = environment.to_block(inherited)?;
+
+    let mut commandline: Vec<u16> = command_line(&executable_path.to_string_lossy(), args)?
+        .encode_utf16()
+        .chain(std::iter::once(0))
+        .collect();
+
+    unsafe {
+        let application_name = HSTRING::from(executable_path.as_os_str());
+        let current_directory = HSTRING::from(game_path.as_os_str());
+        Threading::CreateProcessW(
+            &application_name,
+            PWSTR::from_raw(commandline.as_mut_ptr()),
+            None,
+            None,
+            handle_list.is_some(),
+            creation_flags,
+            Some(environment.as_ptr() as _),
+            &current_directory,
+            &startup_info.StartupInfo,
+            &mut process_info,
+        )
+        .context("failed to spawn process")?;
+    }
+
+    let mut process_info = ProcessInformation::from(process_info);
+    if let Some(child_stdio) = child_stdio {
+        process_info.stdin = child_stdio.stdin_pipe;
+        process_info.stdout = child_stdio.stdout_pipe;
+        process_info.stderr = child_stdio.stderr_pipe;
+    }
+    Ok(process_info)
+}
+
+/// Spawns a process for the given Steam app ID.
+pub fn steam_process<'a>(
+    app_id: u32,
+    executable_path_builder: impl Fn(&Path) -> PathBuf + Copy,
+    args: impl IntoIterator<Item = &'a str>,
+    create_suspended: bool,
+    stdio: &StdioOptions,
+) -> anyhow::Result<ProcessInformation> {
+    let steam_dir = steamlocate::SteamDir::locate()?;
+
+    let (app, library) = steam_dir
+        .find_app(app_id)?
+        .context("failed to locate app")?;
+    let game_path = library.resolve_app_dir(&app);
+    let executable_path = executable_path_builder(&game_path);
+
+    let environment = Environment::new()
+        .with_var("SteamGameId", app_id.to_string())
+        .with_var("SteamAppId", app_id.to_string());
+
+    arbitrary_process(
+        &game_path,
+        &executable_path,
+        &environment,
+        args,
+        create_suspended,
+        stdio,
+    )
+}
@@ -0,0 +1,347 @@
+use std::{collections::HashMap, ffi::c_void, path::Path};
+
+use anyhow::Context;
+use windows::{
+    core::s,
+    Win32::{
+        Foundation::HANDLE,
+        System::{
+            Diagnostics::Debug::{ReadProcessMemory, WriteProcessMemory},
+            Memory::{
+                VirtualAllocEx, VirtualFreeEx, VirtualProtectEx, MEM_COMMIT, MEM_RELEASE,
+                MEM_RESERVE, PAGE_EXECUTE, PAGE_EXECUTE_READ, PAGE_EXECUTE_READWRITE,
+                PAGE_NOACCESS, PAGE_PROTECTION_FLAGS, PAGE_READONLY, PAGE_READWRITE,
+            },
+        },
+    },
+};
+
+use super::{
+    call_remote, check_architecture, kernel32_proc_address, load_library, modules, InjectError,
+    RemoteArgument,
+};
+use crate::{
+    pe::{self, ImportName, Pe},
+    Architecture,
+};
+
+/// How many forwarders are followed when resolving an import before giving up.
+const MAX_FORWARDS: usize = 8;
+
+/// Maps a DLL into a process without the system loader, and returns the address it was mapped
+/// at. Unlike with [`super::inject`], the DLL does not need to be on disk and does not appear in
+/// the process's module list.
+///
+/// The DLL's image is relocated to where it was allocated, and its imports are bound to the
+/// modules the process has already loaded; any other modules it imports from are loaded with
+/// `LoadLibraryW`. Its TLS callbacks and entry point are then called on a new thread, as they
+/// would be by the loader. If the entry point returns `FALSE`, the error will be an
+/// [`InjectError`], and the DLL is left mapped.
+///
+/// The loader is not told about the DLL, so it cannot be ejected, does not receive thread
+/// notifications, and cannot use static thread-local storage. DLLs with TLS data are refused,
+/// and that includes every DLL that links Rust's standard library, which uses thread-locals
+/// itself: only `no_std` payloads and payloads written in other languages that avoid TLS data can
+/// be manually mapped. On x86, exception handlers in the DLL are not registered either.
+pub fn manual_map(process: HANDLE, payload: &[u8]) -> anyhow::Result<usize> {
+    let pe = Pe::parse(payload)?;
+    check_architecture(process, Architecture::from_pe_machine(pe.machine))?;
+    if !pe.is_dll {
+        anyhow::bail!("the payload is not a DLL");
+    }
+
+    let mut image = pe.map()?;
+    let tls = pe.tls(&image)?;
+    if tls.as_ref().is_some_and(|tls| tls.has_data) {
+        anyhow::bail!(
+            "the payload uses static thread-local storage, which cannot be manually mapped"
+        );
+    }
+
+    let base = unsafe {
+        VirtualAllocEx(
+            process,
+            None,
+            image.len(),
+            MEM_RESERVE | MEM_COMMIT,
+            PAGE_READWRITE,
+        )
+    };
+    if base.is_null() {
+        anyhow::bail!(
+            "failed to allocate memory in remote process: {:?}",
+            windows::core::Error::from_win32()
+        );
+    }
+    let result = map_image(process, &pe, &mut image, base as usize);
+    if result.is_err() {
+        unsafe {
+            let _ = VirtualFreeEx(process, base, 0, MEM_RELEASE);
+        }
+    }
+    result?;
+
+    initialize(process, &pe, base as usize, tls.map(|tls| tls.callbacks))?;
+    Ok(base as usize)
+}
+
+/// Relocates and binds the image for `base`, then writes it there and protects its sections.
+fn map_image(process: HANDLE, pe: &Pe, image: &mut [u8], base: usize) -> anyhow::Result<()> {
+    pe.relocate(image, base as u64)?;
+
+    let mut resolver = Resolver {
+        process,
+        modules: modules(process)?
+            .into_iter()
+            .map(|(name, module)| (name.to_lowercase(), module.0 as usize))
+            .collect(),
+    };
+    for import in pe.imports(image)? {
+        let address = resolver
+            .resolve(&import.module, &import.name)
+            .with_context(|| format!("failed to import {}!{}", import.module, import.name))?;
+        pe.bind_import(image, &import, address as u64)?;
+    }
+
+    unsafe {
+        WriteProcessMemory(
+            process,
+            base as *const c_void,
+            image.as_ptr() as *const c_void,
+            image.len(),
+            None,
+        )
+        .context("failed to write the image")?;
+
+        let mut old = PAGE_PROTECTION_FLAGS::default();
+        VirtualProtectEx(
+            process,
+            base as *const c_void,
+            pe.size_of_headers as usize,
+            PAGE_READONLY,
+            &mut old,
+        )
+        .context("failed to protect the headers")?;
+        for section in &pe.sections {
+            if section.mapped_size() == 0 {
+                continue;
+            }
+            VirtualProtectEx(
+                process,
+                (base + section.virtual_address as usize) as *const c_void,
+                section.mapped_size() as usize,
+                section_protection(section),
+                &mut old,
+            )
+            .with_context(|| format!("failed to protect section {}", section.name))?;
+        }
+    }
+    Ok(())
+}
+
+fn section_protection(section: &pe::Section) -> PAGE_PROTECTION_FLAGS {
+    match (
+        section.is_executable(),
+        section.is_readable(),
+        section.is_writable(),
+    ) {
+        (true, _, true) => PAGE_EXECUTE_READWRITE,
+        (true, true, false) => PAGE_EXECUTE_READ,
+        (true, false, false) => PAGE_EXECUTE,
+        (false, _, true) => PAGE_READWRITE,
+        (false, true, false) => PAGE_READONLY,
+        (false, false, false) => PAGE_NOACCESS,
+    }
+}
+
+/// Finds the addresses of functions exported by modules in the target process.
+struct Resolver {
+    process: HANDLE,
+    // Base addresses by lowercase module name
+    modules: HashMap<String, usize>,
+}
+
+impl Resolver {
+    fn resolve(&mut self, module: &str, name: &ImportName) -> anyhow::Result<usize> {
+        let (mut module, mut name) = (module.to_owned(), name.clone());
+        for _ in 0..MAX_FORWARDS {
+            let base = self.module(&module)?;
+
+            // The export directory is read by itself, as other parts of the module may not be
+            // readable
+            let headers = read_remote(self.process, base, 0x1000)?;
+            let directory = Pe::parse(&headers)?.data_directory(pe::directory::EXPORT);
+            if directory.is_empty() {
+                anyhow::bail!("{module} has no exports");
+            }
+            let window = read_remote(
+                self.process,
+                base + directory.rva as usize,
+                directory.size as usize,
+            )?;
+            match pe::find_export(&window, directory.rva, directory, &name)? {
+                Some(pe::Export::Rva(rva)) => return Ok(base + rva as usize),
+                Some(pe::Export::Forwarder(forwarder)) => {
+                    (module, name) = pe::parse_forwarder(&forwarder)
+                        .with_context(|| format!("malformed forwarder {forwarder}"))?;
+                }
+                None => anyhow::bail!("{module} does not export {name}"),
+            }
+        }
+        anyhow::bail!("too many forwarders")
+    }
+
+    /// The base address of a module in the target process, which is loaded if it is not
+    /// already.
+    fn module(&mut self, module: &str) -> anyhow::Result<usize> {
+        let key = module.to_lowercase();
+        if let Some(base) = self.modules.get(&key) {
+            return Ok(*base);
+        }
+        // This also finds the modules that implement API sets, which are not listed by name
+        let base = load_library(self.process, Path::new(module))?.0 as usize;
+        self.modules.insert(key, base);
+        Ok(base)
+    }
+}
+
+fn read_remote(process: HANDLE, address: usize, len: usize) -> anyhow::Result<Vec<u8>> {
+    let mut buffer = vec![0u8; len];
+    unsafe {
+        ReadProcessMemory(
+            process,
+            address as *const c_void,
+            buffer.as_mut_ptr() as *mut c_void,
+            len,
+            None,
+        )
+    }
+    .with_context(|| format!("failed to read {len} bytes at {address:#x}"))?;
+    Ok(buffer)
+}
+
+/// Registers the image's exception handlers, then calls its TLS callbacks and entry point as
+/// the loader would for `DLL_PROCESS_ATTACH`.
+fn initialize(
+    process: HANDLE,
+    pe: &Pe,
+    base: usize,
+    tls_callbacks: Option<Vec<u32>>,
+) -> anyhow::Result<()> {
+    const DLL_PROCESS_ATTACH: usize = 1;
+
+    let mut calls = vec![];
+    let exceptions = pe.data_directory(pe::directory::EXCEPTION);
+    if cfg!(target_arch = "x86_64") && !exceptions.is_empty() {
+        // Each RUNTIME_FUNCTION entry is 12 bytes
+        calls.push([
+            kernel32_proc_address(s!("RtlAddFunctionTable"))?,
+            base + exceptions.rva as usize,
+            exceptions.size as usize / 12,
+            base,
+        ]);
+    }
+    for callback in tls_callbacks.unwrap_or_default() {
+        calls.push([base + callback as usize, base, DLL_PROCESS_ATTACH, 0]);
+    }
+    if pe.entry_point != 0 {
+        calls.push([base + pe.entry_point as usize, base, DLL_PROCESS_ATTACH, 0]);
+    }
+    if calls.is_empty() {
+        return Ok(());
+    }
+
+    let value = call_sequence(process, &calls)?;
+    if pe.entry_point != 0 && value as u32 == 0 {
+        return Err(InjectError::EntryPointFailed.into());
+    }
+    Ok(())
+}
+
+/// Calls each function in `calls` with its three arguments in order on a new thread in the
+/// target process, and returns what the last one returned.
+fn call_sequence(process: HANDLE, calls: &[[usize; 4]]) -> anyhow::Result<usize> {
+    let table: Vec<u8> = calls
+        .iter()
+        .flatten()
+        .chain(&[0, 0, 0, 0])
+        .flat_map(|value| value.to_ne_bytes())
+        .collect();
+    let stub = sequence_stub();
+
+    unsafe {
+        let alloc = VirtualAllocEx(
+            process,
+            None,
+            stub.len(),
+            MEM_RESERVE | MEM_COMMIT,
+            PAGE_READWRITE,
+        );
+        if alloc.is_null() {
+            anyhow::bail!(
+                "failed to allocate memory in remote process: {:?}",
+                windows::core::Error::from_win32()
+            );
+        }
+        let mut old = PAGE_PROTECTION_FLAGS::default();
+        WriteProcessMemory(
+            process,
+            alloc,
+            stub.as_ptr() as *const c_void,
+            stub.len(),
+            None,
+        )
+        .context("failed to write memory")?;
+        VirtualProtectEx(process, alloc, stub.len(), PAGE_EXECUTE_READ, &mut old)
+            .context("failed to protect memory")?;
+
+        // The stub cannot be freed if it may still be running
+        let result = call_remote(process, alloc as usize, RemoteArgument::Data(&table));
+        let timed_out = matches!(&result, Err(err) if matches!(err.downcast_ref(), Some(InjectError::Timeout(_))));
+        if !timed_out {
+            VirtualFreeEx(process, alloc, 0, MEM_RELEASE).context("failed to free memory")?;
+        }
+        Ok(result?.value)
+    }
+}
+
+/// Machine code for a function that takes a table of calls, each a function followed by its
+/// three arguments and ending with a null function, and makes them in order with the `system`
+/// calling convention. It returns what the last call returned.
+fn sequence_stub() -> Vec<u8> {
+    #[cfg(target_arch = "x86_64")]
+    let stub: &[&[u8]] = &[
+        &[0x56],                         // push rsi
+        &[0x48, 0x83, 0xEC, 0x20],       // sub rsp, 0x20
+        &[0x48, 0x89, 0xCE],             // mov rsi, rcx
+        &[0xB8, 0x01, 0x00, 0x00, 0x00], // mov eax, 1
+        &[0x48, 0x83, 0x3E, 0x00],       // next: cmp qword [rsi], 0
+        &[0x74, 0x14],                   // je done
+        &[0x48, 0x8B, 0x4E, 0x08],       // mov rcx, [rsi + 8]
+        &[0x48, 0x8B, 0x56, 0x10],       // mov rdx, [rsi + 0x10]
+        &[0x4C, 0x8B, 0x46, 0x18],       // mov r8, [rsi + 0x18]
+        &[0xFF, 0x16],                   // call [rsi]
+        &[0x48, 0x83, 0xC6, 0x20],       // add rsi, 0x20
+        &[0xEB, 0xE6],                   // jmp next
+        &[0x48, 0x83, 0xC4, 0x20],       // done: add rsp, 0x20
+        &[0x5E],                         // pop rsi
+        &[0xC3],                         // ret
+    ];
+    #[cfg(target_arch = "x86")]
+    let stub: &[&[u8]] = &[
+        &[0x56],                         // push esi
+        &[0x8B, 0x74, 0x24, 0x08],       // mov esi, [esp + 8]
+        &[0xB8, 0x01, 0x00, 0x00, 0x00], // mov eax, 1
+        &[0x83, 0x3E, 0x00],             // next: cmp dword [esi], 0
+        &[0x74, 0x10],                   // je done
+        &[0xFF, 0x76, 0x0C],             // push dword [esi + 0xC]
+        &[0xFF, 0x76, 0x08],             // push dword [esi + 8]
+        &[0xFF, 0x76, 0x04],             // push dword [esi + 4]
+        &[0xFF, 0x16],                   // call [esi]
+        &[0x83, 0xC6, 0x10],             // add esi, 0x10
+        &[0xEB, 0xEB],                   // jmp next
+        &[0x5E],                         // done: pop esi
+        &[0xC2, 0x04, 0x00],             // ret 4
+    ];
+    stub.concat()
+}
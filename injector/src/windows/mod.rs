@@ -0,0 +1,789 @@
+use std::{
+    ffi::c_void,
+    fmt,
+    os::windows::ffi::OsStrExt,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use windows::{
+    core::{s, w, Owned, HRESULT, PCSTR},
+    Win32::{
+        Foundation::{HANDLE, HMODULE, WAIT_OBJECT_0, WAIT_TIMEOUT},
+        System::{
+            Diagnostics::{
+                Debug::{ReadProcessMemory, WriteProcessMemory},
+                ToolHelp::{
+                    CreateToolhelp32Snapshot, Module32FirstW, Module32NextW, Process32FirstW,
+                    Process32NextW, MODULEENTRY32W, PROCESSENTRY32W, TH32CS_SNAPMODULE,
+                    TH32CS_SNAPMODULE32, TH32CS_SNAPPROCESS,
+                },
+            },
+            LibraryLoader::{GetModuleHandleW, GetProcAddress},
+            Memory::{
+                VirtualAllocEx, VirtualFreeEx, MEM_COMMIT, MEM_RELEASE, MEM_RESERVE,
+                PAGE_EXECUTE_READWRITE,
+            },
+            SystemInformation::IMAGE_FILE_MACHINE_UNKNOWN,
+            Threading::{
+                CreateRemoteThread, GetExitCodeProcess, GetExitCodeThread, GetProcessId,
+                IsWow64Process2, OpenProcess, QueueUserAPC, ResumeThread, SuspendThread,
+                WaitForSingleObject, INFINITE, PROCESS_CREATE_THREAD, PROCESS_QUERY_INFORMATION,
+                PROCESS_SYNCHRONIZE, PROCESS_TERMINATE, PROCESS_VM_OPERATION, PROCESS_VM_READ,
+                PROCESS_VM_WRITE,
+            },
+        },
+    },
+};
+
+use crate::{payload, Architecture};
+
+mod manual_map;
+pub(crate) mod query;
+
+pub use manual_map::manual_map;
+
+/// How long [`inject`] and [`eject`] wait for the payload to load or unload.
+const LOAD_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The ways in which loading or unloading the payload in the target process can fail.
+///
+/// [`inject`] and [`eject`] return these wrapped in an [`anyhow::Error`]; use `downcast_ref` to
+/// tell them apart from other failures.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InjectError {
+    /// The payload did not finish loading or unloading in time. It may still do so later.
+    Timeout(Duration),
+    /// `LoadLibraryW` returned null in the target process, with the error code it set.
+    LoadLibraryFailed { last_error: u32 },
+    /// `FreeLibrary` failed in the target process, with the error code it set.
+    FreeLibraryFailed { last_error: u32 },
+    /// The loader thread exited without returning from `LoadLibraryW` or `FreeLibrary`, such as
+    /// when the payload's `DllMain` crashes or exits the thread, with the thread's exit code.
+    LoaderThreadFailed { exit_code: u32 },
+    /// The target process exited before the payload was loaded, with its exit code.
+    ProcessExited { exit_code: u32 },
+    /// The entry point of a manually mapped payload returned `FALSE`.
+    EntryPointFailed,
+    /// The payload or the target process was built for a different architecture than the
+    /// injector. Nothing was written into the target process.
+    ArchitectureMismatch {
+        injector: Architecture,
+        target: Architecture,
+        payload: Architecture,
+    },
+}
+impl fmt::Display for InjectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InjectError::Timeout(timeout) => {
+                write!(
+                    f,
+                    "timed out after {timeout:?} waiting for the payload to load or unload"
+                )
+            }
+            InjectError::LoadLibraryFailed { last_error } => write!(
+                f,
+                "LoadLibraryW failed in the target process: {} (error {last_error})",
+                HRESULT::from_win32(*last_error).message()
+            ),
+            InjectError::FreeLibraryFailed { last_error } => write!(
+                f,
+                "FreeLibrary failed in the target process: {} (error {last_error})",
+                HRESULT::from_win32(*last_error).message()
+            ),
+            InjectError::LoaderThreadFailed { exit_code } => write!(
+                f,
+                "the loader thread exited with code {exit_code:#x} before the payload was loaded or unloaded"
+            ),
+            InjectError::ProcessExited { exit_code } => write!(
+                f,
+                "the target process exited with code {exit_code:#x} before the payload was loaded"
+            ),
+            InjectError::EntryPointFailed => {
+                write!(f, "the payload's entry point returned FALSE")
+            }
+            InjectError::ArchitectureMismatch {
+                injector,
+                target,
+                payload,
+            } => write!(
+                f,
+                "the payload ({payload}), target process ({target}) and injector ({injector}) must have the same architecture"
+            ),
+        }
+    }
+}
+impl std::error::Error for InjectError {}
+
+/// Injects a DLL into a process, returning its module handle in that process. To get a process
+/// handle, use [`get_processes_by_name`] or functions from [`crate::spawn`].
+///
+/// The DLL is loaded from a copy named `<stem>_loaded.<ext>` next to it, so that it can be
+/// rebuilt while the process is running. See [`reload`] to load a rebuilt DLL.
+///
+/// If the payload fails to load, the error will be an [`InjectError`]. This returns as soon as
+/// the payload has loaded; use [`wait_for_exit`] to wait for the process to exit afterwards.
+///
+/// The process and payload must have the same architecture as the injector; a 64-bit injector
+/// can only inject a 64-bit DLL into a 64-bit process. If they do not, the error will be an
+/// [`InjectError`].
+pub fn inject(process: HANDLE, payload_path: &Path) -> anyhow::Result<HMODULE> {
+    check_architecture(process, Architecture::of_file(payload_path)?)?;
+    let injected_payload_path = payload::copy(payload_path, false)?;
+    load_library(process, &injected_payload_path)
+}
+
+fn load_library(process: HANDLE, path: &Path) -> anyhow::Result<HMODULE> {
+    let load_library = kernel32_proc_address(s!("LoadLibraryW"))?;
+    let result = unsafe {
+        call_remote(
+            process,
+            load_library,
+            RemoteArgument::Data(&wide_path(path)),
+        )?
+    };
+    loaded_module(result)
+}
+
+/// The module handle returned by a remote call to `LoadLibraryW`.
+fn loaded_module(result: RemoteCall) -> anyhow::Result<HMODULE> {
+    if result.value == 0 {
+        return Err(InjectError::LoadLibraryFailed {
+            last_error: result.last_error,
+        }
+        .into());
+    }
+    Ok(HMODULE(result.value as *mut c_void))
+}
+
+/// A path as the NUL-terminated UTF-16 that `LoadLibraryW` takes.
+fn wide_path(path: &Path) -> Vec<u8> {
+    path.as_os_str()
+        .encode_wide()
+        .chain(std::iter::once(0))
+        .flat_map(u16::to_ne_bytes)
+        .collect()
+}
+
+/// Injects several DLLs into a process in order, returning their module handles. Stops at the
+/// first payload that fails to inject; the payloads before it stay loaded.
+pub fn inject_all<P: AsRef<Path>>(
+    process: HANDLE,
+    payload_paths: impl IntoIterator<Item = P>,
+) -> anyhow::Result<Vec<HMODULE>> {
+    payload_paths
+        .into_iter()
+        .map(|payload_path| {
+            let payload_path = payload_path.as_ref();
+            inject(process, payload_path)
+                .with_context(|| format!("failed to inject {}", payload_path.display()))
+        })
+        .collect()
+}
+
+/// Injects DLLs into a process that was spawned suspended, loading them in order on its main
+/// thread before the executable's entry point runs, and returns their module handles. This lets
+/// a payload install its hooks before the executable's own initialisers run, which [`inject`]
+/// cannot guarantee as its loader thread runs alongside them.
+///
+/// The loads are queued as APCs on `thread`, which must be the main thread of a process created
+/// with `create_suspended` in [`crate::spawn`] that has not been resumed yet. The thread runs them
+/// as soon as it has initialised the process, and is left suspended again afterwards, so that it
+/// can be resumed once the payloads are ready. Note that the payloads' `DllMain`s run while the
+/// process is still initialising, so threads they start only run once this has returned.
+///
+/// If a payload fails to load, the error will be an [`InjectError`]; the payloads after it are
+/// still loaded.
+pub fn inject_before_start<P: AsRef<Path>>(
+    process: HANDLE,
+    thread: HANDLE,
+    payload_paths: impl IntoIterator<Item = P>,
+) -> anyhow::Result<Vec<HMODULE>> {
+    let payload_paths: Vec<_> = payload_paths
+        .into_iter()
+        .map(|payload_path| {
+            let payload_path = payload_path.as_ref();
+            check_architecture(process, Architecture::of_file(payload_path)?)?;
+            payload::copy(payload_path, false)
+        })
+        .collect::<anyhow::Result<_>>()?;
+    if payload_paths.is_empty() {
+        return Ok(vec![]);
+    }
+
+    unsafe {
+        // APCs only run before the entry point if the thread has never been resumed
+        let suspend_count = SuspendThread(thread);
+        if suspend_count == u32::MAX {
+            anyhow::bail!(
+                "failed to suspend the main thread: {:?}",
+                windows::core::Error::from_win32()
+            );
+        }
+        ResumeThread(thread);
+        if suspend_count != 1 {
+            anyhow::bail!(
+                "the main thread must be suspended once, but was suspended {suspend_count} times"
+            );
+        }
+
+        let load_library = kernel32_proc_address(s!("LoadLibraryW"))?;
+        let mut calls = vec![];
+        for (index, path) in payload_paths.iter().enumerate() {
+            let suspend_after = index == payload_paths.len() - 1;
+            let call = prepare_call(
+                process,
+                load_library,
+                RemoteArgument::Data(&wide_path(path)),
+                suspend_after,
+            )?;
+            write_call(process, &call)?;
+            #[allow(clippy::missing_transmute_annotations)]
+            if QueueUserAPC(
+                Some(std::mem::transmute(call.alloc.byte_add(call.stub_offset))),
+                thread,
+                call.alloc as usize,
+            ) == 0
+            {
+                anyhow::bail!(
+                    "failed to queue the load of {}: {:?}",
+                    path.display(),
+                    windows::core::Error::from_win32()
+                );
+            }
+            calls.push(call);
+        }
+
+        if ResumeThread(thread) == u32::MAX {
+            anyhow::bail!(
+                "failed to resume the main thread: {:?}",
+                windows::core::Error::from_win32()
+            );
+        }
+
+        // The last call suspends the thread once it has completed
+        let last_call = calls.last().expect("no calls were queued");
+        let deadline = Instant::now() + LOAD_TIMEOUT;
+        while read_call_data(process, last_call)?.completed == 0 || !is_suspended(thread)? {
+            if let Some(exit_code) = wait_for_exit(process, Some(Duration::ZERO))? {
+                return Err(InjectError::ProcessExited { exit_code }.into());
+            }
+            if Instant::now() >= deadline {
+                return Err(InjectError::Timeout(LOAD_TIMEOUT).into());
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        let mut results = vec![];
+        for (index, call) in calls.iter().enumerate() {
+            let data = read_call_data(process, call)?;
+            // The thread is still inside the last call's stub, so its memory is left behind
+            if index != calls.len() - 1 {
+                VirtualFreeEx(process, call.alloc, 0, MEM_RELEASE)
+                    .context("failed to free memory")?;
+            }
+            results.push(RemoteCall {
+                value: data.value,
+                last_error: data.last_error,
+            });
+        }
+        results
+            .into_iter()
+            .zip(&payload_paths)
+            .map(|(result, path)| {
+                loaded_module(result)
+                    .with_context(|| format!("failed to inject {}", path.display()))
+            })
+            .collect()
+    }
+}
+
+/// Whether a thread is suspended, which is checked by suspending it again.
+unsafe fn is_suspended(thread: HANDLE) -> anyhow::Result<bool> {
+    let suspend_count = SuspendThread(thread);
+    if suspend_count == u32::MAX {
+        anyhow::bail!(
+            "failed to suspend the main thread: {:?}",
+            windows::core::Error::from_win32()
+        );
+    }
+    ResumeThread(thread);
+    Ok(suspend_count > 0)
+}
+
+/// Unloads a module from a process by calling `FreeLibrary` on it there. A module that was
+/// loaded more than once stays loaded until it has been ejected as many times.
+///
+/// The payload's `DllMain` runs under the loader lock, so a payload that needs the loader to shut
+/// down, such as by ending threads, should be shut down before it is ejected; payloads built with
+/// `#[payload_main]` can export a function that calls `re_utilities::payload::shutdown` for this.
+///
+/// If `FreeLibrary` fails, the error will be an [`InjectError`].
+pub fn eject(process: HANDLE, module: HMODULE) -> anyhow::Result<()> {
+    let free_library = kernel32_proc_address(s!("FreeLibrary"))?;
+    let result = unsafe {
+        call_remote(
+            process,
+            free_library,
+            RemoteArgument::Value(module.0 as usize),
+        )?
+    };
+    if result.value == 0 {
+        return Err(InjectError::FreeLibraryFailed {
+            last_error: result.last_error,
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// Ejects any copies of a DLL that were injected into a process, then injects it again from a
+/// fresh copy, returning its new module handle.
+///
+/// Each reload loads the DLL from a copy with a unique name, as a copy that is still in use
+/// cannot be replaced. Copies left behind by earlier reloads are deleted once they are no longer
+/// in use.
+pub fn reload(process: HANDLE, payload_path: &Path) -> anyhow::Result<HMODULE> {
+    check_architecture(process, Architecture::of_file(payload_path)?)?;
+    for (name, module) in modules(process)? {
+        if payload::is_copy(payload_path, &name) {
+            eject(process, module).with_context(|| format!("failed to eject {name}"))?;
+        }
+    }
+
+    let injected_payload_path = payload::copy(payload_path, true)?;
+    load_library(process, &injected_payload_path)
+}
+
+/// Returns the architecture a process runs as, which for a 32-bit process on 64-bit Windows is
+/// the 32-bit architecture.
+pub fn process_architecture(process: HANDLE) -> anyhow::Result<Architecture> {
+    let mut process_machine = IMAGE_FILE_MACHINE_UNKNOWN;
+    let mut native_machine = IMAGE_FILE_MACHINE_UNKNOWN;
+    unsafe { IsWow64Process2(process, &mut process_machine, Some(&mut native_machine)) }
+        .context("failed to get the architecture of the process")?;
+    // The process machine is only set for processes running under WOW64
+    let machine = if process_machine == IMAGE_FILE_MACHINE_UNKNOWN {
+        native_machine
+    } else {
+        process_machine
+    };
+    Ok(Architecture::from_pe_machine(machine.0))
+}
+
+/// Fails with [`InjectError::ArchitectureMismatch`] unless the process and payload have the same
+/// architecture as the injector.
+fn check_architecture(process: HANDLE, payload: Architecture) -> anyhow::Result<()> {
+    let target = process_architecture(process)?;
+    if target != Architecture::CURRENT || payload != Architecture::CURRENT {
+        return Err(InjectError::ArchitectureMismatch {
+            injector: Architecture::CURRENT,
+            target,
+            payload,
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// Returns the filename and handle of each module loaded in a process.
+fn modules(process: HANDLE) -> anyhow::Result<Vec<(String, HMODULE)>> {
+    module_entries(unsafe { GetProcessId(process) })
+}
+
+/// Returns the filename and handle of each module loaded in the process with the given ID.
+fn module_entries(pid: u32) -> anyhow::Result<Vec<(String, HMODULE)>> {
+    unsafe {
+        let snapshot = Owned::new(
+            CreateToolhelp32Snapshot(TH32CS_SNAPMODULE | TH32CS_SNAPMODULE32, pid)
+                .with_context(|| format!("failed to list modules of process {pid}"))?,
+        );
+        let mut entry = MODULEENTRY32W {
+            dwSize: std::mem::size_of::<MODULEENTRY32W>() as u32,
+            ..Default::default()
+        };
+
+        let mut modules = vec![];
+        if Module32FirstW(*snapshot, &mut entry).is_ok() {
+            loop {
+                let name = String::from_utf16_lossy(&entry.szModule)
+                    .trim_end_matches('\0')
+                    .to_owned();
+                modules.push((name, entry.hModule));
+
+                if Module32NextW(*snapshot, &mut entry).is_err() {
+                    break;
+                }
+            }
+        }
+        Ok(modules)
+    }
+}
+
+/// Waits for a process to exit, returning its exit code, or `None` if it is still running after
+/// `timeout`. Waits forever if `timeout` is `None`.
+pub fn wait_for_exit(process: HANDLE, timeout: Option<Duration>) -> anyhow::Result<Option<u32>> {
+    let timeout = timeout.map_or(INFINITE, |timeout| {
+        timeout.as_millis().min(INFINITE as u128 - 1) as u32
+    });
+    let wait = unsafe { WaitForSingleObject(process, timeout) };
+    if wait == WAIT_TIMEOUT {
+        return Ok(None);
+    }
+    if wait != WAIT_OBJECT_0 {
+        anyhow::bail!(
+            "failed to wait for process: {:?}",
+            windows::core::Error::from_win32()
+        );
+    }
+
+    let mut exit_code = 0;
+    unsafe { GetExitCodeProcess(process, &mut exit_code) }
+        .context("failed to get process exit code")?;
+    Ok(Some(exit_code))
+}
+
+/// Waits for a process to load a module whose file name matches `pattern`, and returns its handle,
+/// or `None` if it has not been loaded after `timeout`. Waits forever if `timeout` is `None`.
+/// The pattern is matched as in [`crate::ProcessQuery::with_module`].
+///
+/// If the process exits first, the error will be an [`InjectError`].
+pub fn wait_for_module(
+    process: HANDLE,
+    pattern: &str,
+    timeout: Option<Duration>,
+) -> anyhow::Result<Option<HMODULE>> {
+    crate::query::poll(timeout, || {
+        if let Some(exit_code) = wait_for_exit(process, Some(Duration::ZERO))? {
+            return Err(InjectError::ProcessExited { exit_code }.into());
+        }
+        // Modules cannot be listed while the process is starting up, which is retried
+        let Ok(modules) = modules(process) else {
+            return Ok(None);
+        };
+        Ok(modules
+            .into_iter()
+            .find(|(name, _)| crate::query::glob_matches(pattern, name))
+            .map(|(_, module)| module))
+    })
+}
+
+/// Gets the address of a function exported by `kernel32.dll`, which is the same in every
+/// process of the same architecture.
+fn kernel32_proc_address(name: PCSTR) -> anyhow::Result<usize> {
+    unsafe {
+        let kernel32_module =
+            GetModuleHandleW(w!("kernel32.dll")).context("failed to get module")?;
+        GetProcAddress(kernel32_module, name)
+            .map(|f| f as usize)
+            .with_context(|| {
+                format!(
+                    "failed to get {} address: {:?}",
+                    name.display(),
+                    windows::core::Error::from_win32()
+                )
+            })
+    }
+}
+
+/// The argument to a function called with [`call_remote`].
+enum RemoteArgument<'a> {
+    Value(usize),
+    /// Copied into the target process, which is passed a pointer to the copy.
+    Data(&'a [u8]),
+}
+
+/// What a function called with [`call_remote`] returned.
+struct RemoteCall {
+    value: usize,
+    last_error: u32,
+}
+
+/// The data the call stub reads and writes in the target process. Any argument data follows it.
+#[repr(C)]
+struct RemoteCallData {
+    function: usize,
+    get_last_error: usize,
+    /// `SuspendThread`, if the stub should suspend its thread once the call has completed.
+    suspend_thread: usize,
+    argument: usize,
+    value: usize,
+    last_error: u32,
+    completed: u32,
+}
+
+/// A call that has been laid out in memory allocated for it in the target process, along with
+/// the stub that makes it.
+struct PreparedCall {
+    alloc: *mut c_void,
+    block: Vec<u8>,
+    stub_offset: usize,
+}
+
+/// Calls `function` with `argument` on a new thread in the target process, and waits for it to
+/// return. Its return value is not truncated to 32 bits like the thread's exit code would be,
+/// and is returned along with `GetLastError`.
+///
+/// `function` must take a single pointer-sized argument and use the `system` calling convention.
+unsafe fn call_remote(
+    process: HANDLE,
+    function: usize,
+    argument: RemoteArgument,
+) -> anyhow::Result<RemoteCall> {
+    let call = prepare_call(process, function, argument, false)?;
+
+    // Run the stub, then free its memory unless it is still running
+    let result = run_stub(process, &call);
+    if !matches!(result, Ok(None)) {
+        VirtualFreeEx(process, call.alloc, 0, MEM_RELEASE).context("failed to free memory")?;
+    }
+    let Some((data, exit_code)) = result? else {
+        return Err(InjectError::Timeout(LOAD_TIMEOUT).into());
+    };
+    if data.completed == 0 {
+        return Err(InjectError::LoaderThreadFailed { exit_code }.into());
+    }
+    Ok(RemoteCall {
+        value: data.value,
+        last_error: data.last_error,
+    })
+}
+
+/// Allocates memory in the target process for a call to `function` with `argument`, and lays out
+/// the call's data, the argument data and the stub in one block for it. Nothing is written to
+/// the memory yet.
+unsafe fn prepare_call(
+    process: HANDLE,
+    function: usize,
+    argument: RemoteArgument,
+    suspend_after: bool,
+) -> anyhow::Result<PreparedCall> {
+    let data_offset = std::mem::size_of::<RemoteCallData>();
+    let (argument, argument_data) = match argument {
+        RemoteArgument::Value(value) => (value, &[][..]),
+        RemoteArgument::Data(data) => (0, data),
+    };
+    let stub_offset = (data_offset + argument_data.len()).next_multiple_of(16);
+    let stub = call_stub();
+
+    // Allocate memory in the target process
+    let alloc = VirtualAllocEx(
+        process,
+        None,
+        stub_offset + stub.len(),
+        MEM_RESERVE | MEM_COMMIT,
+        PAGE_EXECUTE_READWRITE,
+    );
+    if alloc.is_null() {
+        anyhow::bail!(
+            "failed to allocate memory in remote process: {:?}",
+            windows::core::Error::from_win32()
+        );
+    }
+
+    let data = RemoteCallData {
+        function,
+        get_last_error: kernel32_proc_address(s!("GetLastError"))?,
+        suspend_thread: if suspend_after {
+            kernel32_proc_address(s!("SuspendThread"))?
+        } else {
+            0
+        },
+        argument: if argument_data.is_empty() {
+            argument
+        } else {
+            alloc as usize + data_offset
+        },
+        value: 0,
+        last_error: 0,
+        completed: 0,
+    };
+    let mut block = vec![0u8; stub_offset + stub.len()];
+    block[..data_offset].copy_from_slice(std::slice::from_raw_parts(
+        &data as *const RemoteCallData as *const u8,
+        data_offset,
+    ));
+    block[data_offset..data_offset + argument_data.len()].copy_from_slice(argument_data);
+    block[stub_offset..].copy_from_slice(&stub);
+
+    Ok(PreparedCall {
+        alloc,
+        block,
+        stub_offset,
+    })
+}
+
+/// Writes a call to its memory in the target process, then runs its stub on a new thread and
+/// reads back the call's data along with the thread's exit code. Returns `None` if it did not
+/// finish in time.
+unsafe fn run_stub(
+    process: HANDLE,
+    call: &PreparedCall,
+) -> anyhow::Result<Option<(RemoteCallData, u32)>> {
+    write_call(process, call)?;
+
+    // Create a remote thread to make the call
+    #[allow(clippy::missing_transmute_annotations)]
+    let thread_handle = Owned::new(
+        CreateRemoteThread(
+            process,
+            None,
+            0,
+            Some(std::mem::transmute(call.alloc.byte_add(call.stub_offset))),
+            Some(call.alloc),
+            0,
+            None,
+        )
+        .context("failed to create remote thread")?,
+    );
+
+    // Wait for thread to finish
+    let wait = WaitForSingleObject(*thread_handle, LOAD_TIMEOUT.as_millis() as u32);
+    if wait == WAIT_TIMEOUT {
+        return Ok(None);
+    }
+    if wait != WAIT_OBJECT_0 {
+        anyhow::bail!(
+            "failed to wait for the loader thread: {:?}",
+            windows::core::Error::from_win32()
+        );
+    }
+    let mut exit_code = 0;
+    GetExitCodeThread(*thread_handle, &mut exit_code)
+        .context("failed to get the loader thread's exit code")?;
+
+    Ok(Some((read_call_data(process, call)?, exit_code)))
+}
+
+unsafe fn write_call(process: HANDLE, call: &PreparedCall) -> anyhow::Result<()> {
+    WriteProcessMemory(
+        process,
+        call.alloc,
+        call.block.as_ptr() as *const _,
+        call.block.len(),
+        None,
+    )
+    .context("failed to write memory")
+}
+
+unsafe fn read_call_data(process: HANDLE, call: &PreparedCall) -> anyhow::Result<RemoteCallData> {
+    let mut data = std::mem::MaybeUninit::<RemoteCallData>::uninit();
+    ReadProcessMemory(
+        process,
+        call.alloc,
+        data.as_mut_ptr() as *mut c_void,
+        std::mem::size_of::<RemoteCallData>(),
+        None,
+    )
+    .context("failed to read the result of the call")?;
+    Ok(data.assume_init())
+}
+
+/// Machine code for a thread procedure that takes a [`RemoteCallData`], calls its function with
+/// its argument, and records the value returned along with `GetLastError`. The same code works
+/// as an APC routine, which has the same signature.
+fn call_stub() -> Vec<u8> {
+    let function = std::mem::offset_of!(RemoteCallData, function) as u8;
+    let get_last_error = std::mem::offset_of!(RemoteCallData, get_last_error) as u8;
+    let suspend_thread = std::mem::offset_of!(RemoteCallData, suspend_thread) as u8;
+    let argument = std::mem::offset_of!(RemoteCallData, argument) as u8;
+    let value = std::mem::offset_of!(RemoteCallData, value) as u8;
+    let last_error = std::mem::offset_of!(RemoteCallData, last_error) as u8;
+    let completed = std::mem::offset_of!(RemoteCallData, completed) as u8;
+
+    #[cfg(target_arch = "x86_64")]
+    let stub: &[&[u8]] = &[
+        &[0x53],                                          // push rbx
+        &[0x48, 0x83, 0xEC, 0x20],                        // sub rsp, 0x20
+        &[0x48, 0x89, 0xCB],                              // mov rbx, rcx
+        &[0x48, 0x8B, 0x4B, argument],                    // mov rcx, [rbx + argument]
+        &[0xFF, 0x53, function],                          // call [rbx + function]
+        &[0x48, 0x89, 0x43, value],                       // mov [rbx + value], rax
+        &[0xFF, 0x53, get_last_error],                    // call [rbx + get_last_error]
+        &[0x89, 0x43, last_error],                        // mov [rbx + last_error], eax
+        &[0xC7, 0x43, completed, 0x01, 0x00, 0x00, 0x00], // mov dword [rbx + completed], 1
+        &[0x48, 0x8B, 0x43, suspend_thread],              // mov rax, [rbx + suspend_thread]
+        &[0x48, 0x85, 0xC0],                              // test rax, rax
+        &[0x74, 0x09],                                    // jz done
+        &[0x48, 0xC7, 0xC1, 0xFE, 0xFF, 0xFF, 0xFF],      // mov rcx, -2 (the current thread)
+        &[0xFF, 0xD0],                                    // call rax
+        &[0x48, 0x83, 0xC4, 0x20],                        // done: add rsp, 0x20
+        &[0x5B],                                          // pop rbx
+        &[0xC3],                                          // ret
+    ];
+    #[cfg(target_arch = "x86")]
+    let stub: &[&[u8]] = &[
+        &[0x53],                                          // push ebx
+        &[0x8B, 0x5C, 0x24, 0x08],                        // mov ebx, [esp + 8]
+        &[0xFF, 0x73, argument],                          // push dword [ebx + argument]
+        &[0xFF, 0x53, function],                          // call [ebx + function]
+        &[0x89, 0x43, value],                             // mov [ebx + value], eax
+        &[0xFF, 0x53, get_last_error],                    // call [ebx + get_last_error]
+        &[0x89, 0x43, last_error],                        // mov [ebx + last_error], eax
+        &[0xC7, 0x43, completed, 0x01, 0x00, 0x00, 0x00], // mov dword [ebx + completed], 1
+        &[0x8B, 0x43, suspend_thread],                    // mov eax, [ebx + suspend_thread]
+        &[0x85, 0xC0],                                    // test eax, eax
+        &[0x74, 0x04],                                    // jz done
+        &[0x6A, 0xFE],                                    // push -2 (the current thread)
+        &[0xFF, 0xD0],                                    // call eax
+        &[0x5B],                                          // done: pop ebx
+        &[0xC2, 0x04, 0x00],                              // ret 4
+    ];
+    stub.concat()
+}
+
+/// Opens a process with the access needed by [`inject`] and [`wait_for_exit`].
+fn open_process(pid: u32) -> windows::core::Result<Owned<HANDLE>> {
+    unsafe {
+        OpenProcess(
+            PROCESS_VM_READ
+                | PROCESS_VM_WRITE
+                | PROCESS_VM_OPERATION
+                | PROCESS_TERMINATE
+                | PROCESS_CREATE_THREAD
+                | PROCESS_QUERY_INFORMATION
+                | PROCESS_SYNCHRONIZE,
+            false,
+            pid,
+        )
+        .map(|handle| Owned::new(handle))
+    }
+}
+
+/// Gets a list of process handles by their name, if running. The name must match exactly,
+/// ignoring case; use [`crate::ProcessQuery`] to match it with a glob. The handles have the access
+/// needed by [`inject`] and [`wait_for_exit`]. Processes that cannot be opened are skipped;
+/// [`crate::ProcessQuery`] also reports why.
+pub fn get_processes_by_name(name: &str) -> windows::core::Result<Vec<(u32, Owned<HANDLE>)>> {
+    unsafe {
+        let snapshot = Owned::new(CreateToolhelp32Snapshot(TH32CS_SNAPPROCESS, 0)?);
+        let mut entry = PROCESSENTRY32W {
+            dwSize: std::mem::size_of::<PROCESSENTRY32W>() as u32,
+            ..Default::default()
+        };
+
+        let mut handles = Vec::new();
+
+        if Process32FirstW(*snapshot, &mut entry).is_ok() {
+            loop {
+                let process_name = String::from_utf16_lossy(&entry.szExeFile)
+                    .trim_end_matches('\0')
+                    .to_lowercase();
+
+                if process_name == name.to_lowercase() {
+                    if let Ok(handle) = open_process(entry.th32ProcessID) {
+                        handles.push((entry.th32ProcessID, handle));
+                    }
+                }
+
+                if Process32NextW(*snapshot, &mut entry).is_err() {
+                    break;
+                }
+            }
+        }
+
+        Ok(handles)
+    }
+}
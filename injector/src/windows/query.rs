@@ -0,0 +1,118 @@
+use std::{collections::HashMap, ffi::OsString, os::windows::ffi::OsStringExt, path::PathBuf};
+
+use anyhow::Context;
+use windows::{
+    core::{Owned, PWSTR},
+    Win32::{
+        Foundation::{BOOL, HANDLE, HWND, LPARAM, TRUE},
+        System::{
+            Diagnostics::ToolHelp::{
+                CreateToolhelp32Snapshot, Process32FirstW, Process32NextW, PROCESSENTRY32W,
+                TH32CS_SNAPPROCESS,
+            },
+            Threading::{
+                OpenProcess, QueryFullProcessImageNameW, PROCESS_NAME_WIN32,
+                PROCESS_QUERY_LIMITED_INFORMATION,
+            },
+        },
+        UI::WindowsAndMessaging::{
+            EnumWindows, GetWindowTextW, GetWindowThreadProcessId, IsWindowVisible,
+        },
+    },
+};
+
+use super::{module_entries, open_process};
+use crate::query::ProcessEntry;
+
+/// A process handle with the access needed by [`crate::inject`] and [`crate::wait_for_exit`].
+pub type ProcessHandle = Owned<HANDLE>;
+
+pub(crate) fn processes() -> anyhow::Result<Vec<ProcessEntry>> {
+    unsafe {
+        let snapshot = Owned::new(
+            CreateToolhelp32Snapshot(TH32CS_SNAPPROCESS, 0).context("failed to list processes")?,
+        );
+        let mut entry = PROCESSENTRY32W {
+            dwSize: std::mem::size_of::<PROCESSENTRY32W>() as u32,
+            ..Default::default()
+        };
+
+        let mut processes = vec![];
+        if Process32FirstW(*snapshot, &mut entry).is_ok() {
+            loop {
+                processes.push(ProcessEntry {
+                    pid: entry.th32ProcessID,
+                    parent_pid: entry.th32ParentProcessID,
+                    name: String::from_utf16_lossy(&entry.szExeFile)
+                        .trim_end_matches('\0')
+                        .to_owned(),
+                });
+
+                if Process32NextW(*snapshot, &mut entry).is_err() {
+                    break;
+                }
+            }
+        }
+        Ok(processes)
+    }
+}
+
+pub(crate) fn executable_path(pid: u32) -> anyhow::Result<PathBuf> {
+    unsafe {
+        let process = Owned::new(
+            OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, false, pid)
+                .with_context(|| format!("failed to open process {pid}"))?,
+        );
+        let mut buffer = [0u16; 1024];
+        let mut len = buffer.len() as u32;
+        QueryFullProcessImageNameW(
+            *process,
+            PROCESS_NAME_WIN32,
+            PWSTR::from_raw(buffer.as_mut_ptr()),
+            &mut len,
+        )
+        .with_context(|| format!("failed to get executable of process {pid}"))?;
+        Ok(OsString::from_wide(&buffer[..len as usize]).into())
+    }
+}
+
+pub(crate) fn module_names(pid: u32) -> anyhow::Result<Vec<String>> {
+    Ok(module_entries(pid)?
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect())
+}
+
+/// Returns the titles of the visible top-level windows of every process, by process ID.
+pub(crate) fn window_titles() -> anyhow::Result<HashMap<u32, Vec<String>>> {
+    unsafe extern "system" fn callback(window: HWND, titles: LPARAM) -> BOOL {
+        let titles = &mut *(titles.0 as *mut HashMap<u32, Vec<String>>);
+        if IsWindowVisible(window).as_bool() {
+            let mut buffer = [0u16; 512];
+            let len = GetWindowTextW(window, &mut buffer);
+            if len > 0 {
+                let mut pid = 0;
+                GetWindowThreadProcessId(window, Some(&mut pid));
+                titles
+                    .entry(pid)
+                    .or_default()
+                    .push(String::from_utf16_lossy(&buffer[..len as usize]));
+            }
+        }
+        TRUE
+    }
+
+    let mut titles = HashMap::new();
+    unsafe {
+        EnumWindows(
+            Some(callback),
+            LPARAM(&mut titles as *mut HashMap<u32, Vec<String>> as isize),
+        )
+    }
+    .context("failed to list windows")?;
+    Ok(titles)
+}
+
+pub(crate) fn open(pid: u32) -> anyhow::Result<ProcessHandle> {
+    open_process(pid).with_context(|| format!("failed to open process {pid}"))
+}
@@ -0,0 +1,23 @@
+#!/bin/sh
+# Rebuilds the fixture DLLs that the PE tests read. Needs llvm-mc, llvm-dlltool and lld-link,
+# which can be the lld that ships with Rust: LLD_LINK="rust-lld -flavor link" ./build.sh
+set -e
+cd "$(dirname "$0")"
+LLD_LINK=${LLD_LINK:-lld-link}
+work=$(mktemp -d)
+trap 'rm -rf "$work"' EXIT
+
+build() {
+    bits=$1 triple=$2 machine=$3 dlltool_machine=$4
+    shift 4
+    llvm-mc -triple "$triple" -filetype=obj "fixture$bits.s" -o "$work/fixture$bits.obj"
+    for lib in kernel32 ws2_32; do
+        llvm-dlltool -m "$dlltool_machine" -d "$lib.def" -l "$work/$lib$bits.lib"
+    done
+    $LLD_LINK /dll /machine:"$machine" /nodefaultlib /noimplib /brepro /entry:entry /def:fixture.def "$@" \
+        /out:"fixture$bits.dll" "$work/fixture$bits.obj" "$work/kernel32$bits.lib" \
+        "$work/ws2_32$bits.lib"
+}
+
+build 64 x86_64-pc-windows-msvc x64 i386:x86-64
+build 32 i686-pc-windows-msvc x86 i386 /safeseh:no
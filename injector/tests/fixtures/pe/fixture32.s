@@ -0,0 +1,50 @@
+# A tiny DLL for the PE tests: exports by name, by ordinal only and forwarded, imports by name
+# and by ordinal, base relocations, and a TLS directory with data and a callback. See build.sh.
+    .text
+    .globl _entry
+_entry:
+    movl $1, %eax
+    retl $12
+
+    .globl _answer
+_answer:
+    movl $42, %eax
+    retl
+
+    .globl _unnamed
+_unnamed:
+    movl $7, %eax
+    retl
+
+_tls_callback:
+    retl $12
+
+    .data
+    .globl _answer_pointer
+_answer_pointer:
+    .long _answer
+    .long __imp__GetTickCount
+    .long __imp__socket
+
+    .section .tls$, "dw"
+tls_start:
+    .long 0x11223344
+tls_end:
+
+    .section .rdata, "dr"
+    .p2align 2
+    .globl __tls_used
+__tls_used:
+    .long tls_start
+    .long tls_end
+    .long _tls_index
+    .long _tls_callbacks
+    .long 8
+    .long 0
+_tls_callbacks:
+    .long _tls_callback
+    .long 0
+
+    .bss
+_tls_index:
+    .long 0
@@ -0,0 +1,50 @@
+# A tiny DLL for the PE tests: exports by name, by ordinal only and forwarded, imports by name
+# and by ordinal, base relocations, and a TLS directory with data and a callback. See build.sh.
+    .text
+    .globl entry
+entry:
+    movl $1, %eax
+    retq
+
+    .globl answer
+answer:
+    movl $42, %eax
+    retq
+
+    .globl unnamed
+unnamed:
+    movl $7, %eax
+    retq
+
+tls_callback:
+    retq
+
+    .data
+    .globl answer_pointer
+answer_pointer:
+    .quad answer
+    .quad __imp_GetTickCount
+    .quad __imp_socket
+
+    .section .tls$, "dw"
+tls_start:
+    .long 0x11223344
+tls_end:
+
+    .section .rdata, "dr"
+    .p2align 3
+    .globl _tls_used
+_tls_used:
+    .quad tls_start
+    .quad tls_end
+    .quad tls_index
+    .quad tls_callbacks
+    .long 8
+    .long 0
+tls_callbacks:
+    .quad tls_callback
+    .quad 0
+
+    .bss
+tls_index:
+    .long 0
@@ -0,0 +1,247 @@
+use crate::memory;
+
+/// The size of each page of executable memory that the allocator reserves.
+const PAGE_SIZE: usize = 0x10000;
+/// The granularity at which blocks are handed out from a page.
+const SLOT_SIZE: usize = 0x20;
+const SLOTS_PER_PAGE: usize = PAGE_SIZE / SLOT_SIZE;
+
+/// The number of bytes taken up by a jump stub written by [`CodeCaveAllocator::allocate_jump_stub`].
+#[cfg(target_pointer_width = "64")]
+pub const JUMP_STUB_SIZE: usize = 14;
+#[cfg(target_pointer_width = "32")]
+pub const JUMP_STUB_SIZE: usize = 5;
+
+/// A block of executable memory handed out by a [`CodeCaveAllocator`]. The block remains valid
+/// until it is returned with [`CodeCaveAllocator::free`] or the allocator is dropped.
+#[derive(Debug)]
+pub struct CodeCave {
+    address: usize,
+    size: usize,
+}
+impl CodeCave {
+    pub fn address(&self) -> usize {
+        self.address
+    }
+    pub fn size(&self) -> usize {
+        self.size
+    }
+    pub fn as_mut_ptr(&self) -> *mut u8 {
+        self.address as *mut u8
+    }
+}
+
+struct Page {
+    base: usize,
+    used: Vec<bool>,
+}
+impl Page {
+    fn find_free(&self, slots: usize) -> Option<usize> {
+        let mut run = 0;
+        for (index, used) in self.used.iter().enumerate() {
+            run = if *used { 0 } else { run + 1 };
+            if run == slots {
+                return Some(index + 1 - slots);
+            }
+        }
+        None
+    }
+}
+
+/// Hands out small blocks of executable memory that are within `rel32` reach of a target
+/// address, for use as jump stubs and trampolines.
+///
+/// Pages are reserved with [`memory::allocate_near`] as they are needed, and are shared between
+/// all targets that they are close enough to.
+pub struct CodeCaveAllocator {
+    pages: Vec<Page>,
+}
+impl CodeCaveAllocator {
+    pub const fn new() -> CodeCaveAllocator {
+        CodeCaveAllocator { pages: Vec::new() }
+    }
+
+    /// Allocates a block of at least `size` bytes, reachable from `near` with a `rel32`
+    /// displacement.
+    pub fn allocate(&mut self, near: usize, size: usize) -> anyhow::Result<CodeCave> {
+        if size == 0 || size > PAGE_SIZE {
+            anyhow::bail!("invalid code cave size {size:#x}");
+        }
+
+        let slots = size.div_ceil(SLOT_SIZE);
+        let in_range = |page: &Page| {
+            memory::rel32(near, page.base).is_some()
+                && memory::rel32(near, page.base + PAGE_SIZE).is_some()
+        };
+
+        let (page, first_slot) = match self
+            .pages
+            .iter_mut()
+            .filter(|page| in_range(page))
+            .find_map(|page| Some((page.find_free(slots)?, page)))
+        {
+            Some((first_slot, page)) => (page, first_slot),
+            None => {
+                let base = memory::allocate_near(near, PAGE_SIZE)?;
+                self.pages.push(Page {
+                    base,
+                    used: vec![false; SLOTS_PER_PAGE],
+                });
+                (self.pages.last_mut().unwrap(), 0)
+            }
+        };
+
+        page.used[first_slot..first_slot + slots].fill(true);
+        Ok(CodeCave {
+            address: page.base + first_slot * SLOT_SIZE,
+            size: slots * SLOT_SIZE,
+        })
+    }
+
+    /// Allocates a block near `near` containing an absolute jump to `destination`.
+    ///
+    /// # Safety
+    /// `destination` must be valid code to jump to.
+    pub unsafe fn allocate_jump_stub(
+        &mut self,
+        near: usize,
+        destination: usize,
+    ) -> anyhow::Result<CodeCave> {
+        let cave = self.allocate(near, JUMP_STUB_SIZE)?;
+        let stub = jump_stub(cave.address, destination);
+        std::ptr::copy_nonoverlapping(stub.as_ptr(), cave.as_mut_ptr(), stub.len());
+        Ok(cave)
+    }
+
+    /// Returns a block to the allocator. The block must not be executing.
+    pub fn free(&mut self, cave: CodeCave) {
+        let Some(page) = self
+            .pages
+            .iter_mut()
+            .find(|page| (page.base..page.base + PAGE_SIZE).contains(&cave.address))
+        else {
+            return;
+        };
+
+        let first_slot = (cave.address - page.base) / SLOT_SIZE;
+        page.used[first_slot..first_slot + cave.size / SLOT_SIZE].fill(false);
+    }
+}
+impl Default for CodeCaveAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Drop for CodeCaveAllocator {
+    fn drop(&mut self) {
+        for page in &self.pages {
+            unsafe {
+                let _ = memory::free(page.base, PAGE_SIZE);
+            }
+        }
+    }
+}
+
+/// Builds the bytes for an unconditional jump to `destination` that will be placed at `address`.
+#[cfg(target_pointer_width = "64")]
+fn jump_stub(_address: usize, destination: usize) -> [u8; JUMP_STUB_SIZE] {
+    // jmp [rip+0]; dq destination
+    let mut bytes = [0; JUMP_STUB_SIZE];
+    bytes[..6].copy_from_slice(&[0xFF, 0x25, 0x00, 0x00, 0x00, 0x00]);
+    bytes[6..].copy_from_slice(&(destination as u64).to_le_bytes());
+    bytes
+}
+
+/// Builds the bytes for an unconditional jump to `destination` that will be placed at `address`.
+#[cfg(target_pointer_width = "32")]
+fn jump_stub(address: usize, destination: usize) -> [u8; JUMP_STUB_SIZE] {
+    // jmp rel32
+    let mut bytes = [0; JUMP_STUB_SIZE];
+    bytes[0] = 0xE9;
+    bytes[1..].copy_from_slice(&destination.wrapping_sub(address + 5).to_le_bytes());
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reachable(near: usize, cave: &CodeCave) -> bool {
+        memory::rel32(near, cave.address()).is_some()
+            && memory::rel32(near, cave.address() + cave.size()).is_some()
+    }
+
+    #[test]
+    fn rel32_reach() {
+        assert_eq!(memory::rel32(0x1000, 0x1010), Some(0x10));
+        assert_eq!(memory::rel32(0x1010, 0x1000), Some(-0x10));
+        assert_eq!(
+            memory::rel32(0x1000, 0x1000 + i32::MAX as usize),
+            Some(i32::MAX)
+        );
+        assert_eq!(memory::rel32(0x1000, 0x1000 + i32::MAX as usize + 1), None);
+        #[cfg(target_pointer_width = "64")]
+        assert_eq!(memory::rel32(0x7FFF_0000_0000, 0x1000), None);
+    }
+
+    #[test]
+    fn allocations_are_in_reach_and_do_not_overlap() {
+        let mut allocator = CodeCaveAllocator::new();
+        let near = rel32_reach as fn() as usize;
+        let caves: Vec<_> = [1, SLOT_SIZE, SLOT_SIZE + 1, 0x100]
+            .into_iter()
+            .map(|size| {
+                let cave = allocator.allocate(near, size).unwrap();
+                assert!(cave.size() >= size);
+                assert!(reachable(near, &cave));
+                cave
+            })
+            .collect();
+
+        for (index, a) in caves.iter().enumerate() {
+            for b in &caves[index + 1..] {
+                assert!(
+                    a.address() + a.size() <= b.address() || b.address() + b.size() <= a.address()
+                );
+            }
+        }
+        // Small allocations near the same target share a page
+        assert_eq!(allocator.pages.len(), 1);
+    }
+
+    #[test]
+    fn freed_blocks_are_reused() {
+        let mut allocator = CodeCaveAllocator::new();
+        let near = rel32_reach as fn() as usize;
+        let first = allocator.allocate(near, SLOT_SIZE * 2).unwrap();
+        let address = first.address();
+        allocator.free(first);
+        assert_eq!(
+            allocator.allocate(near, SLOT_SIZE).unwrap().address(),
+            address
+        );
+    }
+
+    #[test]
+    fn invalid_sizes_are_refused() {
+        let mut allocator = CodeCaveAllocator::new();
+        assert!(allocator.allocate(0x1000, 0).is_err());
+        assert!(allocator.allocate(0x1000, PAGE_SIZE + 1).is_err());
+        assert!(allocator.pages.is_empty());
+    }
+
+    #[test]
+    fn jump_stub_reaches_destination() {
+        extern "C" fn destination() -> u32 {
+            0x1234_5678
+        }
+
+        let mut allocator = CodeCaveAllocator::new();
+        let near = rel32_reach as fn() as usize;
+        let cave = unsafe { allocator.allocate_jump_stub(near, destination as *const () as usize) }
+            .unwrap();
+        assert!(reachable(near, &cave));
+        let stub: extern "C" fn() -> u32 = unsafe { std::mem::transmute(cave.address()) };
+        assert_eq!(stub(), 0x1234_5678);
+    }
+}
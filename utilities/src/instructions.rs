@@ -0,0 +1,173 @@
+use std::slice;
+
+use iced_x86::{Decoder, DecoderError, DecoderOptions, Instruction};
+
+use crate::memory;
+
+/// The longest an x86 instruction can be.
+pub const MAX_INSTRUCTION_LEN: usize = 15;
+
+#[cfg(target_pointer_width = "64")]
+const BITNESS: u32 = 64;
+#[cfg(target_pointer_width = "32")]
+const BITNESS: u32 = 32;
+
+/// Decodes `count` instructions starting at `address`.
+///
+/// # Safety
+/// `address` must be the start of an instruction.
+pub unsafe fn decode(address: usize, count: usize) -> anyhow::Result<Vec<Instruction>> {
+    let code = readable_code(address, count * MAX_INSTRUCTION_LEN)?;
+    let mut decoder = Decoder::with_ip(BITNESS, code, address as u64, DecoderOptions::NONE);
+    (0..count).map(|_| decode_one(&mut decoder)).collect()
+}
+
+/// Decodes the whole instructions starting at `address` that cover at least `len` bytes.
+///
+/// # Safety
+/// `address` must be the start of an instruction.
+pub unsafe fn decode_covering(address: usize, len: usize) -> anyhow::Result<Vec<Instruction>> {
+    // Any instruction that starts within `len` bytes ends within this many bytes.
+    let code = readable_code(address, len + MAX_INSTRUCTION_LEN)?;
+    let mut decoder = Decoder::with_ip(BITNESS, code, address as u64, DecoderOptions::NONE);
+
+    let mut instructions = vec![];
+    let mut covered = 0;
+    while covered < len {
+        let instruction = decode_one(&mut decoder)?;
+        covered += instruction.len();
+        instructions.push(instruction);
+    }
+    Ok(instructions)
+}
+
+/// Returns the length of the whole instructions starting at `address` that cover at least `len`
+/// bytes; that is, the distance to the first instruction boundary at or after `address + len`.
+///
+/// # Safety
+/// `address` must be the start of an instruction.
+pub unsafe fn boundary_after(address: usize, len: usize) -> anyhow::Result<usize> {
+    Ok(decode_covering(address, len)?
+        .iter()
+        .map(Instruction::len)
+        .sum())
+}
+
+/// Returns up to `len` bytes starting at `address`, stopping short at the first page that cannot
+/// be read, so that decoding near the end of a mapping does not fault.
+unsafe fn readable_code<'a>(address: usize, len: usize) -> anyhow::Result<&'a [u8]> {
+    let end = address.saturating_add(len);
+    let mut readable_end = address;
+    while readable_end < end {
+        let region = memory::query(readable_end)?;
+        if !region.is_readable() {
+            break;
+        }
+        readable_end = region.range().end;
+    }
+    if readable_end == address && len > 0 {
+        anyhow::bail!("{address:#x} is not readable");
+    }
+    Ok(slice::from_raw_parts(
+        address as *const u8,
+        readable_end.min(end) - address,
+    ))
+}
+
+fn decode_one(decoder: &mut Decoder) -> anyhow::Result<Instruction> {
+    let ip = decoder.ip();
+    let instruction = decoder.decode();
+    match decoder.last_error() {
+        DecoderError::None => Ok(instruction),
+        DecoderError::NoMoreBytes => {
+            anyhow::bail!("instruction at {ip:#x} runs into unreadable memory")
+        }
+        _ => anyhow::bail!("invalid instruction at {ip:#x}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Encoded the same way in 32-bit and 64-bit code
+    const MOV_EAX_ECX: [u8; 2] = [0x89, 0xC8];
+    const NOP: [u8; 1] = [0x90];
+    const CALL_REL32: [u8; 5] = [0xE8, 0, 0, 0, 0];
+    const RET: [u8; 1] = [0xC3];
+
+    /// The instructions, padded with enough NOPs that decoding never reads past the end.
+    fn code() -> Vec<u8> {
+        let mut code = [&MOV_EAX_ECX[..], &NOP, &CALL_REL32, &RET].concat();
+        code.resize(code.len() + 4 * MAX_INSTRUCTION_LEN, NOP[0]);
+        code
+    }
+
+    #[test]
+    fn decode_counts_instructions() {
+        let code = code();
+        let address = code.as_ptr() as usize;
+        let lengths = |count| -> Vec<usize> {
+            unsafe { decode(address, count) }
+                .unwrap()
+                .iter()
+                .map(Instruction::len)
+                .collect()
+        };
+        assert_eq!(lengths(0), [0usize; 0]);
+        assert_eq!(lengths(1), [2]);
+        assert_eq!(lengths(4), [2, 1, 5, 1]);
+        assert_eq!(
+            unsafe { decode(address, 1) }.unwrap()[0].ip(),
+            address as u64
+        );
+    }
+
+    #[test]
+    fn boundaries_round_up_to_whole_instructions() {
+        let code = code();
+        let address = code.as_ptr() as usize;
+        let boundary = |len| unsafe { boundary_after(address, len) }.unwrap();
+        assert_eq!(boundary(1), 2);
+        assert_eq!(boundary(2), 2);
+        assert_eq!(boundary(3), 3);
+        assert_eq!(boundary(4), 8);
+        assert_eq!(boundary(8), 8);
+        assert_eq!(boundary(9), 9);
+        assert_eq!(unsafe { decode_covering(address, 5) }.unwrap().len(), 3);
+    }
+
+    #[test]
+    fn invalid_instructions_are_refused() {
+        // `FF /7` is not an instruction
+        let mut code = [NOP[0]; 2 * MAX_INSTRUCTION_LEN + 1];
+        code[1..3].copy_from_slice(&[0xFF, 0xFF]);
+        let address = code.as_ptr() as usize;
+        assert_eq!(unsafe { boundary_after(address, 1) }.unwrap(), 1);
+        assert!(unsafe { boundary_after(address, 2) }.is_err());
+        assert!(unsafe { decode(address, 2) }.is_err());
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn decoding_stops_at_unreadable_memory() {
+        let page_size = crate::linux::memory::page_size();
+        let address =
+            memory::allocate_near(code as fn() -> Vec<u8> as usize, page_size * 2).unwrap();
+        unsafe {
+            // Leave a NOP and the start of a call at the end of the first page, and nothing after
+            assert_eq!(libc::munmap((address + page_size) as _, page_size), 0);
+            let end = address + page_size;
+            std::ptr::write_bytes(address as *mut u8, NOP[0], page_size);
+            (end as *mut u8).sub(1).write(CALL_REL32[0]);
+
+            assert_eq!(boundary_after(end - 2, 1).unwrap(), 1);
+            assert_eq!(decode(end - 2, 1).unwrap().len(), 1);
+            let error = boundary_after(end - 2, 2).unwrap_err();
+            assert!(error.to_string().contains("unreadable"), "{error}");
+            assert!(decode(end, 1).is_err());
+
+            memory::free(address, page_size).unwrap();
+        }
+    }
+}
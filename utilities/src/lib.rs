@@ -1,11 +1,51 @@
 pub mod util;
 
+#[cfg(any(target_os = "windows", target_os = "linux"))]
+pub mod code_cave;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub mod instructions;
+#[cfg(any(target_os = "windows", target_os = "linux"))]
+pub mod memory;
+#[cfg(all(
+    target_arch = "x86_64",
+    any(target_os = "windows", target_os = "linux")
+))]
+pub mod mid_hook;
+#[cfg(all(
+    any(target_arch = "x86", target_arch = "x86_64"),
+    any(target_os = "windows", target_os = "linux")
+))]
+mod patcher;
+#[cfg(any(target_os = "windows", target_os = "linux"))]
+pub mod payload;
+#[cfg(any(target_os = "windows", target_os = "linux"))]
+pub mod pointer_path;
+#[cfg(any(target_os = "windows", target_os = "linux"))]
+pub mod process;
+#[cfg(any(target_os = "windows", target_os = "linux"))]
+pub mod scan;
+#[cfg(any(target_os = "windows", target_os = "linux"))]
+pub(crate) mod thread_relocation;
+#[cfg(any(target_os = "windows", target_os = "linux"))]
+pub mod value_scan;
+
+#[cfg(all(
+    any(target_arch = "x86", target_arch = "x86_64"),
+    any(target_os = "windows", target_os = "linux")
+))]
+pub use patcher::Patcher;
+
 #[cfg(target_os = "windows")]
 mod windows;
 
 #[cfg(target_os = "windows")]
 pub use crate::windows::*;
 
+#[cfg(target_os = "linux")]
+mod linux;
+#[cfg(target_os = "linux")]
+pub use crate::linux::*;
+
 #[cfg(target_os = "windows")]
 pub use retour;
 
@@ -0,0 +1,367 @@
+use std::{fs, path::PathBuf};
+
+use anyhow::Context;
+
+use crate::memory::{self, Protection, Region, RegionKind, RegionState, NEAR_RANGE};
+
+pub(crate) fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+/// Allocates `size` bytes of executable memory within [`NEAR_RANGE`] of `target`.
+pub fn allocate_near(target: usize, size: usize) -> anyhow::Result<usize> {
+    // Step in 64KiB increments to match the allocation granularity used on Windows; this keeps
+    // the number of attempts bounded while still finding gaps between mappings.
+    const STEP: usize = 0x10000;
+
+    let size = size.next_multiple_of(page_size());
+    let min_address = target.saturating_sub(NEAR_RANGE).max(STEP);
+    let max_address = target.saturating_add(NEAR_RANGE);
+
+    let start = target & !(STEP - 1);
+    for distance in (0..NEAR_RANGE).step_by(STEP) {
+        let below = start.checked_sub(distance).filter(|a| *a >= min_address);
+        let above = start
+            .checked_add(distance)
+            .filter(|a| a.saturating_add(size) <= max_address);
+        if below.is_none() && above.is_none() {
+            break;
+        }
+
+        for candidate in below.into_iter().chain(above) {
+            if let Some(address) = unsafe { try_allocate(candidate, size) } {
+                return Ok(address);
+            }
+        }
+    }
+
+    anyhow::bail!("failed to find free memory within range of {target:#x}")
+}
+
+unsafe fn try_allocate(address: usize, size: usize) -> Option<usize> {
+    // `MAP_FIXED_NOREPLACE` refuses to clobber existing mappings. Kernels that predate it treat
+    // the address as a hint instead, so we have to check where the mapping actually landed.
+    let ptr = libc::mmap(
+        address as _,
+        size,
+        libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC,
+        libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_FIXED_NOREPLACE,
+        -1,
+        0,
+    );
+    if ptr == libc::MAP_FAILED {
+        return None;
+    }
+    if ptr as usize != address {
+        libc::munmap(ptr, size);
+        return None;
+    }
+    Some(address)
+}
+
+/// # Safety
+/// `address` must have been returned by [`allocate_near`] with the same `size`, and nothing may
+/// still be using it.
+pub unsafe fn free(address: usize, size: usize) -> anyhow::Result<()> {
+    let size = size.next_multiple_of(page_size());
+    if libc::munmap(address as _, size) != 0 {
+        anyhow::bail!(
+            "failed to unmap memory: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+    Ok(())
+}
+
+/// Writes `bytes` to `ptr`, temporarily making the memory writable if it isn't already.
+///
+/// # Safety
+/// `ptr` must point to `bytes.len()` bytes of mapped memory that nothing else is writing to.
+pub unsafe fn safe_write(ptr: *mut u8, bytes: &[u8]) -> anyhow::Result<()> {
+    let page_size = page_size();
+    let start = (ptr as usize) & !(page_size - 1);
+    let end = (ptr as usize + bytes.len()).next_multiple_of(page_size);
+
+    // Remember the protection of every mapping we touch, so that each can be put back the way
+    // it was.
+    let regions = memory::regions_in(start..end)?;
+    if let Some(free) = regions.iter().find(|r| r.state == RegionState::Free) {
+        anyhow::bail!("{:#x} is not mapped", free.base.max(start));
+    }
+
+    if libc::mprotect(
+        start as _,
+        end - start,
+        libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC,
+    ) != 0
+    {
+        anyhow::bail!(
+            "failed to unprotect memory: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+    std::slice::from_raw_parts_mut(ptr, bytes.len()).copy_from_slice(bytes);
+    for region in regions {
+        let region_start = region.base.max(start);
+        let region_end = region.range().end.min(end);
+        if libc::mprotect(
+            region_start as _,
+            region_end - region_start,
+            region.native_protection as i32,
+        ) != 0
+        {
+            anyhow::bail!(
+                "failed to reprotect memory: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Returns the region containing `address`. Addresses between mappings are reported as a free
+/// region spanning the gap.
+pub fn query(address: usize) -> anyhow::Result<Region> {
+    let mut gap_start = 0;
+    for region in regions()? {
+        if region.contains(address) {
+            return Ok(region);
+        }
+        if region.base > address {
+            return Ok(free_region(gap_start..region.base));
+        }
+        gap_start = region.range().end;
+    }
+    Ok(free_region(gap_start..usize::MAX))
+}
+
+/// Returns the mappings of the process, in address order.
+pub fn regions() -> anyhow::Result<impl Iterator<Item = Region>> {
+    let maps = fs::read_to_string("/proc/self/maps").context("failed to read memory maps")?;
+    maps.lines()
+        .map(parse_mapping)
+        .collect::<anyhow::Result<Vec<_>>>()
+        .map(Vec::into_iter)
+}
+
+fn free_region(range: std::ops::Range<usize>) -> Region {
+    Region {
+        base: range.start,
+        size: range.end - range.start,
+        state: RegionState::Free,
+        kind: None,
+        protection: Protection::default(),
+        module: None,
+        native_protection: libc::PROT_NONE as u32,
+    }
+}
+
+/// Parses a line of `/proc/<pid>/maps`: `start-end perms offset dev inode [path]`.
+pub(crate) fn parse_mapping(line: &str) -> anyhow::Result<Region> {
+    let mut fields = line.splitn(6, ' ');
+    let (Some(addresses), Some(permissions)) = (fields.next(), fields.next()) else {
+        anyhow::bail!("malformed memory map line: {line}");
+    };
+    let path = fields.nth(3).unwrap_or("").trim_start();
+    let (start, end) = addresses
+        .split_once('-')
+        .with_context(|| format!("malformed memory map line: {line}"))?;
+    let (start, end) = (
+        usize::from_str_radix(start, 16)?,
+        usize::from_str_radix(end, 16)?,
+    );
+
+    let permissions = permissions.as_bytes();
+    let protection = Protection {
+        read: permissions.contains(&b'r'),
+        write: permissions.contains(&b'w'),
+        execute: permissions.contains(&b'x'),
+        guard: false,
+    };
+    let mut native_protection = libc::PROT_NONE;
+    for (enabled, flag) in [
+        (protection.read, libc::PROT_READ),
+        (protection.write, libc::PROT_WRITE),
+        (protection.execute, libc::PROT_EXEC),
+    ] {
+        if enabled {
+            native_protection |= flag;
+        }
+    }
+
+    // Anything backed by a file is treated as belonging to a module, as there is no cheap way
+    // to tell executable images apart from other mapped files.
+    let (kind, module) = if path.starts_with("/dev/shm/") || path.starts_with("/SYSV") {
+        (RegionKind::Mapped, None)
+    } else if path.starts_with('/') {
+        (RegionKind::Image, Some(PathBuf::from(path)))
+    } else {
+        (RegionKind::Private, None)
+    };
+
+    Ok(Region {
+        base: start,
+        size: end - start,
+        state: RegionState::Committed,
+        kind: Some(kind),
+        protection,
+        module,
+        native_protection: native_protection as u32,
+    })
+}
+
+/// Copies `buffer.len()` bytes from `address` into `buffer`, failing if any of them are not
+/// mapped and readable.
+pub fn read(address: usize, buffer: &mut [u8]) -> anyhow::Result<()> {
+    // Going through the kernel turns a fault into an error.
+    let local = libc::iovec {
+        iov_base: buffer.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buffer.len(),
+    };
+    let remote = libc::iovec {
+        iov_base: address as *mut libc::c_void,
+        iov_len: buffer.len(),
+    };
+    let read = unsafe { libc::process_vm_readv(libc::getpid(), &local, 1, &remote, 1, 0) };
+    if read != buffer.len() as isize {
+        anyhow::bail!(
+            "{:#x}..{:#x} is not readable",
+            address,
+            address + buffer.len()
+        );
+    }
+    Ok(())
+}
+
+/// Copies `bytes` to `address`, failing if any of the destination is not mapped and writable.
+pub fn write(address: usize, bytes: &[u8]) -> anyhow::Result<()> {
+    let local = libc::iovec {
+        iov_base: bytes.as_ptr() as *mut libc::c_void,
+        iov_len: bytes.len(),
+    };
+    let remote = libc::iovec {
+        iov_base: address as *mut libc::c_void,
+        iov_len: bytes.len(),
+    };
+    let written = unsafe { libc::process_vm_writev(libc::getpid(), &local, 1, &remote, 1, 0) };
+    if written != bytes.len() as isize {
+        anyhow::bail!(
+            "{:#x}..{:#x} is not writable",
+            address,
+            address + bytes.len()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_mappings() {
+        let image = parse_mapping(
+            "7f1000000000-7f1000002000 r-xp 00001000 08:01 1234      /usr/lib/lib a.so",
+        )
+        .unwrap();
+        assert_eq!(image.range(), 0x7f1000000000..0x7f1000002000);
+        assert_eq!(image.state, RegionState::Committed);
+        assert_eq!(image.kind, Some(RegionKind::Image));
+        assert_eq!(image.module, Some(PathBuf::from("/usr/lib/lib a.so")));
+        assert!(image.is_readable() && !image.is_writable() && image.protection.execute);
+        assert_eq!(
+            image.native_protection,
+            (libc::PROT_READ | libc::PROT_EXEC) as u32
+        );
+
+        let heap = parse_mapping("1000-3000 rw-p 00000000 00:00 0      [heap]").unwrap();
+        assert_eq!(heap.kind, Some(RegionKind::Private));
+        assert_eq!(heap.module, None);
+        assert!(heap.is_writable() && !heap.protection.execute);
+
+        let anonymous = parse_mapping("1000-2000 ---p 00000000 00:00 0").unwrap();
+        assert_eq!(anonymous.kind, Some(RegionKind::Private));
+        assert!(!anonymous.is_readable());
+
+        let shared = parse_mapping("1000-2000 rw-s 00000000 00:05 7 /dev/shm/buffer").unwrap();
+        assert_eq!(shared.kind, Some(RegionKind::Mapped));
+
+        assert!(parse_mapping("1000-2000").is_err());
+        assert!(parse_mapping("10002000 r--p 00000000 00:00 0").is_err());
+        assert!(parse_mapping("zz-2000 r--p 00000000 00:00 0").is_err());
+    }
+
+    #[test]
+    fn regions_cover_own_code_and_data() {
+        let regions: Vec<_> = regions().unwrap().collect();
+        assert!(regions
+            .windows(2)
+            .all(|pair| pair[0].range().end <= pair[1].base));
+
+        let function = regions_cover_own_code_and_data as fn() as usize;
+        let code = regions.iter().find(|r| r.contains(function)).unwrap();
+        assert!(code.protection.execute);
+        assert_eq!(code.kind, Some(RegionKind::Image));
+        assert_eq!(
+            code.module
+                .as_deref()
+                .map(fs::canonicalize)
+                .transpose()
+                .unwrap(),
+            Some(fs::canonicalize(std::env::current_exe().unwrap()).unwrap())
+        );
+
+        let local = 0u64;
+        let stack = query(&local as *const u64 as usize).unwrap();
+        assert!(stack.is_writable());
+        assert_eq!(stack.kind, Some(RegionKind::Private));
+    }
+
+    #[test]
+    fn query_reports_gaps_as_free() {
+        for address in [0, usize::MAX] {
+            let region = query(address).unwrap();
+            assert_eq!(region.state, RegionState::Free);
+            assert!(region.contains(address) || region.range().end == usize::MAX);
+            assert_eq!(region.kind, None);
+            assert!(!region.is_readable());
+        }
+    }
+
+    #[test]
+    fn safe_write_restores_each_region_protection() {
+        let size = page_size() * 2;
+        let address = allocate_near(page_size as fn() -> usize as usize, size).unwrap();
+        unsafe {
+            // Split the allocation into a read-only page and a read-write one, then write
+            // across both
+            assert_eq!(
+                libc::mprotect(address as _, page_size(), libc::PROT_READ),
+                0
+            );
+            assert_eq!(
+                libc::mprotect(
+                    (address + page_size()) as _,
+                    page_size(),
+                    libc::PROT_READ | libc::PROT_WRITE
+                ),
+                0
+            );
+            let regions = memory::regions_in(address..address + size).unwrap();
+            assert_eq!(regions.len(), 2);
+            assert!(write(address, &[1]).is_err());
+
+            let middle = address + page_size() - 2;
+            safe_write(middle as *mut u8, &[1, 2, 3, 4]).unwrap();
+            let mut bytes = [0; 4];
+            read(middle, &mut bytes).unwrap();
+            assert_eq!(bytes, [1, 2, 3, 4]);
+            assert_eq!(
+                memory::regions_in(address..address + size).unwrap(),
+                regions
+            );
+
+            free(address, size).unwrap();
+        }
+    }
+}
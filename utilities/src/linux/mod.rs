@@ -0,0 +1,5 @@
+pub(crate) mod memory;
+pub(crate) mod process;
+mod thread_suspender;
+
+pub use thread_suspender::{SuspendedThread, ThreadSuspender};
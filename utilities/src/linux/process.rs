@@ -0,0 +1,120 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions},
+    os::unix::fs::FileExt,
+    path::PathBuf,
+    sync::Arc,
+};
+
+use anyhow::Context;
+
+use crate::{linux::memory, memory::Region, process::ModuleInfo};
+
+/// A process whose memory can be read and written, which may be the current process.
+///
+/// Memory is read with `process_vm_readv`, falling back to `/proc/<pid>/mem` for pages it
+/// cannot access. Writes go through `/proc/<pid>/mem`, which ignores page protection. Both
+/// require permission to ptrace the process.
+///
+/// Cloning a `Process` shares the underlying file.
+#[derive(Clone)]
+pub struct Process {
+    pid: u32,
+    mem: Arc<File>,
+}
+impl Process {
+    /// Opens the process with the given ID for reading and writing memory.
+    pub fn open(pid: u32) -> anyhow::Result<Process> {
+        let path = format!("/proc/{pid}/mem");
+        let mem = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .with_context(|| format!("failed to open process {pid}"))?;
+        Ok(Process {
+            pid,
+            mem: Arc::new(mem),
+        })
+    }
+
+    /// Opens the current process, which can fail if `/proc` is not mounted or ptrace is denied.
+    pub fn current() -> anyhow::Result<Process> {
+        Process::open(std::process::id()).context("failed to open the current process")
+    }
+
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+
+    /// Fills `buffer` with the bytes at `address`.
+    pub fn read(&self, address: usize, buffer: &mut [u8]) -> anyhow::Result<()> {
+        let local = libc::iovec {
+            iov_base: buffer.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buffer.len(),
+        };
+        let remote = libc::iovec {
+            iov_base: address as *mut libc::c_void,
+            iov_len: buffer.len(),
+        };
+        let read =
+            unsafe { libc::process_vm_readv(self.pid as libc::pid_t, &local, 1, &remote, 1, 0) };
+        if read == buffer.len() as isize {
+            return Ok(());
+        }
+
+        self.mem
+            .read_exact_at(buffer, address as u64)
+            .with_context(|| format!("failed to read {} bytes at {address:#x}", buffer.len()))
+    }
+
+    /// Writes `bytes` to `address`.
+    pub fn write(&self, address: usize, bytes: &[u8]) -> anyhow::Result<()> {
+        self.mem
+            .write_all_at(bytes, address as u64)
+            .with_context(|| format!("failed to write {} bytes at {address:#x}", bytes.len()))
+    }
+
+    /// Returns the mappings of the process, in address order.
+    pub fn regions(&self) -> anyhow::Result<Vec<Region>> {
+        self.maps()?.lines().map(memory::parse_mapping).collect()
+    }
+
+    fn maps(&self) -> anyhow::Result<String> {
+        fs::read_to_string(format!("/proc/{}/maps", self.pid))
+            .with_context(|| format!("failed to read memory map of process {}", self.pid))
+    }
+
+    /// Returns the file-backed mappings of the process, grouped by file.
+    pub(crate) fn module_infos(&self) -> anyhow::Result<Vec<ModuleInfo>> {
+        let maps = self.maps()?;
+
+        let mut modules: BTreeMap<usize, ModuleInfo> = BTreeMap::new();
+        let mut by_path: BTreeMap<PathBuf, usize> = BTreeMap::new();
+        for line in maps.lines() {
+            let region = memory::parse_mapping(line)?;
+            let Some(path) = region.module else {
+                continue;
+            };
+            let end = region.base + region.size;
+
+            match by_path.get(&path) {
+                Some(base) => {
+                    let module = modules.get_mut(base).unwrap();
+                    module.size = end - module.base;
+                }
+                None => {
+                    by_path.insert(path.clone(), region.base);
+                    modules.insert(
+                        region.base,
+                        ModuleInfo {
+                            path: path.to_string_lossy().into_owned(),
+                            base: region.base,
+                            size: region.size,
+                        },
+                    );
+                }
+            }
+        }
+        Ok(modules.into_values().collect())
+    }
+}
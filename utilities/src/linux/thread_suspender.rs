@@ -0,0 +1,312 @@
+use std::{
+    fs, mem, ptr,
+    sync::{
+        atomic::{AtomicI32, AtomicPtr, Ordering},
+        Mutex, MutexGuard, Once,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+
+/// The most threads that can be suspended at once.
+const MAX_THREADS: usize = 1024;
+/// How long to wait for a thread to respond to the suspend signal before skipping it; it may
+/// have exited, or have the signal blocked.
+const SUSPEND_TIMEOUT: Duration = Duration::from_millis(100);
+
+// The states of a slot. A slot is claimed by the suspender (`REQUESTED`), taken over by the
+// signal handler once the thread stops (`SUSPENDED`), released by the suspender (`RESUMING`),
+// and handed back by the handler as it returns (`IDLE`).
+const IDLE: i32 = 0;
+const REQUESTED: i32 = 1;
+const SUSPENDED: i32 = 2;
+const RESUMING: i32 = 3;
+
+struct Slot {
+    tid: AtomicI32,
+    state: AtomicI32,
+    context: AtomicPtr<libc::ucontext_t>,
+}
+
+// Everything the signal handler touches lives here, as it cannot take locks or allocate.
+static SLOTS: [Slot; MAX_THREADS] = [const {
+    Slot {
+        tid: AtomicI32::new(0),
+        state: AtomicI32::new(IDLE),
+        context: AtomicPtr::new(ptr::null_mut()),
+    }
+}; MAX_THREADS];
+
+fn signal() -> libc::c_int {
+    // glibc reserves the first few real-time signals for itself; stay clear of those and of
+    // the ones applications tend to pick.
+    libc::SIGRTMIN() + 6
+}
+
+fn install_handler() -> anyhow::Result<()> {
+    static INSTALL: Once = Once::new();
+    let mut result = Ok(());
+    INSTALL.call_once(|| unsafe {
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = handle_signal as *const () as usize;
+        action.sa_flags = libc::SA_SIGINFO | libc::SA_RESTART;
+        libc::sigfillset(&mut action.sa_mask);
+        if libc::sigaction(signal(), &action, ptr::null_mut()) != 0 {
+            result = Err(std::io::Error::last_os_error())
+                .context("failed to install thread suspension signal handler");
+        }
+    });
+    result
+}
+
+extern "C" fn handle_signal(
+    _signal: libc::c_int,
+    _info: *mut libc::siginfo_t,
+    context: *mut libc::c_void,
+) {
+    unsafe {
+        let errno = *libc::__errno_location();
+        let tid = gettid();
+        let slot = SLOTS.iter().find(|slot| {
+            slot.tid.load(Ordering::Acquire) == tid
+                && slot.state.load(Ordering::Acquire) == REQUESTED
+        });
+        if let Some(slot) = slot {
+            slot.context
+                .store(context as *mut libc::ucontext_t, Ordering::Release);
+            // The suspender may have given up on us in the meantime.
+            if slot
+                .state
+                .compare_exchange(REQUESTED, SUSPENDED, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+            {
+                // Any changes the suspender makes to the context are applied when we return.
+                while slot.state.load(Ordering::Acquire) == SUSPENDED {
+                    futex_wait(&slot.state, SUSPENDED);
+                }
+                slot.context.store(ptr::null_mut(), Ordering::Release);
+                slot.state.store(IDLE, Ordering::Release);
+            }
+        }
+        *libc::__errno_location() = errno;
+    }
+}
+
+fn gettid() -> libc::pid_t {
+    unsafe { libc::syscall(libc::SYS_gettid) as libc::pid_t }
+}
+
+fn futex_wait(futex: &AtomicI32, expected: i32) {
+    unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            futex.as_ptr(),
+            libc::FUTEX_WAIT | libc::FUTEX_PRIVATE_FLAG,
+            expected,
+            ptr::null::<libc::timespec>(),
+        );
+    }
+}
+
+fn futex_wake(futex: &AtomicI32) {
+    unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            futex.as_ptr(),
+            libc::FUTEX_WAKE | libc::FUTEX_PRIVATE_FLAG,
+            i32::MAX,
+        );
+    }
+}
+
+/// A thread that has been suspended by a [`ThreadSuspender`], along with its register state.
+pub struct SuspendedThread {
+    id: libc::pid_t,
+    slot: &'static Slot,
+}
+impl SuspendedThread {
+    pub fn id(&self) -> libc::pid_t {
+        self.id
+    }
+
+    /// The thread's registers, as of when it was suspended.
+    pub fn context(&self) -> &libc::ucontext_t {
+        unsafe { &*self.slot.context.load(Ordering::Acquire) }
+    }
+
+    pub fn instruction_pointer(&self) -> usize {
+        #[cfg(target_arch = "x86_64")]
+        return self.context().uc_mcontext.gregs[libc::REG_RIP as usize] as usize;
+        #[cfg(target_arch = "x86")]
+        return self.context().uc_mcontext.gregs[libc::REG_EIP as usize] as usize;
+    }
+
+    /// Moves the thread to `address`; it will continue from there when resumed.
+    pub fn set_instruction_pointer(&mut self, address: usize) -> anyhow::Result<()> {
+        // The context lives on the suspended thread's stack and is restored by the kernel when
+        // the signal handler returns, so it can be written directly.
+        let context = unsafe { &mut *self.slot.context.load(Ordering::Acquire) };
+        #[cfg(target_arch = "x86_64")]
+        {
+            context.uc_mcontext.gregs[libc::REG_RIP as usize] = address as i64;
+        }
+        #[cfg(target_arch = "x86")]
+        {
+            context.uc_mcontext.gregs[libc::REG_EIP as usize] = address as i32;
+        }
+        Ok(())
+    }
+}
+
+struct Suspension {
+    threads: Vec<SuspendedThread>,
+    depth: usize,
+}
+
+// The threads are tracked globally so that code rewriting memory can check whether a
+// suspended thread is in the way, without needing to be handed the suspender.
+static SUSPENSION: Mutex<Option<Suspension>> = Mutex::new(None);
+
+fn suspension() -> MutexGuard<'static, Option<Suspension>> {
+    SUSPENSION.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Suspends every other thread in the process until dropped.
+///
+/// Threads are stopped by sending them a real-time signal whose handler waits until they are
+/// resumed. Threads that have the signal blocked cannot be suspended, and are skipped.
+///
+/// Suspenders can be nested; the threads are resumed when the outermost one is dropped.
+pub struct ThreadSuspender {
+    _private: (),
+}
+impl ThreadSuspender {
+    pub fn new() -> anyhow::Result<Self> {
+        install_handler()?;
+        let mut suspension = suspension();
+        match suspension.as_mut() {
+            Some(suspension) => suspension.depth += 1,
+            None => {
+                let threads = Self::suspend(&Self::thread_ids()?)?;
+                *suspension = Some(Suspension { threads, depth: 1 });
+            }
+        }
+        Ok(Self { _private: () })
+    }
+    fn thread_ids() -> anyhow::Result<Vec<libc::pid_t>> {
+        let own_tid = gettid();
+        let mut ids = vec![];
+        for entry in fs::read_dir("/proc/self/task").context("failed to list threads")? {
+            let id = entry?.file_name().to_string_lossy().parse()?;
+            if id != own_tid {
+                ids.push(id);
+            }
+        }
+        Ok(ids)
+    }
+    fn suspend(ids: &[libc::pid_t]) -> anyhow::Result<Vec<SuspendedThread>> {
+        if ids.len() > MAX_THREADS {
+            anyhow::bail!(
+                "cannot suspend {} threads; at most {MAX_THREADS} are supported",
+                ids.len()
+            );
+        }
+
+        let pid = unsafe { libc::getpid() };
+        let mut requested = Vec::with_capacity(ids.len());
+        for (&id, slot) in ids.iter().zip(&SLOTS) {
+            // A handler from a previous suspension may still be on its way out.
+            while slot.state.load(Ordering::Acquire) != IDLE {
+                thread::yield_now();
+            }
+            slot.tid.store(id, Ordering::Release);
+            slot.state.store(REQUESTED, Ordering::Release);
+            let sent = unsafe { libc::syscall(libc::SYS_tgkill, pid, id, signal()) } == 0;
+            if sent {
+                requested.push(SuspendedThread { id, slot });
+            } else {
+                // The thread has exited since we listed it.
+                slot.state.store(IDLE, Ordering::Release);
+            }
+        }
+
+        let deadline = Instant::now() + SUSPEND_TIMEOUT;
+        let mut suspended = Vec::with_capacity(requested.len());
+        for thread in requested {
+            loop {
+                if thread.slot.state.load(Ordering::Acquire) == SUSPENDED {
+                    suspended.push(thread);
+                    break;
+                }
+                if Instant::now() >= deadline
+                    && thread
+                        .slot
+                        .state
+                        .compare_exchange(REQUESTED, IDLE, Ordering::AcqRel, Ordering::Acquire)
+                        .is_ok()
+                {
+                    break;
+                }
+                thread::yield_now();
+            }
+        }
+
+        #[cfg(feature = "debug-console")]
+        println!("Suspended {} threads", suspended.len());
+        Ok(suspended)
+    }
+    fn resume(threads: &[SuspendedThread]) {
+        #[cfg(feature = "debug-console")]
+        println!("Resumed {} threads", threads.len());
+        for thread in threads {
+            thread.slot.state.store(RESUMING, Ordering::Release);
+            futex_wake(&thread.slot.state);
+        }
+    }
+    pub fn for_block<T>(mut f: impl FnMut() -> anyhow::Result<T>) -> anyhow::Result<T> {
+        let _suspender = ThreadSuspender::new()?;
+        f()
+    }
+
+    /// Calls `f` with the threads that are currently suspended.
+    pub fn with_threads<T>(&self, f: impl FnOnce(&mut [SuspendedThread]) -> T) -> T {
+        Self::with_suspended(f).expect("threads should be suspended")
+    }
+
+    /// Calls `f` with the threads that are currently suspended, if there are any.
+    pub(crate) fn with_suspended<T>(f: impl FnOnce(&mut [SuspendedThread]) -> T) -> Option<T> {
+        suspension()
+            .as_mut()
+            .map(|suspension| f(&mut suspension.threads))
+    }
+
+    /// Resumes the suspended threads for `duration` so that they can make progress, then
+    /// suspends them again.
+    pub(crate) fn let_threads_run(duration: Duration) {
+        let mut suspension = suspension();
+        let Some(suspension) = suspension.as_mut() else {
+            return;
+        };
+        let ids: Vec<_> = suspension.threads.iter().map(|t| t.id).collect();
+        Self::resume(&mem::take(&mut suspension.threads));
+        thread::sleep(duration);
+        // Threads that fail to suspend again are left running, as they would be if they had
+        // been created after the suspension.
+        suspension.threads = Self::suspend(&ids).unwrap_or_default();
+    }
+}
+impl Drop for ThreadSuspender {
+    fn drop(&mut self) {
+        let mut suspension = suspension();
+        let Some(state) = suspension.as_mut() else {
+            return;
+        };
+        state.depth -= 1;
+        if state.depth == 0 {
+            let state = suspension.take().unwrap();
+            Self::resume(&state.threads);
+        }
+    }
+}
@@ -0,0 +1,99 @@
+#[cfg(target_os = "linux")]
+use crate::linux::memory as sys;
+#[cfg(target_os = "windows")]
+use crate::windows::memory as sys;
+
+use std::{ops::Range, path::PathBuf};
+
+pub use sys::{allocate_near, free, query, read, regions, safe_write, write};
+
+/// How far away from its target [`allocate_near`] may place an allocation. This is a little
+/// under 2GiB so that a `rel32` displacement can reach every byte of the allocation.
+pub const NEAR_RANGE: usize = 0x7FF0_0000;
+
+/// Returns the `rel32` displacement for a branch whose next instruction starts at `next` and
+/// lands on `destination`, if it is within reach.
+pub fn rel32(next: usize, destination: usize) -> Option<i32> {
+    (destination as isize)
+        .wrapping_sub(next as isize)
+        .try_into()
+        .ok()
+}
+
+/// Whether a [`Region`] is in use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RegionState {
+    /// Backed by memory; only committed regions can be accessed.
+    Committed,
+    /// Set aside, but not backed by memory yet. Only reported on Windows.
+    Reserved,
+    /// Not mapped at all.
+    Free,
+}
+
+/// What a [`Region`] holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RegionKind {
+    /// Mapped from an executable image, such as the main executable or a library.
+    Image,
+    /// Mapped from some other file, or shared memory.
+    Mapped,
+    /// Private to the process, such as heaps, stacks and JIT code.
+    Private,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Protection {
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+    /// The region is a guard page, which faults on first access. Only reported on Windows.
+    pub guard: bool,
+}
+
+/// A run of pages with the same state and protection.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Region {
+    pub base: usize,
+    pub size: usize,
+    pub state: RegionState,
+    /// `None` for regions that are not committed.
+    pub kind: Option<RegionKind>,
+    pub protection: Protection,
+    /// The file of the module that the region belongs to, if any.
+    pub module: Option<PathBuf>,
+    // The protection as the platform describes it, so that it can be restored exactly.
+    pub(crate) native_protection: u32,
+}
+
+impl Region {
+    pub fn range(&self) -> Range<usize> {
+        self.base..self.base + self.size
+    }
+
+    pub fn contains(&self, address: usize) -> bool {
+        self.range().contains(&address)
+    }
+
+    /// Whether the region can be read without faulting.
+    pub fn is_readable(&self) -> bool {
+        self.state == RegionState::Committed && self.protection.read && !self.protection.guard
+    }
+
+    /// Whether the region can be written without faulting.
+    pub fn is_writable(&self) -> bool {
+        self.is_readable() && self.protection.write
+    }
+}
+
+/// Returns the regions covering `range`, in order. The first and last may extend past it.
+pub(crate) fn regions_in(range: Range<usize>) -> anyhow::Result<Vec<Region>> {
+    let mut regions = vec![];
+    let mut address = range.start;
+    while address < range.end {
+        let region = query(address)?;
+        address = region.range().end;
+        regions.push(region);
+    }
+    Ok(regions)
+}
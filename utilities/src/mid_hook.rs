@@ -0,0 +1,472 @@
+use std::{
+    mem::{self, offset_of, ManuallyDrop},
+    slice,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex, MutexGuard,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Context as _;
+use iced_x86::{code_asm::*, BlockEncoderOptions, Instruction};
+
+use crate::{
+    code_cave::{CodeCave, CodeCaveAllocator},
+    instructions, memory,
+    thread_relocation::relocate_threads,
+    ThreadSuspender,
+};
+
+/// The register state at the point a [`MidHook`] was hit. Any changes made by the callback are
+/// written back before execution resumes, with the exception of `rsp`, which is read-only.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Context {
+    pub xmm0: u128,
+    pub xmm1: u128,
+    pub xmm2: u128,
+    pub xmm3: u128,
+    pub xmm4: u128,
+    pub xmm5: u128,
+    pub xmm6: u128,
+    pub xmm7: u128,
+    pub xmm8: u128,
+    pub xmm9: u128,
+    pub xmm10: u128,
+    pub xmm11: u128,
+    pub xmm12: u128,
+    pub xmm13: u128,
+    pub xmm14: u128,
+    pub xmm15: u128,
+    pub rax: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rbx: u64,
+    pub rsp: u64,
+    pub rbp: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rflags: u64,
+}
+
+type Callback = dyn Fn(&mut Context) + Send + Sync;
+
+/// The size of the `jmp rel32` written over the hooked instructions.
+const JMP_SIZE: usize = 5;
+/// The number of bytes below `rsp` that may be in use by the hooked code (the System V red zone).
+const RED_ZONE: i32 = 128;
+/// How long dropping a hook waits for calls to leave its callback before leaking it instead.
+const IN_FLIGHT_TIMEOUT: Duration = Duration::from_secs(5);
+
+static CAVES: Mutex<CodeCaveAllocator> = Mutex::new(CodeCaveAllocator::new());
+
+fn caves() -> MutexGuard<'static, CodeCaveAllocator> {
+    CAVES.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+struct State {
+    target: usize,
+    callback: Box<Callback>,
+    original_bytes: Vec<u8>,
+    patch_bytes: Vec<u8>,
+    stub: Option<CodeCave>,
+    // The address of each overwritten instruction, paired with the address of its copy in the
+    // stub.
+    relocations: Vec<(usize, usize)>,
+    enabled: Mutex<bool>,
+    // The number of threads that have called into the callback from the stub and not yet
+    // returned to it. Maintained by the stub itself, as those threads' return addresses point
+    // into it.
+    in_flight: AtomicUsize,
+}
+
+impl State {
+    /// The addresses at which a thread would be partway through the overwritten instructions.
+    fn interior(&self) -> std::ops::Range<usize> {
+        self.target + 1..self.target + self.original_bytes.len()
+    }
+}
+
+/// A hook on an arbitrary instruction, rather than a function entry point.
+///
+/// When execution reaches the hooked instruction, the registers are saved to a [`Context`] and
+/// passed to the callback. Once it returns, the (possibly modified) registers are restored, the
+/// instructions overwritten by the hook are run from a trampoline, and execution continues
+/// after them.
+///
+/// At least five bytes of whole instructions are overwritten at the target; nothing may branch
+/// into the middle of them. If the other threads have been suspended with a
+/// [`crate::ThreadSuspender`], any thread that is partway through them when the hook is enabled
+/// is moved to the copy of its instruction in the trampoline.
+pub struct MidHook {
+    // Boxed so that the stub can refer to the state by address. Leaked, along with the stub, if
+    // the hook is dropped while it cannot be shown to be unused.
+    state: ManuallyDrop<Box<State>>,
+}
+impl MidHook {
+    /// Creates a disabled hook at `target` that calls `callback` each time it is reached.
+    ///
+    /// # Safety
+    /// `target` must be the start of an instruction in executable memory.
+    pub unsafe fn new(
+        target: usize,
+        callback: impl Fn(&mut Context) + Send + Sync + 'static,
+    ) -> anyhow::Result<MidHook> {
+        let relocated = instructions::decode_covering(target, JMP_SIZE)?;
+        let overwritten_len: usize = relocated.iter().map(|i| i.len()).sum();
+        let original_bytes = slice::from_raw_parts(target as *const u8, overwritten_len).to_vec();
+
+        let mut state = Box::new(State {
+            target,
+            callback: Box::new(callback),
+            original_bytes,
+            patch_bytes: vec![],
+            stub: None,
+            relocations: vec![],
+            enabled: Mutex::new(false),
+            in_flight: AtomicUsize::new(0),
+        });
+
+        // Assemble once to find out how large the stub is, then again at its real address, as
+        // the encoding of the relocated instructions depends on where they end up.
+        let state_ptr = &*state as *const State as u64;
+        let size = assemble_stub(
+            target as u64,
+            state_ptr,
+            &relocated,
+            target + overwritten_len,
+        )?
+        .0
+        .len();
+        let stub = caves().allocate(target, size + 16)?;
+        let (code, relocated_ips) = match assemble_stub(
+            stub.address() as u64,
+            state_ptr,
+            &relocated,
+            target + overwritten_len,
+        ) {
+            Ok((code, ips)) if code.len() <= stub.size() => (code, ips),
+            result => {
+                caves().free(stub);
+                result?;
+                anyhow::bail!("mid-hook stub for {target:#x} grew during relocation");
+            }
+        };
+        std::ptr::copy_nonoverlapping(code.as_ptr(), stub.as_mut_ptr(), code.len());
+
+        let rel = memory::rel32(target + JMP_SIZE, stub.address())
+            .context("mid-hook stub is out of reach")?;
+        state.patch_bytes = [0xE9]
+            .into_iter()
+            .chain(rel.to_le_bytes())
+            .chain(std::iter::repeat_n(0x90, overwritten_len - JMP_SIZE))
+            .collect();
+        state.stub = Some(stub);
+        state.relocations = relocated
+            .iter()
+            .map(|i| i.ip() as usize)
+            .zip(relocated_ips)
+            .collect();
+
+        Ok(MidHook {
+            state: ManuallyDrop::new(state),
+        })
+    }
+
+    /// # Safety
+    /// No thread may be executing the instructions being overwritten, unless the other threads
+    /// have been suspended with a [`crate::ThreadSuspender`].
+    pub unsafe fn enable(&self) -> anyhow::Result<()> {
+        let mut enabled = self.state.enabled.lock().unwrap_or_else(|e| e.into_inner());
+        if !*enabled {
+            relocate_threads(self.state.interior(), |ip| {
+                self.state
+                    .relocations
+                    .iter()
+                    .find(|(original, _)| *original == ip)
+                    .map(|(_, relocated)| *relocated)
+            })?;
+            memory::safe_write(self.state.target as *mut u8, &self.state.patch_bytes)?;
+            *enabled = true;
+        }
+        Ok(())
+    }
+
+    /// # Safety
+    /// No thread may be executing the instructions being restored, unless the other threads
+    /// have been suspended with a [`crate::ThreadSuspender`].
+    pub unsafe fn disable(&self) -> anyhow::Result<()> {
+        let mut enabled = self.state.enabled.lock().unwrap_or_else(|e| e.into_inner());
+        if *enabled {
+            relocate_threads(self.state.interior(), |_| None)?;
+            memory::safe_write(self.state.target as *mut u8, &self.state.original_bytes)?;
+            *enabled = false;
+        }
+        Ok(())
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        *self.state.enabled.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn target(&self) -> usize {
+        self.state.target
+    }
+}
+impl MidHook {
+    /// Waits for the calls that are inside the callback to return to the stub, letting any
+    /// suspended threads run so that they can. Returns whether they did in time.
+    fn wait_until_idle(&self) -> bool {
+        const RETRY_INTERVAL: Duration = Duration::from_millis(1);
+
+        let deadline = Instant::now() + IN_FLIGHT_TIMEOUT;
+        while self.state.in_flight.load(Ordering::SeqCst) != 0 {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            if ThreadSuspender::with_suspended(|_| ()).is_some() {
+                ThreadSuspender::let_threads_run(RETRY_INTERVAL);
+            } else {
+                std::thread::sleep(RETRY_INTERVAL);
+            }
+        }
+        true
+    }
+}
+impl Drop for MidHook {
+    fn drop(&mut self) {
+        // Threads inside the callback will return into the stub and read the state, so both
+        // are leaked if the hook is still in place or still being called.
+        if unsafe { self.disable() }.is_err() || !self.wait_until_idle() {
+            return;
+        }
+        if let Some(stub) = self.state.stub.take() {
+            // Threads running the relocated instructions can carry on from the originals, now
+            // that they have been restored.
+            let _ = relocate_threads(stub.address()..stub.address() + stub.size(), |ip| {
+                self.state
+                    .relocations
+                    .iter()
+                    .find(|(_, relocated)| *relocated == ip)
+                    .map(|(original, _)| *original)
+            });
+            caves().free(stub);
+        }
+        unsafe { ManuallyDrop::drop(&mut self.state) };
+    }
+}
+
+unsafe extern "C" fn dispatch(context: *mut Context, state: *const State) {
+    ((*state).callback)(&mut *context);
+}
+
+/// Assembles the stub that saves the registers, calls [`dispatch`], restores the registers, runs
+/// the relocated instructions and jumps back to `resume`. Returns the code, along with the
+/// address of each relocated instruction within it.
+fn assemble_stub(
+    ip: u64,
+    state: u64,
+    relocated: &[Instruction],
+    resume: usize,
+) -> anyhow::Result<(Vec<u8>, Vec<usize>)> {
+    fn offset(field: usize) -> i32 {
+        field as i32
+    }
+    const GPRS: [(AsmRegister64, usize); 14] = [
+        (rcx, offset_of!(Context, rcx)),
+        (rdx, offset_of!(Context, rdx)),
+        (rbx, offset_of!(Context, rbx)),
+        (rbp, offset_of!(Context, rbp)),
+        (rsi, offset_of!(Context, rsi)),
+        (rdi, offset_of!(Context, rdi)),
+        (r8, offset_of!(Context, r8)),
+        (r9, offset_of!(Context, r9)),
+        (r10, offset_of!(Context, r10)),
+        (r11, offset_of!(Context, r11)),
+        (r12, offset_of!(Context, r12)),
+        (r13, offset_of!(Context, r13)),
+        (r14, offset_of!(Context, r14)),
+        (r15, offset_of!(Context, r15)),
+    ];
+    const XMMS: [AsmRegisterXmm; 16] = [
+        xmm0, xmm1, xmm2, xmm3, xmm4, xmm5, xmm6, xmm7, xmm8, xmm9, xmm10, xmm11, xmm12, xmm13,
+        xmm14, xmm15,
+    ];
+    let xmm_offset = |index: usize| offset(offset_of!(Context, xmm0) + index * 16);
+    let in_flight = offset(offset_of!(State, in_flight));
+
+    // The context is followed by a slot holding the address of the saved rax/rflags, which is
+    // also where rsp has to return to.
+    let frame_slot = offset(mem::size_of::<Context>());
+    let frame_size = (mem::size_of::<Context>() + 16).next_multiple_of(16) as i32;
+    // The saved rax and rflags sit between the original rsp and the frame.
+    let saved_size = RED_ZONE + 16;
+
+    let mut a = CodeAssembler::new(64)?;
+
+    // Step over the red zone, save the registers we need to align the stack, then align it.
+    a.lea(rsp, ptr(rsp - RED_ZONE))?;
+    a.pushfq()?;
+    a.push(rax)?;
+    a.mov(rax, rsp)?;
+    a.and(rsp, -16)?;
+    a.sub(rsp, frame_size)?;
+    a.mov(qword_ptr(rsp + frame_slot), rax)?;
+
+    // Fill in the context.
+    for (register, field) in GPRS {
+        a.mov(qword_ptr(rsp + offset(field)), register)?;
+    }
+    a.mov(rcx, qword_ptr(rax))?;
+    a.mov(qword_ptr(rsp + offset(offset_of!(Context, rax))), rcx)?;
+    a.mov(rcx, qword_ptr(rax + 8))?;
+    a.mov(qword_ptr(rsp + offset(offset_of!(Context, rflags))), rcx)?;
+    a.lea(rcx, ptr(rax + saved_size))?;
+    a.mov(qword_ptr(rsp + offset(offset_of!(Context, rsp))), rcx)?;
+    for (index, register) in XMMS.into_iter().enumerate() {
+        a.movdqu(xmmword_ptr(rsp + xmm_offset(index)), register)?;
+    }
+
+    // Call the dispatcher with the context and state, counting the call as in flight until it
+    // returns. The arguments are placed in the registers for both the Windows and System V
+    // calling conventions, and the stack has shadow space for the former.
+    a.cld()?;
+    a.mov(rbx, rsp)?;
+    a.mov(rcx, rsp)?;
+    a.mov(rdi, rsp)?;
+    a.mov(rdx, state)?;
+    a.lock().inc(qword_ptr(rdx + in_flight))?;
+    a.mov(rsi, rdx)?;
+    a.sub(rsp, 32)?;
+    a.mov(rax, dispatch as *const () as u64)?;
+    a.call(rax)?;
+    a.mov(rsp, rbx)?;
+    a.mov(rax, state)?;
+    a.lock().dec(qword_ptr(rax + in_flight))?;
+
+    // Restore everything from the context. rax and rflags go back via the frame, as they are
+    // needed until the very end.
+    for (index, register) in XMMS.into_iter().enumerate() {
+        a.movdqu(register, xmmword_ptr(rsp + xmm_offset(index)))?;
+    }
+    a.mov(rax, qword_ptr(rsp + frame_slot))?;
+    a.mov(rcx, qword_ptr(rsp + offset(offset_of!(Context, rax))))?;
+    a.mov(qword_ptr(rax), rcx)?;
+    a.mov(rcx, qword_ptr(rsp + offset(offset_of!(Context, rflags))))?;
+    a.mov(qword_ptr(rax + 8), rcx)?;
+    a.mov(rax, rsp)?;
+    for (register, field) in GPRS {
+        a.mov(register, qword_ptr(rax + offset(field)))?;
+    }
+    a.mov(rsp, qword_ptr(rax + frame_slot))?;
+    a.pop(rax)?;
+    a.popfq()?;
+    a.lea(rsp, ptr(rsp + RED_ZONE))?;
+
+    // Run the instructions we overwrote, then return to the original code.
+    let mut labels = Vec::with_capacity(relocated.len());
+    for instruction in relocated {
+        let mut label = a.create_label();
+        a.set_label(&mut label)?;
+        a.add_instruction(*instruction)?;
+        labels.push(label);
+    }
+    a.jmp(resume as u64)?;
+
+    let result = a.assemble_options(ip, BlockEncoderOptions::RETURN_NEW_INSTRUCTION_OFFSETS)?;
+    let ips = labels
+        .iter()
+        .map(|label| Ok(result.label_ip(label)? as usize))
+        .collect::<anyhow::Result<_>>()?;
+    Ok((result.inner.code_buffer, ips))
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use std::{
+        hint::black_box,
+        sync::atomic::{AtomicBool, AtomicU64},
+    };
+
+    use super::*;
+
+    #[inline(never)]
+    extern "C" fn add_one(value: u64) -> u64 {
+        value.wrapping_add(1)
+    }
+
+    #[inline(never)]
+    extern "C" fn add_two(value: u64) -> u64 {
+        value.wrapping_add(2)
+    }
+
+    #[test]
+    fn drop_waits_for_calls_inside_the_callback() {
+        static ENTERED: AtomicBool = AtomicBool::new(false);
+        static RELEASED: AtomicBool = AtomicBool::new(false);
+
+        let hook = unsafe {
+            MidHook::new(add_two as *const () as usize, |context| {
+                ENTERED.store(true, Ordering::SeqCst);
+                while !RELEASED.load(Ordering::SeqCst) {
+                    std::thread::yield_now();
+                }
+                context.rdi = 10;
+            })
+        }
+        .unwrap();
+        unsafe { hook.enable() }.unwrap();
+
+        let caller = std::thread::spawn(|| black_box(add_two as extern "C" fn(u64) -> u64)(1));
+        while !ENTERED.load(Ordering::SeqCst) {
+            std::thread::yield_now();
+        }
+        let dropper = std::thread::spawn(move || drop(hook));
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(!dropper.is_finished());
+
+        RELEASED.store(true, Ordering::SeqCst);
+        dropper.join().unwrap();
+        assert_eq!(caller.join().unwrap(), 12);
+        assert_eq!(black_box(add_two as extern "C" fn(u64) -> u64)(1), 3);
+    }
+
+    #[test]
+    fn callback_can_read_and_change_registers() {
+        static SEEN: AtomicU64 = AtomicU64::new(0);
+
+        let target = add_one as *const () as usize;
+        let hook = unsafe {
+            MidHook::new(target, |context| {
+                // The first argument is passed in rdi
+                SEEN.store(context.rdi, Ordering::SeqCst);
+                context.rdi = 41;
+            })
+        }
+        .unwrap();
+        assert_eq!(hook.target(), target);
+        assert!(!hook.is_enabled());
+        assert_eq!(black_box(add_one as extern "C" fn(u64) -> u64)(1), 2);
+
+        unsafe { hook.enable() }.unwrap();
+        assert!(hook.is_enabled());
+        assert_eq!(black_box(add_one as extern "C" fn(u64) -> u64)(1), 42);
+        assert_eq!(SEEN.load(Ordering::SeqCst), 1);
+
+        unsafe { hook.disable() }.unwrap();
+        assert_eq!(black_box(add_one as extern "C" fn(u64) -> u64)(7), 8);
+        assert_eq!(SEEN.load(Ordering::SeqCst), 1);
+
+        drop(hook);
+        assert_eq!(black_box(add_one as extern "C" fn(u64) -> u64)(1), 2);
+    }
+}
@@ -0,0 +1,577 @@
+use std::ops::Range;
+
+use crate::{
+    code_cave::{CodeCave, CodeCaveAllocator},
+    instructions, memory,
+    thread_relocation::relocate_threads,
+    util,
+};
+
+const NOP: u8 = 0x90;
+
+struct Patch {
+    address: usize,
+    original_bytes: Box<[u8]>,
+    // Code cave memory that the patched bytes refer to, such as a jump stub for a destination
+    // that was out of reach. Freed when the patch is removed.
+    cave: Option<CodeCave>,
+}
+
+impl Patch {
+    fn range(&self) -> Range<usize> {
+        self.address..self.address + self.original_bytes.len()
+    }
+
+    fn overlaps(&self, range: &Range<usize>) -> bool {
+        self.address < range.end && range.start < self.range().end
+    }
+
+    /// The addresses at which a thread would be partway through the patch.
+    fn interior(&self) -> Range<usize> {
+        self.address + 1..self.range().end
+    }
+}
+
+/// Applies byte patches to memory, remembering the bytes underneath them so that they can be
+/// restored.
+///
+/// [`Patcher::patch`] writes bytes as they are, which suits data. For code,
+/// [`Patcher::patch_code`] also checks that the patch ends on an instruction boundary, while
+/// [`Patcher::patch_padded`] and [`Patcher::nop_instructions`] fill out the rest of the last
+/// instruction.
+///
+/// Patches may not overlap unless they are explicitly stacked with [`Patcher::patch_stacked`].
+/// Stacked patches can be removed in any order; the bytes that were present before each patch
+/// are carried over to whichever patch is still covering them.
+///
+/// If the other threads have been suspended with a [`crate::ThreadSuspender`], patching and
+/// unpatching wait for any thread that is partway through the affected bytes to move on, and
+/// fail if it does not.
+pub struct Patcher {
+    // Kept in the order the patches were applied; later patches sit on top of earlier ones.
+    patches: Vec<Patch>,
+    caves: CodeCaveAllocator,
+}
+
+#[allow(clippy::missing_safety_doc)]
+impl Patcher {
+    pub fn new() -> Patcher {
+        Patcher {
+            patches: vec![],
+            caves: CodeCaveAllocator::new(),
+        }
+    }
+
+    pub unsafe fn safe_write(&self, ptr: *mut u8, bytes: &[u8]) -> anyhow::Result<()> {
+        memory::safe_write(ptr, bytes)
+    }
+
+    /// Writes `bytes` to `address`. Fails if the range overlaps an existing patch.
+    pub unsafe fn patch(&mut self, address: usize, bytes: &[u8]) -> anyhow::Result<()> {
+        let range = address..address + bytes.len();
+        if let Some(existing) = self.patches.iter().find(|p| p.overlaps(&range)) {
+            anyhow::bail!(
+                "patch at {:#x}..{:#x} overlaps existing patch at {:#x}..{:#x}",
+                range.start,
+                range.end,
+                existing.range().start,
+                existing.range().end
+            );
+        }
+
+        self.apply(address, bytes)
+    }
+
+    /// Writes `bytes` over the instructions at `address`. Fails if the range overlaps an
+    /// existing patch, or if it would end partway through an instruction.
+    pub unsafe fn patch_code(&mut self, address: usize, bytes: &[u8]) -> anyhow::Result<()> {
+        self.check_boundary(address, bytes.len())?;
+        self.patch(address, bytes)
+    }
+
+    /// Writes `bytes` to `address`, padding them with NOPs up to the end of the last instruction
+    /// they touch. Returns the number of bytes written. Fails if the range overlaps an existing
+    /// patch.
+    pub unsafe fn patch_padded(&mut self, address: usize, bytes: &[u8]) -> anyhow::Result<usize> {
+        let len = instructions::boundary_after(address, bytes.len())?;
+        let mut padded = bytes.to_vec();
+        padded.resize(len, NOP);
+        self.patch(address, &padded)?;
+        Ok(len)
+    }
+
+    /// Replaces the `count` instructions starting at `address` with NOPs. Returns the number of
+    /// bytes written. Fails if the range overlaps an existing patch.
+    pub unsafe fn nop_instructions(
+        &mut self,
+        address: usize,
+        count: usize,
+    ) -> anyhow::Result<usize> {
+        let len = instructions::decode(address, count)?
+            .iter()
+            .map(|i| i.len())
+            .sum();
+        self.patch(address, &vec![NOP; len])?;
+        Ok(len)
+    }
+
+    /// Writes `bytes` to `address`, allowing it to overlap existing patches. The new patch
+    /// sits on top of any patches it overlaps. Like [`Patcher::patch`], instruction boundaries
+    /// are not checked.
+    pub unsafe fn patch_stacked(&mut self, address: usize, bytes: &[u8]) -> anyhow::Result<()> {
+        self.apply(address, bytes)
+    }
+
+    /// Removes the most recently applied patch starting at `address`, restoring the bytes
+    /// that were underneath it. Bytes that are still covered by a later patch are left alone.
+    pub unsafe fn unpatch(&mut self, address: usize) -> anyhow::Result<()> {
+        let Some(index) = self.patches.iter().rposition(|p| p.address == address) else {
+            anyhow::bail!("no patch at {address:#x}");
+        };
+        relocate_threads(self.patches[index].interior(), |_| None)?;
+        let patch = self.patches.remove(index);
+
+        // Walk each byte of the removed patch: if a later patch covers it, that patch now
+        // sits directly on top of our original byte; otherwise, the byte goes back to memory.
+        let mut restore = vec![];
+        for (offset, original) in patch.original_bytes.iter().enumerate() {
+            let byte_address = patch.address + offset;
+            let covering = self.patches[index..]
+                .iter_mut()
+                .find(|p| p.range().contains(&byte_address));
+
+            match covering {
+                Some(later) => later.original_bytes[byte_address - later.address] = *original,
+                None => restore.push((byte_address, *original)),
+            }
+        }
+
+        // Coalesce the bytes to restore into contiguous runs to minimise protection changes.
+        let mut runs: Vec<(usize, Vec<u8>)> = vec![];
+        for (byte_address, byte) in restore {
+            match runs.last_mut() {
+                Some((start, bytes)) if *start + bytes.len() == byte_address => bytes.push(byte),
+                _ => runs.push((byte_address, vec![byte])),
+            }
+        }
+        for (start, bytes) in runs {
+            self.safe_write(util::make_ptr(start), &bytes)?;
+        }
+        if let Some(cave) = patch.cave {
+            self.caves.free(cave);
+        }
+
+        Ok(())
+    }
+
+    /// Returns whether any byte in `address..address + len` is currently patched.
+    pub fn is_patched(&self, address: usize, len: usize) -> bool {
+        let range = address..address + len;
+        self.patches.iter().any(|p| p.overlaps(&range))
+    }
+
+    /// Replaces the destination of the call at `src` with `dst`, returning the original
+    /// destination. The call must be either a `call rel32` (`E8`) or a `call [rip+disp32]`
+    /// (`FF 15`); if `dst` is too far away to be reached with a `rel32`, the call is routed
+    /// through a jump stub allocated near `src`.
+    ///
+    /// The replacement can be undone with [`Patcher::unpatch`] on `src`.
+    pub unsafe fn replace_call_destination(
+        &mut self,
+        src: usize,
+        dst: usize,
+    ) -> anyhow::Result<usize> {
+        self.replace_branch_destination(src, dst, BranchKind::Call)
+    }
+
+    /// Replaces the destination of the jump at `src` with `dst`, returning the original
+    /// destination. The jump must be either a `jmp rel32` (`E9`) or a `jmp [rip+disp32]`
+    /// (`FF 25`); if `dst` is too far away to be reached with a `rel32`, the jump is routed
+    /// through a jump stub allocated near `src`.
+    ///
+    /// The replacement can be undone with [`Patcher::unpatch`] on `src`.
+    pub unsafe fn replace_jump_destination(
+        &mut self,
+        src: usize,
+        dst: usize,
+    ) -> anyhow::Result<usize> {
+        self.replace_branch_destination(src, dst, BranchKind::Jump)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum BranchKind {
+    Call,
+    Jump,
+}
+impl BranchKind {
+    fn rel32_opcode(self) -> u8 {
+        match self {
+            BranchKind::Call => 0xE8,
+            BranchKind::Jump => 0xE9,
+        }
+    }
+    fn indirect_modrm(self) -> u8 {
+        match self {
+            BranchKind::Call => 0x15,
+            BranchKind::Jump => 0x25,
+        }
+    }
+}
+
+impl Patcher {
+    unsafe fn check_boundary(&self, address: usize, len: usize) -> anyhow::Result<()> {
+        let boundary = instructions::boundary_after(address, len)?;
+        if boundary != len {
+            anyhow::bail!(
+                "patch at {:#x}..{:#x} would split the instruction ending at {:#x}",
+                address,
+                address + len,
+                address + boundary
+            );
+        }
+        Ok(())
+    }
+
+    unsafe fn replace_branch_destination(
+        &mut self,
+        src: usize,
+        dst: usize,
+        kind: BranchKind,
+    ) -> anyhow::Result<usize> {
+        // Read only as much as the branch's form takes, as the instruction may end the page
+        let opcode = util::make_ptr::<u8>(src).read();
+        let len = if opcode == kind.rel32_opcode() { 5 } else { 6 };
+        let instruction = std::slice::from_raw_parts(util::make_ptr::<u8>(src), len);
+
+        let (orig_dest, new_bytes, cave) = if instruction[0] == kind.rel32_opcode() {
+            // A relative branch: the destination is relative to the end of the instruction.
+            let next = src + 5;
+            let rel = i32::from_le_bytes(instruction[1..5].try_into()?);
+            let orig_dest = next.wrapping_add_signed(rel as isize);
+
+            let (new_rel, cave) = match memory::rel32(next, dst) {
+                Some(rel) => (rel, None),
+                None => {
+                    let cave = self.caves.allocate_jump_stub(src, dst)?;
+                    let rel = memory::rel32(next, cave.address())
+                        .expect("code cave should be within reach");
+                    (rel, Some(cave))
+                }
+            };
+
+            let mut new_bytes = vec![kind.rel32_opcode()];
+            new_bytes.extend_from_slice(&new_rel.to_le_bytes());
+            (orig_dest, new_bytes, cave)
+        } else if instruction[0] == 0xFF && instruction[1] == kind.indirect_modrm() {
+            // An indirect branch through a pointer: on x86-64, the pointer's location is
+            // relative to the end of the instruction, while on x86 it is absolute.
+            let disp = i32::from_le_bytes(instruction[2..6].try_into()?);
+            #[cfg(target_pointer_width = "64")]
+            let slot = (src + 6).wrapping_add_signed(disp as isize);
+            #[cfg(target_pointer_width = "32")]
+            let slot = disp as usize;
+            let orig_dest = util::make_ptr::<usize>(slot).read_unaligned();
+
+            // Rather than changing the shape of the instruction, point it at a new slot
+            // containing our destination.
+            let cave = self.caves.allocate(src, std::mem::size_of::<usize>())?;
+            (cave.as_mut_ptr() as *mut usize).write_unaligned(dst);
+            #[cfg(target_pointer_width = "64")]
+            let new_disp =
+                memory::rel32(src + 6, cave.address()).expect("code cave should be within reach");
+            #[cfg(target_pointer_width = "32")]
+            let new_disp = cave.address() as i32;
+
+            let mut new_bytes = vec![0xFF, kind.indirect_modrm()];
+            new_bytes.extend_from_slice(&new_disp.to_le_bytes());
+            (orig_dest, new_bytes, Some(cave))
+        } else {
+            anyhow::bail!(
+                "instruction at {src:#x} is not a supported {} (found {:02X?})",
+                match kind {
+                    BranchKind::Call => "call",
+                    BranchKind::Jump => "jump",
+                },
+                instruction
+            );
+        };
+
+        // Finally, we patch the existing branch and return the original destination.
+        if let Err(err) = self.patch(src, &new_bytes) {
+            if let Some(cave) = cave {
+                self.caves.free(cave);
+            }
+            return Err(err);
+        }
+        self.patches
+            .last_mut()
+            .expect("patch was just applied")
+            .cave = cave;
+
+        Ok(orig_dest)
+    }
+
+    unsafe fn apply(&mut self, address: usize, bytes: &[u8]) -> anyhow::Result<()> {
+        let addr_ptr = util::make_ptr::<u8>(address);
+        let mut original_bytes = vec![0; bytes.len()];
+        memory::read(address, &mut original_bytes)?;
+        let patch = Patch {
+            address,
+            original_bytes: original_bytes.into(),
+            cave: None,
+        };
+        relocate_threads(patch.interior(), |_| None)?;
+        self.safe_write(addr_ptr, bytes)?;
+        self.patches.push(patch);
+        Ok(())
+    }
+}
+
+impl Default for Patcher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for Patcher {
+    fn drop(&mut self) {
+        // Unwind in reverse application order so that stacked patches restore cleanly.
+        while let Some(patch) = self.patches.pop() {
+            let _ = relocate_threads(patch.interior(), |_| None);
+            unsafe {
+                let _ = self.safe_write(util::make_ptr(patch.address), &patch.original_bytes);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BUFFER_SIZE: usize = 0x1000;
+
+    /// A page of NOPs to patch, so that every byte is an instruction boundary.
+    struct Buffer(usize);
+    impl Buffer {
+        fn new() -> Buffer {
+            let address =
+                memory::allocate_near(Buffer::new as fn() -> Buffer as usize, BUFFER_SIZE).unwrap();
+            unsafe { std::ptr::write_bytes(address as *mut u8, NOP, BUFFER_SIZE) };
+            Buffer(address)
+        }
+        fn at(&self, offset: usize) -> usize {
+            self.0 + offset
+        }
+        fn write(&self, offset: usize, bytes: &[u8]) {
+            unsafe {
+                std::ptr::copy_nonoverlapping(
+                    bytes.as_ptr(),
+                    self.at(offset) as *mut u8,
+                    bytes.len(),
+                )
+            };
+        }
+        fn bytes(&self, offset: usize, len: usize) -> Vec<u8> {
+            unsafe { std::slice::from_raw_parts((self.0 + offset) as *const u8, len).to_vec() }
+        }
+    }
+    impl Drop for Buffer {
+        fn drop(&mut self) {
+            unsafe { memory::free(self.0, BUFFER_SIZE).unwrap() };
+        }
+    }
+
+    #[test]
+    fn unpatch_restores_original_bytes() {
+        let buffer = Buffer::new();
+        let mut patcher = Patcher::new();
+        unsafe {
+            patcher.patch(buffer.at(4), &[1, 2, 3]).unwrap();
+            assert_eq!(buffer.bytes(3, 5), [NOP, 1, 2, 3, NOP]);
+            assert!(patcher.is_patched(buffer.at(6), 1));
+            assert!(!patcher.is_patched(buffer.at(7), 8));
+
+            patcher.unpatch(buffer.at(4)).unwrap();
+            assert_eq!(buffer.bytes(0, 16), [NOP; 16]);
+            assert!(!patcher.is_patched(buffer.at(4), 3));
+            assert!(patcher.unpatch(buffer.at(4)).is_err());
+        }
+    }
+
+    #[test]
+    fn overlapping_patches_are_refused() {
+        let buffer = Buffer::new();
+        let mut patcher = Patcher::new();
+        unsafe {
+            patcher.patch(buffer.at(4), &[1; 4]).unwrap();
+            assert!(patcher.patch(buffer.at(6), &[2; 4]).is_err());
+            assert!(patcher.patch(buffer.at(2), &[2; 3]).is_err());
+            assert_eq!(
+                buffer.bytes(0, 12),
+                [NOP, NOP, NOP, NOP, 1, 1, 1, 1, NOP, NOP, NOP, NOP]
+            );
+
+            // Adjacent patches do not overlap
+            patcher.patch(buffer.at(8), &[3; 2]).unwrap();
+            patcher.patch(buffer.at(2), &[3; 2]).unwrap();
+        }
+    }
+
+    #[test]
+    fn stacked_patches_restore_in_application_order() {
+        let buffer = Buffer::new();
+        let mut patcher = Patcher::new();
+        unsafe {
+            patcher.patch(buffer.at(0), &[1; 8]).unwrap();
+            patcher.patch_stacked(buffer.at(4), &[2; 8]).unwrap();
+            assert_eq!(buffer.bytes(0, 12), [1, 1, 1, 1, 2, 2, 2, 2, 2, 2, 2, 2]);
+
+            // Removing the top patch reveals the one underneath it
+            patcher.unpatch(buffer.at(4)).unwrap();
+            assert_eq!(
+                buffer.bytes(0, 12),
+                [1, 1, 1, 1, 1, 1, 1, 1, NOP, NOP, NOP, NOP]
+            );
+            patcher.unpatch(buffer.at(0)).unwrap();
+            assert_eq!(buffer.bytes(0, 12), [NOP; 12]);
+        }
+    }
+
+    #[test]
+    fn stacked_patches_restore_out_of_order() {
+        let buffer = Buffer::new();
+        let mut patcher = Patcher::new();
+        unsafe {
+            patcher.patch(buffer.at(0), &[1; 8]).unwrap();
+            patcher.patch_stacked(buffer.at(4), &[2; 8]).unwrap();
+
+            // Removing the bottom patch leaves the top one in place, which now restores the
+            // original bytes when it is removed
+            patcher.unpatch(buffer.at(0)).unwrap();
+            assert_eq!(
+                buffer.bytes(0, 12),
+                [NOP, NOP, NOP, NOP, 2, 2, 2, 2, 2, 2, 2, 2]
+            );
+            patcher.unpatch(buffer.at(4)).unwrap();
+            assert_eq!(buffer.bytes(0, 12), [NOP; 12]);
+        }
+    }
+
+    #[test]
+    fn drop_restores_everything() {
+        let buffer = Buffer::new();
+        {
+            let mut patcher = Patcher::new();
+            unsafe {
+                patcher.patch(buffer.at(0), &[1; 8]).unwrap();
+                patcher.patch_stacked(buffer.at(2), &[2; 4]).unwrap();
+                patcher.patch_stacked(buffer.at(6), &[3; 4]).unwrap();
+                patcher.patch(buffer.at(16), &[4; 4]).unwrap();
+            }
+        }
+        assert_eq!(buffer.bytes(0, 32), [NOP; 32]);
+    }
+
+    #[test]
+    fn code_patches_must_end_on_instruction_boundaries() {
+        let buffer = Buffer::new();
+        let mut patcher = Patcher::new();
+        // `mov eax, ecx` followed by NOPs
+        buffer.write(0x100, &[0x89, 0xC8]);
+        unsafe {
+            assert!(patcher.patch_code(buffer.at(0x100), &[0xCC]).is_err());
+            assert!(!patcher.is_patched(buffer.at(0x100), 2));
+            patcher.patch_code(buffer.at(0x100), &[0xCC; 3]).unwrap();
+
+            // Unchecked patches can split instructions, as data has none
+            buffer.write(0x200, &[0x89, 0xC8]);
+            patcher.patch(buffer.at(0x200), &[0xCC]).unwrap();
+            assert_eq!(buffer.bytes(0x200, 2), [0xCC, 0xC8]);
+
+            buffer.write(0x300, &[0x89, 0xC8]);
+            assert_eq!(patcher.patch_padded(buffer.at(0x300), &[0xCC]).unwrap(), 2);
+            assert_eq!(buffer.bytes(0x300, 3), [0xCC, NOP, NOP]);
+            assert_eq!(patcher.nop_instructions(buffer.at(0x400), 3).unwrap(), 3);
+        }
+    }
+
+    #[test]
+    fn replace_rel32_branch_destinations() {
+        let buffer = Buffer::new();
+        let mut patcher = Patcher::new();
+        // A `call rel32` and a `jmp rel32` that end the page, to `0x800`
+        let call = BUFFER_SIZE - 10;
+        let jump = BUFFER_SIZE - 5;
+        buffer.write(call, &[0xE8]);
+        buffer.write(call + 1, &(0x800 - (call as i32 + 5)).to_le_bytes());
+        buffer.write(jump, &[0xE9]);
+        buffer.write(jump + 1, &(0x800 - (jump as i32 + 5)).to_le_bytes());
+        let original = buffer.bytes(call, 10);
+
+        unsafe {
+            let destination = buffer.at(0x400);
+            assert_eq!(
+                patcher
+                    .replace_call_destination(buffer.at(call), destination)
+                    .unwrap(),
+                buffer.at(0x800)
+            );
+            assert_eq!(
+                patcher
+                    .replace_jump_destination(buffer.at(jump), destination)
+                    .unwrap(),
+                buffer.at(0x800)
+            );
+            assert_eq!(buffer.bytes(call, 1), [0xE8]);
+            assert_eq!(
+                buffer.bytes(call + 1, 4),
+                (0x400 - (call as i32 + 5)).to_le_bytes()
+            );
+            assert_eq!(buffer.bytes(jump, 1), [0xE9]);
+            assert_eq!(
+                buffer.bytes(jump + 1, 4),
+                (0x400 - (jump as i32 + 5)).to_le_bytes()
+            );
+
+            // A call is not a jump
+            assert!(patcher
+                .replace_jump_destination(buffer.at(call), destination)
+                .is_err());
+
+            patcher.unpatch(buffer.at(call)).unwrap();
+            patcher.unpatch(buffer.at(jump)).unwrap();
+        }
+        assert_eq!(buffer.bytes(call, 10), original);
+    }
+
+    #[test]
+    #[cfg(target_pointer_width = "64")]
+    fn replace_indirect_call_destination() {
+        let buffer = Buffer::new();
+        let mut patcher = Patcher::new();
+        // A `call [rip+disp32]` through a pointer at `0x800`
+        buffer.write(0x800, &0x1234usize.to_le_bytes());
+        buffer.write(0x100, &[0xFF, 0x15]);
+        buffer.write(0x102, &(0x800 - 0x106i32).to_le_bytes());
+
+        unsafe {
+            assert_eq!(
+                patcher
+                    .replace_call_destination(buffer.at(0x100), 0x5678)
+                    .unwrap(),
+                0x1234
+            );
+            assert_eq!(buffer.bytes(0x100, 2), [0xFF, 0x15]);
+            let disp = i32::from_le_bytes(buffer.bytes(0x102, 4).try_into().unwrap());
+            let slot = buffer.at(0x106).wrapping_add_signed(disp as isize);
+            assert_eq!((slot as *const usize).read_unaligned(), 0x5678);
+            // The original pointer is left alone
+            assert_eq!(buffer.bytes(0x800, 8), 0x1234usize.to_le_bytes());
+
+            patcher.unpatch(buffer.at(0x100)).unwrap();
+        }
+        assert_eq!(buffer.bytes(0x102, 4), (0x800 - 0x106i32).to_le_bytes());
+    }
+}
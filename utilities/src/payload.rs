@@ -0,0 +1,166 @@
+use std::{ops::Range, sync::Mutex, thread::JoinHandle};
+
+use anyhow::Context;
+
+use crate::{memory, thread_relocation::relocate_threads, ThreadSuspender};
+
+type ShutdownTask = Box<dyn FnOnce() + Send>;
+
+struct Runtime {
+    init_thread: Option<JoinHandle<()>>,
+    // The addresses the payload is loaded at, once they are known.
+    image: Option<Range<usize>>,
+    shutdown_tasks: Vec<ShutdownTask>,
+    is_shut_down: bool,
+}
+
+static RUNTIME: Mutex<Runtime> = Mutex::new(Runtime {
+    init_thread: None,
+    image: None,
+    shutdown_tasks: vec![],
+    is_shut_down: false,
+});
+
+/// Starts the payload, running `main` on a new thread. If `main` fails, anything it set up is shut
+/// down, and the error is printed if the `debug-console` feature is enabled.
+///
+/// This is called by the entry point that `#[payload_main]` generates, which cannot do much
+/// itself as it runs while the loader is busy. `address` is any address within the payload.
+pub fn attach(address: usize, main: fn() -> anyhow::Result<()>) {
+    let init_thread = std::thread::spawn(move || {
+        match image_range(address) {
+            Ok(image) => RUNTIME.lock().unwrap().image = Some(image),
+            Err(_err) => {
+                #[cfg(feature = "debug-console")]
+                eprintln!("failed to find the payload's image: {_err:?}");
+            }
+        }
+
+        if let Err(_err) = main() {
+            #[cfg(feature = "debug-console")]
+            eprintln!("failed to start payload: {_err:?}");
+            run_shutdown_tasks();
+        }
+    });
+    RUNTIME.lock().unwrap().init_thread = Some(init_thread);
+}
+
+/// Shuts the payload down before it is unloaded: runs the shutdown tasks in the reverse of the
+/// order they were registered in, then waits for every other thread to leave the payload's code.
+/// Only the first call does anything.
+///
+/// [`detach`] does this when the payload is unloaded, but on Windows that happens inside
+/// `DllMain`, under the loader lock: any shutdown task or thread that needs the loader, such as
+/// one that loads a library or starts or ends a thread, will deadlock. Payloads that shut down
+/// anything more than hooks should export a function that calls this, and have the injector call
+/// it before ejecting them.
+pub fn shutdown() {
+    let image = {
+        let mut runtime = RUNTIME.lock().unwrap();
+        if std::mem::replace(&mut runtime.is_shut_down, true) {
+            return;
+        }
+        runtime.image.clone()
+    };
+
+    run_shutdown_tasks();
+
+    if let Some(image) = image {
+        let result = ThreadSuspender::for_block(|| relocate_threads(image.clone(), |_| None));
+        if let Err(_err) = result {
+            #[cfg(feature = "debug-console")]
+            eprintln!("failed to wait for threads to leave the payload: {_err:?}");
+        }
+    }
+}
+
+/// Shuts the payload down with [`shutdown`] as it is unloaded, unless that has already been
+/// done.
+///
+/// This is called by the entry point that `#[payload_main]` generates. Shutting down while
+/// `main` is still running is not supported, as the loader will not let it finish.
+pub fn detach() {
+    let _init_thread = RUNTIME.lock().unwrap().init_thread.take();
+    #[cfg(feature = "debug-console")]
+    if _init_thread.is_some_and(|thread| !thread.is_finished()) {
+        eprintln!("the payload is being unloaded before it finished starting");
+    }
+
+    shutdown();
+}
+
+/// The entry point of a DLL payload, which `#[payload_main]` forwards `DllMain` to.
+///
+/// Nothing is shut down when the process is exiting, as its other threads have already been
+/// terminated and its memory is about to go away.
+#[cfg(target_os = "windows")]
+pub fn dll_main(
+    module: usize,
+    reason: u32,
+    reserved: *mut std::ffi::c_void,
+    main: fn() -> anyhow::Result<()>,
+) -> bool {
+    const DLL_PROCESS_DETACH: u32 = 0;
+    const DLL_PROCESS_ATTACH: u32 = 1;
+
+    match reason {
+        DLL_PROCESS_ATTACH => attach(module, main),
+        DLL_PROCESS_DETACH if reserved.is_null() => detach(),
+        _ => {}
+    }
+    true
+}
+
+/// Registers `task` to run when the payload shuts down. Tasks run in the reverse of the order
+/// they were registered in, so that anything set up during start-up is torn down before what it
+/// depends on.
+///
+/// Singletons created with [`crate::singleton`] are destroyed this way.
+pub fn on_shutdown(task: impl FnOnce() + Send + 'static) {
+    RUNTIME.lock().unwrap().shutdown_tasks.push(Box::new(task));
+}
+
+/// Keeps `value` alive until the payload shuts down, then drops it in the same order as the
+/// shutdown tasks.
+pub fn keep<T: Send + 'static>(value: T) {
+    on_shutdown(move || drop(value));
+}
+
+/// Enables `libraries` with a new [`crate::Patcher`], and registers a shutdown task that
+/// disables them and then drops the patcher, restoring everything it patched.
+#[cfg(target_os = "windows")]
+pub fn enable_hooks(libraries: crate::hook_library::HookLibraries) -> anyhow::Result<()> {
+    let mut patcher = crate::Patcher::new();
+    let libraries = libraries.enable(&mut patcher)?;
+    on_shutdown(move || {
+        if let Err(_err) = libraries.set_enabled(&mut patcher, false) {
+            #[cfg(feature = "debug-console")]
+            eprintln!("failed to disable hooks: {_err:?}");
+        }
+        drop(libraries);
+        drop(patcher);
+    });
+    Ok(())
+}
+
+fn run_shutdown_tasks() {
+    // Tasks are taken one at a time, as they may register more.
+    loop {
+        let Some(task) = RUNTIME.lock().unwrap().shutdown_tasks.pop() else {
+            break;
+        };
+        task();
+    }
+}
+
+/// Finds the addresses covered by the module that contains `address`.
+fn image_range(address: usize) -> anyhow::Result<Range<usize>> {
+    let module = memory::query(address)?
+        .module
+        .with_context(|| format!("{address:#x} is not part of a module"))?;
+    memory::regions()?
+        .filter(|region| region.module.as_ref() == Some(&module))
+        .map(|region| region.range())
+        .reduce(|a, b| a.start.min(b.start)..a.end.max(b.end))
+        .with_context(|| format!("no regions belong to {}", module.display()))
+}
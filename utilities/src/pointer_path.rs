@@ -0,0 +1,500 @@
+use std::{fmt, marker::PhantomData, str::FromStr};
+
+use anyhow::Context;
+
+use crate::{memory, process::Process, util};
+
+/// Where a [`PointerPath`] starts.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum PointerBase {
+    /// The base address of the module with this filename.
+    Module(String),
+    /// An absolute address.
+    Absolute(usize),
+}
+
+/// A multi-level pointer path, such as `[[game.exe+0x1A2B3C]+0x18]+0x40`.
+///
+/// Each pair of brackets dereferences the pointer-sized value at the address inside them. Paths
+/// start from either a module's base address, named by its filename, or an absolute address.
+/// Numbers are decimal unless prefixed with `0x`, and offsets may be subtracted as well as added.
+/// A base is only taken as an address if all of it is a number, so `7zFM.exe` names a module.
+/// A `-` always subtracts, so module names containing one, or whitespace, brackets or `+`, must
+/// be quoted, as in `"my-mod.dll"+0x10`.
+/// A path can be parsed from a string, and is displayed in the same notation.
+/// Pointers are assumed to be the same size as in the current process.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PointerPath {
+    base: PointerBase,
+    // The first offset is added to the base; each one after it is added after dereferencing.
+    offsets: Vec<isize>,
+}
+
+impl PointerPath {
+    pub fn new(base: PointerBase, offsets: impl Into<Vec<isize>>) -> PointerPath {
+        let mut offsets = offsets.into();
+        if offsets.is_empty() {
+            offsets.push(0);
+        }
+        PointerPath { base, offsets }
+    }
+
+    pub fn base(&self) -> &PointerBase {
+        &self.base
+    }
+
+    /// The offset added at each level; the first is added to the base, and each one after
+    /// that to the pointer read from the previous level.
+    pub fn offsets(&self) -> &[isize] {
+        &self.offsets
+    }
+
+    /// The number of pointers dereferenced while resolving the path.
+    pub fn depth(&self) -> usize {
+        self.offsets.len() - 1
+    }
+
+    /// Follows the path in `process`, returning the address it ends at.
+    pub fn resolve<T>(&self, process: &Process) -> anyhow::Result<RemotePtr<T>> {
+        let address = self.walk(
+            |name| Ok(process.module(name)?.base()),
+            |address| unsafe { process.read_value::<usize>(address) },
+        )?;
+        Ok(RemotePtr::new(address))
+    }
+
+    /// Follows the path in the current process, returning the address it ends at.
+    pub fn resolve_local<T>(&self) -> anyhow::Result<*mut T> {
+        let address = self.walk(local_module_base, |address| {
+            let mut pointer = [0; std::mem::size_of::<usize>()];
+            memory::read(address, &mut pointer)?;
+            Ok(usize::from_ne_bytes(pointer))
+        })?;
+        Ok(unsafe { util::make_ptr_with_offset(address, 0) })
+    }
+
+    fn walk(
+        &self,
+        module_base: impl Fn(&str) -> anyhow::Result<usize>,
+        read_pointer: impl Fn(usize) -> anyhow::Result<usize>,
+    ) -> anyhow::Result<usize> {
+        let mut address = match &self.base {
+            PointerBase::Module(name) => {
+                module_base(name).with_context(|| format!("failed to find module {name}"))?
+            }
+            PointerBase::Absolute(address) => *address,
+        }
+        .wrapping_add_signed(self.offsets[0]);
+
+        for (level, offset) in self.offsets.iter().enumerate().skip(1) {
+            let pointer = read_pointer(address).with_context(|| {
+                format!(
+                    "failed to dereference [{}] (level {level}): {address:#x} is not readable",
+                    self.prefix(level)
+                )
+            })?;
+            if pointer == 0 {
+                anyhow::bail!(
+                    "failed to dereference [{}] (level {level}): pointer at {address:#x} is null",
+                    self.prefix(level)
+                );
+            }
+            address = pointer.wrapping_add_signed(*offset);
+        }
+        Ok(address)
+    }
+
+    /// The path to the pointer read at the `level`th dereference.
+    fn prefix(&self, level: usize) -> PointerPath {
+        PointerPath::new(self.base.clone(), &self.offsets[..level])
+    }
+}
+
+#[cfg(target_os = "windows")]
+fn local_module_base(name: &str) -> anyhow::Result<usize> {
+    crate::module::Module::get_all()
+        .find(|m| {
+            m.filename()
+                .is_some_and(|filename| filename.eq_ignore_ascii_case(name))
+        })
+        .map(|m| m.rel_to_abs_addr(0) as usize)
+        .with_context(|| format!("no module named {name} in the current process"))
+}
+
+#[cfg(target_os = "linux")]
+fn local_module_base(name: &str) -> anyhow::Result<usize> {
+    // A module's lowest mapping is its base, and the regions are in address order
+    memory::regions()?
+        .find(|region| {
+            region
+                .module
+                .as_ref()
+                .and_then(|path| path.file_name())
+                .is_some_and(|filename| filename.eq_ignore_ascii_case(name))
+        })
+        .map(|region| region.base)
+        .with_context(|| format!("no module named {name} in the current process"))
+}
+
+impl fmt::Display for PointerPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn offset(f: &mut fmt::Formatter<'_>, offset: isize) -> fmt::Result {
+            match offset {
+                0 => Ok(()),
+                offset if offset < 0 => write!(f, "-{:#X}", offset.unsigned_abs()),
+                offset => write!(f, "+{offset:#X}"),
+            }
+        }
+
+        for _ in 1..self.offsets.len() {
+            write!(f, "[")?;
+        }
+        match &self.base {
+            PointerBase::Module(name) if needs_quotes(name) => write!(f, "\"{name}\"")?,
+            PointerBase::Module(name) => write!(f, "{name}")?,
+            PointerBase::Absolute(address) => write!(f, "{address:#X}")?,
+        }
+        for (index, value) in self.offsets.iter().enumerate() {
+            if index > 0 {
+                write!(f, "]")?;
+            }
+            offset(f, *value)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for PointerPath {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let mut parser = Parser {
+            input: s,
+            position: 0,
+        };
+        let path = parser.path()?;
+        parser.skip_whitespace();
+        if parser.position != s.len() {
+            parser.fail("unexpected character")?;
+        }
+        Ok(path)
+    }
+}
+
+/// Splits the number at the start of `s` from the rest, returning its digits, radix and the
+/// rest of the input.
+fn split_number(s: &str) -> (&str, u32, &str) {
+    let (digits, radix) = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(rest) => (rest, 16),
+        None => (s, 10),
+    };
+    let len = digits
+        .find(|c: char| !c.is_digit(radix))
+        .unwrap_or(digits.len());
+    (&digits[..len], radix, &digits[len..])
+}
+
+/// Whether all of `s` is a number.
+fn is_number(s: &str) -> bool {
+    let (digits, _, rest) = split_number(s);
+    !digits.is_empty() && rest.is_empty()
+}
+
+/// Whether a module name has to be quoted to be parsed back as the same name.
+fn needs_quotes(name: &str) -> bool {
+    name.is_empty() || is_number(name) || name.contains(is_delimiter)
+}
+
+/// Whether `c` ends an unquoted base.
+fn is_delimiter(c: char) -> bool {
+    "[]+-\"".contains(c) || c.is_whitespace()
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    position: usize,
+}
+
+impl Parser<'_> {
+    // path := ('[' path ']' | base) offset*
+    fn path(&mut self) -> anyhow::Result<PointerPath> {
+        self.skip_whitespace();
+        let mut path = if self.eat('[') {
+            let mut inner = self.path()?;
+            self.skip_whitespace();
+            if !self.eat(']') {
+                self.fail("expected `]`")?;
+            }
+            inner.offsets.push(0);
+            inner
+        } else {
+            PointerPath::new(self.base()?, [0])
+        };
+
+        loop {
+            self.skip_whitespace();
+            let negative = if self.eat('+') {
+                false
+            } else if self.eat('-') {
+                true
+            } else {
+                break;
+            };
+            self.skip_whitespace();
+            let value = self.number()? as isize;
+            let offset = path.offsets.last_mut().unwrap();
+            *offset = if negative {
+                offset.wrapping_sub(value)
+            } else {
+                offset.wrapping_add(value)
+            };
+        }
+        Ok(path)
+    }
+
+    // base := '"' name '"' | number | name
+    fn base(&mut self) -> anyhow::Result<PointerBase> {
+        if self.eat('"') {
+            let start = self.position;
+            let Some(len) = self.input[start..].find('"') else {
+                return self.fail("expected a closing `\"`");
+            };
+            if len == 0 {
+                return self.fail("expected a module name");
+            }
+            self.position += len + 1;
+            return Ok(PointerBase::Module(
+                self.input[start..start + len].to_owned(),
+            ));
+        }
+
+        let start = self.position;
+        while self.peek().is_some_and(|c| !is_delimiter(c)) {
+            self.position += self.peek().unwrap().len_utf8();
+        }
+        let token = &self.input[start..self.position];
+        if token.is_empty() {
+            return self.fail("expected a module name or address");
+        }
+        if is_number(token) {
+            self.position = start;
+            return Ok(PointerBase::Absolute(self.number()?));
+        }
+        Ok(PointerBase::Module(token.to_owned()))
+    }
+
+    fn number(&mut self) -> anyhow::Result<usize> {
+        let start = self.position;
+        let (digits, radix, rest) = split_number(&self.input[start..]);
+        if digits.is_empty() {
+            return self.fail("expected a number");
+        }
+        self.position = self.input.len() - rest.len();
+        usize::from_str_radix(digits, radix)
+            .with_context(|| format!("number at position {start} is too large"))
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.input[self.position..].chars().next()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        let matched = self.peek() == Some(c);
+        if matched {
+            self.position += c.len_utf8();
+        }
+        matched
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.position += self.peek().unwrap().len_utf8();
+        }
+    }
+
+    fn fail<T>(&self, message: &str) -> anyhow::Result<T> {
+        anyhow::bail!(
+            "invalid pointer path `{}`: {message} at position {}",
+            self.input,
+            self.position
+        )
+    }
+}
+
+/// An address of a `T` in another process.
+pub struct RemotePtr<T> {
+    address: usize,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T> RemotePtr<T> {
+    pub fn new(address: usize) -> RemotePtr<T> {
+        RemotePtr {
+            address,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn address(&self) -> usize {
+        self.address
+    }
+
+    pub fn cast<U>(self) -> RemotePtr<U> {
+        RemotePtr::new(self.address)
+    }
+}
+
+impl<T: Copy> RemotePtr<T> {
+    /// Reads the value.
+    ///
+    /// # Safety
+    /// The bytes are taken as-is, so every bit pattern must be a valid `T`.
+    pub unsafe fn read(&self, process: &Process) -> anyhow::Result<T> {
+        process.read_value(self.address)
+    }
+
+    pub fn write(&self, process: &Process, value: &T) -> anyhow::Result<()> {
+        process.write_value(self.address, value)
+    }
+}
+
+impl<T> Clone for RemotePtr<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+impl<T> Copy for RemotePtr<T> {}
+
+impl<T> fmt::Debug for RemotePtr<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "RemotePtr({:#x})", self.address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> PointerPath {
+        s.parse().unwrap_or_else(|err| panic!("{err:#}"))
+    }
+
+    fn module(name: &str, offsets: impl Into<Vec<isize>>) -> PointerPath {
+        PointerPath::new(PointerBase::Module(name.to_owned()), offsets)
+    }
+
+    #[test]
+    fn parse_paths() {
+        assert_eq!(
+            parse("[[game.exe+0x1A2B3C]+0x18]+0x40"),
+            module("game.exe", [0x1A2B3C, 0x18, 0x40])
+        );
+        assert_eq!(
+            parse(" [ [ game.exe + 16 ] - 0X8 ] "),
+            module("game.exe", [16, -8, 0])
+        );
+        assert_eq!(parse("game.exe+1+2-4"), module("game.exe", [-1]));
+        assert_eq!(
+            parse("[0x1000]+8"),
+            PointerPath::new(PointerBase::Absolute(0x1000), [0, 8])
+        );
+        assert_eq!(
+            parse("4096"),
+            PointerPath::new(PointerBase::Absolute(4096), [0])
+        );
+    }
+
+    #[test]
+    fn bases_are_only_addresses_if_wholly_numeric() {
+        assert_eq!(parse("7zFM.exe+0x10"), module("7zFM.exe", [0x10]));
+        assert_eq!(parse("[0x10.dll]"), module("0x10.dll", [0, 0]));
+        assert_eq!(parse("123abc"), module("123abc", [0]));
+    }
+
+    #[test]
+    fn dashes_subtract_unless_quoted() {
+        assert_eq!(parse("game-0x10"), module("game", [-0x10]));
+        assert_eq!(parse("\"my-mod.dll\"+8"), module("my-mod.dll", [8]));
+        assert_eq!(parse("[\"1234\"]-1"), module("1234", [0, -1]));
+        assert!("my-mod.dll+8".parse::<PointerPath>().is_err());
+    }
+
+    #[test]
+    fn invalid_paths_are_refused() {
+        for path in [
+            "",
+            "[game.exe+1",
+            "game.exe+1]",
+            "game.exe+",
+            "game.exe+0x",
+            "game.exe 1",
+            "[]",
+            "\"\"+1",
+            "\"game.exe+1",
+            "0x10000000000000000",
+        ] {
+            assert!(path.parse::<PointerPath>().is_err(), "{path:?}");
+        }
+    }
+
+    #[test]
+    fn display_round_trips() {
+        for (path, displayed) in [
+            (
+                "[[game.exe+0x1A2B3C]+0x18]+0x40",
+                "[[game.exe+0x1A2B3C]+0x18]+0x40",
+            ),
+            ("[game.exe+16]-8", "[game.exe+0x10]-0x8"),
+            ("[[0x1000]]", "[[0x1000]]"),
+            ("7zFM.exe+0x10", "7zFM.exe+0x10"),
+            ("\"my-mod.dll\"", "\"my-mod.dll\""),
+            ("\"My Game.exe\"+1", "\"My Game.exe\"+0x1"),
+            ("\"1234\"", "\"1234\""),
+        ] {
+            let parsed = parse(path);
+            assert_eq!(parsed.to_string(), displayed);
+            assert_eq!(parse(displayed), parsed);
+        }
+    }
+
+    #[test]
+    fn resolve_local_follows_pointers() {
+        let target = Box::new(0u64);
+        let target_address = &*target as *const u64 as usize;
+        let pointers = Box::new([0, target_address - 8, 0usize]);
+        let pointers_address = pointers.as_ptr() as usize;
+        let size = std::mem::size_of::<usize>() as isize;
+
+        let path = PointerPath::new(PointerBase::Absolute(pointers_address), [size, 8]);
+        assert_eq!(path.depth(), 1);
+        assert_eq!(
+            path.resolve_local::<u64>().unwrap() as usize,
+            target_address
+        );
+        assert_eq!(
+            path.resolve::<u64>(&Process::current().unwrap())
+                .unwrap()
+                .address(),
+            target_address
+        );
+
+        let null = PointerPath::new(PointerBase::Absolute(pointers_address), [0, 0]);
+        let error = null.resolve_local::<u64>().unwrap_err();
+        assert!(error.to_string().contains("is null"), "{error}");
+        let unreadable = PointerPath::new(PointerBase::Absolute(0), [0, 0]);
+        assert!(unreadable.resolve_local::<u64>().is_err());
+    }
+
+    #[test]
+    fn resolve_local_finds_modules() {
+        let exe = std::env::current_exe().unwrap();
+        let filename = exe.file_name().unwrap().to_str().unwrap();
+        let base = Process::current().unwrap().module(filename).unwrap().base();
+
+        let path = module(&filename.to_uppercase(), [0x10]);
+        assert_eq!(path.resolve_local::<u8>().unwrap() as usize, base + 0x10);
+        assert!(module("no such module.dll", [0])
+            .resolve_local::<u8>()
+            .is_err());
+    }
+}
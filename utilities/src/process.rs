@@ -0,0 +1,280 @@
+use std::{collections, mem, path::Path};
+
+use anyhow::Context;
+
+#[cfg(target_os = "linux")]
+use crate::linux::process as sys;
+use crate::scan::{self, CacheKey};
+#[cfg(target_os = "windows")]
+use crate::windows::process as sys;
+
+pub use sys::Process;
+
+/// Where a module is loaded in a process, as reported by the platform.
+pub(crate) struct ModuleInfo {
+    pub path: String,
+    pub base: usize,
+    pub size: usize,
+}
+
+impl Process {
+    /// Reads a `T` from `address`.
+    ///
+    /// # Safety
+    /// The bytes are taken as-is, so every bit pattern must be a valid `T`.
+    pub unsafe fn read_value<T: Copy>(&self, address: usize) -> anyhow::Result<T> {
+        let mut value = mem::MaybeUninit::<T>::uninit();
+        let bytes =
+            std::slice::from_raw_parts_mut(value.as_mut_ptr() as *mut u8, mem::size_of::<T>());
+        self.read(address, bytes)?;
+        Ok(value.assume_init())
+    }
+
+    /// Writes `value` to `address`.
+    pub fn write_value<T: Copy>(&self, address: usize, value: &T) -> anyhow::Result<()> {
+        let bytes = unsafe {
+            std::slice::from_raw_parts(value as *const T as *const u8, mem::size_of::<T>())
+        };
+        self.write(address, bytes)
+    }
+
+    /// Reads `len` bytes from `address`.
+    pub fn read_bytes(&self, address: usize, len: usize) -> anyhow::Result<Vec<u8>> {
+        let mut bytes = vec![0; len];
+        self.read(address, &mut bytes)?;
+        Ok(bytes)
+    }
+
+    /// Reads `size` bytes of a module's image from `base`. Pages that cannot be read, such as
+    /// the gaps between the segments of an ELF image, are left zeroed.
+    fn read_image(&self, base: usize, size: usize) -> anyhow::Result<Vec<u8>> {
+        const PAGE_SIZE: usize = 0x1000;
+
+        let mut image = vec![0; size];
+        if self.read(base, &mut image).is_ok() {
+            return Ok(image);
+        }
+        let mut any_read = false;
+        for (index, page) in image.chunks_mut(PAGE_SIZE).enumerate() {
+            any_read |= self.read(base + index * PAGE_SIZE, page).is_ok();
+        }
+        if !any_read {
+            anyhow::bail!("failed to read module image at {base:#x}");
+        }
+        Ok(image)
+    }
+
+    /// Returns the modules loaded in the process.
+    pub fn modules(&self) -> anyhow::Result<Vec<ProcessModule>> {
+        Ok(self
+            .module_infos()?
+            .into_iter()
+            .map(|info| ProcessModule {
+                process: self.clone(),
+                path: info.path,
+                base: info.base,
+                size: info.size,
+                image: vec![],
+                cache: collections::HashMap::new(),
+            })
+            .collect())
+    }
+
+    /// Returns the module with the given filename, ignoring case.
+    pub fn module(&self, filename: &str) -> anyhow::Result<ProcessModule> {
+        self.modules()?
+            .into_iter()
+            .find(|m| {
+                m.filename()
+                    .is_some_and(|name| name.eq_ignore_ascii_case(filename))
+            })
+            .with_context(|| format!("no module named {filename} in process {}", self.pid()))
+    }
+}
+
+/// A module loaded in a [`Process`], which may be another process.
+///
+/// Scanning works on a copy of the module's image, which is read from the process the first
+/// time it is needed; use [`ProcessModule::refresh_image`] to pick up any changes since.
+/// Addresses are in the process's address space.
+#[derive(Clone)]
+pub struct ProcessModule {
+    process: Process,
+    path: String,
+    base: usize,
+    size: usize,
+    image: Vec<u8>,
+    cache: collections::HashMap<CacheKey, usize>,
+}
+
+impl ProcessModule {
+    pub fn process(&self) -> &Process {
+        &self.process
+    }
+
+    pub fn base(&self) -> usize {
+        self.base
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn path(&self) -> &Path {
+        Path::new(&self.path)
+    }
+
+    pub fn filename(&self) -> Option<String> {
+        self.path()
+            .file_name()
+            .and_then(|s| s.to_str())
+            .map(|s| s.to_string())
+    }
+
+    /// Returns the module's image, reading it from the process if it has not been read yet.
+    pub fn image(&mut self) -> anyhow::Result<&[u8]> {
+        if self.image.is_empty() {
+            self.refresh_image()?;
+        }
+        Ok(&self.image)
+    }
+
+    /// Reads the module's image from the process again, and forgets any cached scan results.
+    pub fn refresh_image(&mut self) -> anyhow::Result<()> {
+        self.image = self.process.read_image(self.base, self.size)?;
+        self.cache.clear();
+        Ok(())
+    }
+
+    pub fn scan(&mut self, pattern: &str) -> anyhow::Result<usize> {
+        let key = CacheKey::Regular(pattern.to_owned());
+        let offset = match self.cache.get(&key) {
+            Some(offset) => *offset,
+            None => scan::find_first(self.image()?, pattern)?,
+        };
+        self.cache.insert(key, offset);
+
+        Ok(self.rel_to_abs_addr(offset))
+    }
+
+    /// Scans for `pattern`, then follows the `rel32` at `addr_offset` bytes into the match,
+    /// returning the address it refers to.
+    pub fn scan_for_relative_callsite(
+        &mut self,
+        pattern: &str,
+        addr_offset: usize,
+    ) -> anyhow::Result<usize> {
+        let key = CacheKey::RelativeCallsite(pattern.to_owned());
+        let offset = match self.cache.get(&key) {
+            Some(offset) => *offset,
+            None => {
+                let image = self.image()?;
+                let rel_offset = scan::find_first(image, pattern)? + addr_offset;
+                let rel = image
+                    .get(rel_offset..rel_offset + 4)
+                    .context("relative callsite is outside of the module")?;
+                let rel = i32::from_ne_bytes(rel.try_into()?) as isize + 4;
+                rel_offset
+                    .checked_add_signed(rel)
+                    .context("relative callsite target is outside of the module")?
+            }
+        };
+        self.cache.insert(key, offset);
+
+        Ok(self.rel_to_abs_addr(offset))
+    }
+
+    pub fn scan_after_ptr(&mut self, base: usize, pattern: &str) -> anyhow::Result<usize> {
+        let base_offset = usize::try_from(self.abs_to_rel_addr(base))
+            .ok()
+            .filter(|offset| *offset <= self.size)
+            .with_context(|| format!("{base:#x} is outside of the module"))?;
+
+        let key = CacheKey::AfterPtr(pattern.to_owned(), base_offset);
+        let offset = match self.cache.get(&key) {
+            Some(offset) => *offset,
+            None => base_offset + scan::find_first(&self.image()?[base_offset..], pattern)?,
+        };
+        self.cache.insert(key, offset);
+
+        Ok(self.rel_to_abs_addr(offset))
+    }
+
+    pub fn abs_to_rel_addr(&self, address: usize) -> isize {
+        address.wrapping_sub(self.base) as isize
+    }
+
+    pub fn rel_to_abs_addr(&self, offset: usize) -> usize {
+        self.base.wrapping_add(offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static MARKER: [u8; 8] = [0x3C, 0x9A, 0x71, 0xE5, 0x0D, 0xB8, 0x46, 0x2F];
+
+    #[test]
+    fn read_and_write_own_memory() {
+        let process = Process::current().unwrap();
+        assert_eq!(process.pid(), std::process::id());
+
+        let mut value = Box::new(0x1122_3344_5566_7788u64);
+        let address = &mut *value as *mut u64 as usize;
+        assert_eq!(
+            unsafe { process.read_value::<u64>(address) }.unwrap(),
+            *value
+        );
+        assert_eq!(process.read_bytes(address, 8).unwrap(), value.to_ne_bytes());
+
+        process.write_value(address, &0xAABBu64).unwrap();
+        assert_eq!(unsafe { std::ptr::read_volatile(&*value) }, 0xAABB);
+        process
+            .write(address, &0xCCDDu64.to_ne_bytes()[..2])
+            .unwrap();
+        assert_eq!(
+            unsafe { process.read_value::<u64>(address) }.unwrap(),
+            0xCCDD
+        );
+    }
+
+    #[test]
+    fn unmapped_memory_is_an_error() {
+        let process = Process::current().unwrap();
+        let mut buffer = [0; 4];
+        assert!(process.read(0, &mut buffer).is_err());
+        assert!(unsafe { process.read_value::<u32>(usize::MAX - 8) }.is_err());
+        assert!(process.write(0, &buffer).is_err());
+    }
+
+    #[test]
+    fn scan_own_module() {
+        let process = Process::current().unwrap();
+        let exe = std::env::current_exe().unwrap();
+        let filename = exe.file_name().unwrap().to_str().unwrap();
+
+        let mut module = process.module(filename).unwrap();
+        assert_eq!(module.filename().as_deref(), Some(filename));
+        let function = scan_own_module as fn() as usize;
+        assert!((module.base()..module.base() + module.size()).contains(&function));
+        assert_eq!(
+            module.abs_to_rel_addr(function) as usize + module.base(),
+            function
+        );
+
+        let pattern = MARKER.map(|b| format!("{b:02X}")).join(" ");
+        let found = module.scan(&pattern).unwrap();
+        assert_eq!(
+            unsafe { process.read_value::<[u8; 8]>(found) }.unwrap(),
+            MARKER
+        );
+        // Cached results are returned without scanning again
+        assert_eq!(module.scan(&pattern).unwrap(), found);
+        assert!(module
+            .scan("3C 9A 71 E5 0D B8 46 2F 3C 9A 71 E5 0D B8 46 2F")
+            .is_err());
+
+        assert!(process.module("no such module.dll").is_err());
+    }
+}
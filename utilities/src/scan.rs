@@ -0,0 +1,161 @@
+use std::io;
+
+use anyhow::anyhow;
+
+use crate::memory::{self, Region};
+
+/// What a scan result was cached under.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum CacheKey {
+    Regular(String),
+    RelativeCallsite(String),
+    AfterPtr(String, usize),
+}
+
+/// Returns the offset of the first match for `pattern` (in `DE ? BE EF` form) in `bytes`.
+pub(crate) fn find_first(bytes: &[u8], pattern: &str) -> anyhow::Result<usize> {
+    patternscan::scan_first_match(io::Cursor::new(bytes), pattern)?
+        .ok_or_else(|| anyhow!("failed to scan"))
+}
+
+/// A match found by [`scan_regions`].
+#[derive(Debug, Clone)]
+pub struct RegionMatch {
+    pub address: usize,
+    /// The region the match was found in.
+    pub region: Region,
+}
+
+/// How much of a region is copied out to be scanned at a time.
+const CHUNK_SIZE: usize = 0x100_0000;
+
+/// Scans every readable region of the current process that `filter` accepts for `pattern`
+/// (in `DE ? BE EF` form), returning all of the matches in address order.
+///
+/// Regions are copied out with [`memory::read`] before being scanned. It goes through the
+/// kernel, so regions which are freed in the meantime are skipped rather than faulting; the
+/// copy itself is never reported as a match.
+/// To search the heaps, for example, accept regions whose `kind` is
+/// [`memory::RegionKind::Private`] and that are writable.
+pub fn scan_regions(
+    pattern: &str,
+    mut filter: impl FnMut(&Region) -> bool,
+) -> anyhow::Result<Vec<RegionMatch>> {
+    let pattern_len = pattern.split_whitespace().count();
+    if pattern_len == 0 {
+        anyhow::bail!("pattern is empty");
+    }
+
+    let regions: Vec<_> = memory::regions()?
+        .filter(|r| r.is_readable() && filter(r))
+        .collect();
+
+    let mut buffer = vec![0u8; CHUNK_SIZE];
+    let buffer_range = buffer.as_ptr_range();
+    let buffer_range = buffer_range.start as usize..buffer_range.end as usize;
+
+    let mut matches = vec![];
+    for region in regions {
+        let offsets = match scan_region_into(&region, pattern, pattern_len, &mut buffer) {
+            Ok(offsets) => offsets,
+            // The region has gone away or changed protection since it was listed.
+            Err(_) => continue,
+        };
+        matches.extend(
+            offsets
+                .into_iter()
+                .map(|offset| region.base + offset)
+                .filter(|address| !buffer_range.contains(address))
+                .map(|address| RegionMatch {
+                    address,
+                    region: region.clone(),
+                }),
+        );
+    }
+    Ok(matches)
+}
+
+/// Scans a single region of the current process for `pattern`, returning the addresses of all
+/// matches. Fails if the region cannot be read.
+pub fn scan_region(region: &Region, pattern: &str) -> anyhow::Result<Vec<usize>> {
+    let pattern_len = pattern.split_whitespace().count();
+    let mut buffer = vec![0u8; CHUNK_SIZE.min(region.size)];
+    Ok(scan_region_into(region, pattern, pattern_len, &mut buffer)?
+        .into_iter()
+        .map(|offset| region.base + offset)
+        .collect())
+}
+
+/// Scans `region` a chunk at a time through `buffer`, returning the offsets of the matches.
+fn scan_region_into(
+    region: &Region,
+    pattern: &str,
+    pattern_len: usize,
+    buffer: &mut [u8],
+) -> anyhow::Result<Vec<usize>> {
+    let mut offsets = vec![];
+    let mut offset = 0;
+    while offset < region.size {
+        let len = buffer.len().min(region.size - offset);
+        let chunk = &mut buffer[..len];
+        memory::read(region.base + offset, chunk)?;
+
+        // Chunks overlap by the length of the pattern, so only matches starting before the
+        // overlap belong to this chunk, unless it is the last.
+        let is_last = offset + len == region.size;
+        let limit = if is_last {
+            len
+        } else {
+            len - (pattern_len - 1).min(len)
+        };
+        offsets.extend(
+            patternscan::scan(io::Cursor::new(&*chunk), pattern)?
+                .into_iter()
+                .filter(|m| *m < limit)
+                .map(|m| offset + m),
+        );
+        if is_last {
+            break;
+        }
+        offset += limit.max(1);
+    }
+    Ok(offsets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::RegionKind;
+
+    const PATTERN: &str = "5A 17 ? C3 9E 42 D1 08";
+
+    #[test]
+    fn scan_heap_regions() {
+        // Built at runtime, so that the only copies are on the heap
+        let marker: Vec<u8> = [0x5A, 0x17, 0x00, 0xC3, 0x9E, 0x42, 0xD1, 0x08]
+            .iter()
+            .map(|b| std::hint::black_box(*b))
+            .collect();
+        let mut second = marker.clone();
+        second[2] = 0xFF;
+        let addresses = [marker.as_ptr() as usize, second.as_ptr() as usize];
+
+        let matches = scan_regions(PATTERN, |region| {
+            region.kind == Some(RegionKind::Private) && region.is_writable()
+        })
+        .unwrap();
+        for address in addresses {
+            let found = matches.iter().find(|m| m.address == address).unwrap();
+            assert!(found.region.contains(address));
+            assert!(scan_region(&found.region, PATTERN)
+                .unwrap()
+                .contains(&address));
+        }
+        assert!(matches
+            .windows(2)
+            .all(|pair| pair[0].address < pair[1].address));
+
+        assert!(scan_regions("", |_| true).is_err());
+        assert!(scan_regions(PATTERN, |_| false).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,48 @@
+use std::{ops::Range, time::Duration};
+
+use crate::ThreadSuspender;
+
+/// How many times to let the suspended threads run before giving up on them leaving a range.
+const ATTEMPTS: usize = 100;
+const RETRY_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Makes sure that no suspended thread is executing within `range`, which is about to be
+/// rewritten.
+///
+/// Each thread whose instruction pointer lies within the range is moved to the address returned
+/// by `relocate`; if it returns `None`, the threads are resumed briefly to give the thread a
+/// chance to leave the range by itself, and the check is repeated. Fails if a thread is still
+/// within the range after a number of attempts.
+///
+/// Does nothing if no threads are suspended.
+pub(crate) fn relocate_threads(
+    range: Range<usize>,
+    relocate: impl Fn(usize) -> Option<usize>,
+) -> anyhow::Result<()> {
+    for _ in 0..ATTEMPTS {
+        let stuck = ThreadSuspender::with_suspended(|threads| {
+            let mut stuck = false;
+            for thread in threads {
+                let ip = thread.instruction_pointer();
+                if !range.contains(&ip) {
+                    continue;
+                }
+                match relocate(ip) {
+                    Some(new_ip) => thread.set_instruction_pointer(new_ip)?,
+                    None => stuck = true,
+                }
+            }
+            anyhow::Ok(stuck)
+        });
+        match stuck {
+            None | Some(Ok(false)) => return Ok(()),
+            Some(Err(err)) => return Err(err),
+            Some(Ok(true)) => ThreadSuspender::let_threads_run(RETRY_INTERVAL),
+        }
+    }
+    anyhow::bail!(
+        "a thread is still executing {:#x}..{:#x}",
+        range.start,
+        range.end
+    )
+}
@@ -10,6 +10,33 @@ pub unsafe fn make_ptr_with_offset<U>(ptr: usize, offset: isize) -> *mut U {
     (ptr as *mut u8).offset(offset) as *mut U
 }
 
+/// Reads a `T` from `address`, returning an error rather than faulting if the memory is not
+/// committed and readable.
+///
+/// # Safety
+/// The bytes are taken as-is, so every bit pattern must be a valid `T`.
+#[cfg(any(target_os = "windows", target_os = "linux"))]
+pub unsafe fn read<T: Copy>(address: usize) -> anyhow::Result<T> {
+    let mut value = std::mem::MaybeUninit::<T>::uninit();
+    let bytes =
+        std::slice::from_raw_parts_mut(value.as_mut_ptr() as *mut u8, std::mem::size_of::<T>());
+    crate::memory::read(address, bytes)?;
+    Ok(value.assume_init())
+}
+
+/// Writes `value` to `address`, returning an error rather than faulting if the memory is not
+/// committed and writable. Unlike [`crate::memory::safe_write`], the protection is left alone.
+///
+/// # Safety
+/// `T` must have no padding, as its bytes are copied as-is, and `address` must be a place
+/// where any bit pattern of a `T` can be stored without breaking whatever else uses it.
+#[cfg(any(target_os = "windows", target_os = "linux"))]
+pub unsafe fn write<T: Copy>(address: usize, value: T) -> anyhow::Result<()> {
+    let bytes =
+        std::slice::from_raw_parts(&value as *const T as *const u8, std::mem::size_of::<T>());
+    crate::memory::write(address, bytes)
+}
+
 // this should probably be a derive macro
 #[macro_export]
 macro_rules! singleton {
@@ -17,10 +44,14 @@ macro_rules! singleton {
         static mut INSTANCE: Option<$class_name> = None;
 
         impl $class_name {
+            /// Creates the instance, which is destroyed when the payload shuts down if it has
+            /// not been already.
             pub fn create($($arg_name : $arg_type),*) -> anyhow::Result<()> {
                 unsafe {
                     INSTANCE = Some(<$class_name>::new( $($arg_name),* )?);
                 }
+                #[cfg(any(target_os = "windows", target_os = "linux"))]
+                $crate::payload::on_shutdown(<$class_name>::destroy);
                 Ok(())
             }
 
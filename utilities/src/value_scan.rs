@@ -0,0 +1,435 @@
+use std::cmp::Ordering;
+
+use crate::{memory::Region, process::Process};
+
+/// A value to search for with a [`ValueScanner`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScanValue {
+    I32(i32),
+    F32(f32),
+    /// A UTF-8 string, without a terminator.
+    String(String),
+    /// A UTF-16 string, without a terminator.
+    WideString(String),
+    Bytes(Vec<u8>),
+}
+
+impl ScanValue {
+    fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            ScanValue::I32(value) => value.to_le_bytes().to_vec(),
+            ScanValue::F32(value) => value.to_le_bytes().to_vec(),
+            ScanValue::String(value) => value.as_bytes().to_vec(),
+            ScanValue::WideString(value) => {
+                value.encode_utf16().flat_map(u16::to_le_bytes).collect()
+            }
+            ScanValue::Bytes(value) => value.clone(),
+        }
+    }
+
+    /// Reinterprets `bytes` as the same kind of value as `self`.
+    fn with_bytes(&self, bytes: &[u8]) -> ScanValue {
+        match self {
+            ScanValue::I32(_) => ScanValue::I32(i32::from_le_bytes(bytes.try_into().unwrap())),
+            ScanValue::F32(_) => ScanValue::F32(f32::from_le_bytes(bytes.try_into().unwrap())),
+            ScanValue::String(_) => ScanValue::String(String::from_utf8_lossy(bytes).into_owned()),
+            ScanValue::WideString(_) => {
+                let units: Vec<u16> = bytes
+                    .chunks_exact(2)
+                    .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                    .collect();
+                ScanValue::WideString(String::from_utf16_lossy(&units))
+            }
+            ScanValue::Bytes(_) => ScanValue::Bytes(bytes.to_vec()),
+        }
+    }
+
+    fn is_same_kind(&self, other: &ScanValue) -> bool {
+        std::mem::discriminant(self) == std::mem::discriminant(other)
+    }
+
+    /// The default alignment of candidates: numbers are assumed to be naturally aligned.
+    fn alignment(&self) -> usize {
+        match self {
+            ScanValue::I32(_) | ScanValue::F32(_) => 4,
+            ScanValue::WideString(_) => 2,
+            ScanValue::String(_) | ScanValue::Bytes(_) => 1,
+        }
+    }
+
+    fn is_number(&self) -> bool {
+        matches!(self, ScanValue::I32(_) | ScanValue::F32(_))
+    }
+
+    /// Compares two encodings of this kind of value: numerically for numbers, and otherwise
+    /// only for equality.
+    fn compare(&self, a: &[u8], b: &[u8]) -> Option<Ordering> {
+        match self {
+            ScanValue::I32(_) => {
+                let decode = |bytes: &[u8]| i32::from_le_bytes(bytes.try_into().unwrap());
+                Some(decode(a).cmp(&decode(b)))
+            }
+            ScanValue::F32(_) => {
+                let decode = |bytes: &[u8]| f32::from_le_bytes(bytes.try_into().unwrap());
+                decode(a).partial_cmp(&decode(b))
+            }
+            _ => (a == b).then_some(Ordering::Equal),
+        }
+    }
+}
+
+/// How a next scan narrows down the candidates, by comparing each one's current value with
+/// the value it had at the previous scan.
+#[derive(Debug, Clone, PartialEq)]
+pub enum NextScan {
+    Changed,
+    Unchanged,
+    /// Only valid for numbers.
+    Increased,
+    /// Only valid for numbers.
+    Decreased,
+    /// The value is now equal to this; it must be the same kind of value as the first scan.
+    Equal(ScanValue),
+}
+
+/// A candidate found by a [`ValueScanner`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScanResult {
+    pub address: usize,
+    /// The value as of the most recent scan.
+    pub value: ScanValue,
+}
+
+// The candidates within one region. Offsets are relative to the region's base so that they fit
+// in 32 bits; values are packed back to back, and left empty while they are all equal to the
+// value searched for by the first scan.
+struct RegionCandidates {
+    base: usize,
+    offsets: Vec<u32>,
+    values: Vec<u8>,
+}
+
+/// How much memory is read at a time.
+const CHUNK_SIZE: usize = 0x100_0000;
+/// The largest region whose offsets fit in a `u32`.
+const MAX_REGION_SIZE: usize = u32::MAX as usize;
+
+/// Finds the addresses of a value in a process by repeatedly narrowing down the candidates, in
+/// the style of Cheat Engine.
+///
+/// A [`ValueScanner::first_scan`] searches every writable region of the process for a value;
+/// each [`ValueScanner::next_scan`] then keeps only the candidates whose value has changed in
+/// the given way since the previous scan. Candidates are stored as 32-bit offsets into their
+/// region, along with their last value, so millions of them fit comfortably in memory.
+///
+/// Use [`Process::current`] to scan the current process.
+pub struct ValueScanner {
+    process: Process,
+    alignment: Option<usize>,
+    value: Option<ScanValue>,
+    value_bytes: Vec<u8>,
+    candidates: Vec<RegionCandidates>,
+}
+
+impl ValueScanner {
+    pub fn new(process: Process) -> ValueScanner {
+        ValueScanner {
+            process,
+            alignment: None,
+            value: None,
+            value_bytes: vec![],
+            candidates: vec![],
+        }
+    }
+
+    /// Only consider addresses that are a multiple of `alignment`. By default, numbers are
+    /// assumed to be naturally aligned, wide strings 2-byte aligned, and anything else
+    /// unaligned.
+    pub fn with_alignment(mut self, alignment: usize) -> Self {
+        self.alignment = Some(alignment.max(1));
+        self
+    }
+
+    pub fn process(&self) -> &Process {
+        &self.process
+    }
+
+    /// Searches every writable region of the process for `value`, replacing any previous
+    /// results. Returns the number of candidates found.
+    pub fn first_scan(&mut self, value: ScanValue) -> anyhow::Result<usize> {
+        let bytes = value.to_bytes();
+        if bytes.is_empty() {
+            anyhow::bail!("cannot scan for an empty value");
+        }
+        if bytes.len() > CHUNK_SIZE {
+            anyhow::bail!("cannot scan for a value larger than {CHUNK_SIZE:#x} bytes");
+        }
+        let alignment = self.alignment.unwrap_or_else(|| value.alignment());
+
+        // Candidates are stored as 32-bit offsets, so split up any larger regions.
+        let regions: Vec<Region> = self
+            .process
+            .regions()?
+            .into_iter()
+            .filter(|r| r.is_writable())
+            .flat_map(|r| {
+                (0..r.size)
+                    .step_by(MAX_REGION_SIZE)
+                    .map(move |offset| Region {
+                        base: r.base + offset,
+                        size: MAX_REGION_SIZE.min(r.size - offset),
+                        ..r.clone()
+                    })
+            })
+            .collect();
+
+        let mut buffer = vec![0u8; CHUNK_SIZE];
+        // When scanning ourselves, the buffer will contain whatever it last read.
+        let buffer_range = buffer.as_ptr_range();
+        let buffer_range = buffer_range.start as usize..buffer_range.end as usize;
+        let is_current = self.process.pid() == std::process::id();
+
+        self.candidates.clear();
+        for region in regions {
+            let mut candidates = RegionCandidates {
+                base: region.base,
+                offsets: vec![],
+                values: vec![],
+            };
+            let mut offset = 0;
+            while offset < region.size {
+                let len = CHUNK_SIZE.min(region.size - offset);
+                let chunk = &mut buffer[..len];
+                if self.process.read(region.base + offset, chunk).is_err() {
+                    // The region has gone away or changed since it was listed.
+                    break;
+                }
+                // Chunks overlap by the length of the value, so that values straddling a
+                // boundary are found; matches in the overlap are left to the next chunk.
+                let is_last = offset + len == region.size;
+                let limit = if is_last {
+                    len
+                } else {
+                    len - (bytes.len() - 1).min(len)
+                };
+
+                let first = (region.base + offset).next_multiple_of(alignment) - region.base;
+                let mut position = first - offset;
+                while position < limit && position + bytes.len() <= len {
+                    if chunk[position..position + bytes.len()] == bytes[..] {
+                        let address = region.base + offset + position;
+                        if !(is_current && buffer_range.contains(&address)) {
+                            candidates.offsets.push((offset + position) as u32);
+                        }
+                    }
+                    position += alignment;
+                }
+
+                if is_last || limit == 0 {
+                    break;
+                }
+                offset += limit;
+            }
+            if !candidates.offsets.is_empty() {
+                candidates.offsets.shrink_to_fit();
+                self.candidates.push(candidates);
+            }
+        }
+
+        self.value_bytes = bytes;
+        self.value = Some(value);
+        Ok(self.len())
+    }
+
+    /// Keeps only the candidates whose value has changed as described by `scan` since the
+    /// previous scan. Candidates that can no longer be read are dropped. Returns the number of
+    /// candidates left.
+    pub fn next_scan(&mut self, scan: NextScan) -> anyhow::Result<usize> {
+        let Some(value) = &self.value else {
+            anyhow::bail!("a first scan must be run before a next scan");
+        };
+        let size = self.value_bytes.len();
+        let expected = match &scan {
+            NextScan::Equal(expected) if !expected.is_same_kind(value) => {
+                anyhow::bail!("cannot compare {value:?} with {expected:?}")
+            }
+            NextScan::Equal(expected) => Some(expected.to_bytes()),
+            NextScan::Increased | NextScan::Decreased if !value.is_number() => {
+                anyhow::bail!("only numbers can be compared with increased or decreased")
+            }
+            _ => None,
+        };
+
+        let mut buffer = vec![];
+        for candidates in &mut self.candidates {
+            let mut offsets = Vec::with_capacity(candidates.offsets.len());
+            let mut values = Vec::with_capacity(candidates.offsets.len() * size);
+
+            // Read spans of nearby candidates at once, rather than each one separately.
+            let mut index = 0;
+            while index < candidates.offsets.len() {
+                let start = candidates.offsets[index] as usize;
+                let mut end_index = index;
+                while end_index < candidates.offsets.len()
+                    && candidates.offsets[end_index] as usize + size - start <= CHUNK_SIZE
+                {
+                    end_index += 1;
+                }
+                let end = candidates.offsets[end_index - 1] as usize + size;
+                buffer.resize(end - start, 0);
+                let readable = self
+                    .process
+                    .read(candidates.base + start, &mut buffer)
+                    .is_ok();
+
+                for candidate in index..end_index {
+                    let offset = candidates.offsets[candidate] as usize;
+                    let previous = if candidates.values.is_empty() {
+                        &self.value_bytes[..]
+                    } else {
+                        &candidates.values[candidate * size..(candidate + 1) * size]
+                    };
+                    let current = &buffer[offset - start..offset - start + size];
+
+                    let keep = readable
+                        && match &scan {
+                            NextScan::Changed => current != previous,
+                            NextScan::Unchanged => current == previous,
+                            NextScan::Increased => {
+                                value.compare(current, previous) == Some(Ordering::Greater)
+                            }
+                            NextScan::Decreased => {
+                                value.compare(current, previous) == Some(Ordering::Less)
+                            }
+                            NextScan::Equal(_) => {
+                                let expected = expected.as_deref().unwrap();
+                                value.compare(current, expected) == Some(Ordering::Equal)
+                            }
+                        };
+                    if keep {
+                        offsets.push(offset as u32);
+                        values.extend_from_slice(current);
+                    }
+                }
+                index = end_index;
+            }
+
+            offsets.shrink_to_fit();
+            values.shrink_to_fit();
+            candidates.offsets = offsets;
+            candidates.values = values;
+        }
+        self.candidates.retain(|c| !c.offsets.is_empty());
+
+        Ok(self.len())
+    }
+
+    /// The number of candidates left.
+    pub fn len(&self) -> usize {
+        self.candidates.iter().map(|c| c.offsets.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.candidates.is_empty()
+    }
+
+    /// The addresses of the candidates left, in address order.
+    pub fn addresses(&self) -> impl Iterator<Item = usize> + '_ {
+        self.candidates.iter().flat_map(|candidates| {
+            candidates
+                .offsets
+                .iter()
+                .map(|offset| candidates.base + *offset as usize)
+        })
+    }
+
+    /// The candidates left, with their values as of the most recent scan, in address order.
+    pub fn results(&self) -> impl Iterator<Item = ScanResult> + '_ {
+        let size = self.value_bytes.len();
+        self.candidates.iter().flat_map(move |candidates| {
+            candidates
+                .offsets
+                .iter()
+                .enumerate()
+                .map(move |(index, offset)| {
+                    let bytes = if candidates.values.is_empty() {
+                        &self.value_bytes[..]
+                    } else {
+                        &candidates.values[index * size..(index + 1) * size]
+                    };
+                    ScanResult {
+                        address: candidates.base + *offset as usize,
+                        value: self.value.as_ref().unwrap().with_bytes(bytes),
+                    }
+                })
+        })
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    fn scanner() -> ValueScanner {
+        ValueScanner::new(Process::current().unwrap())
+    }
+
+    #[test]
+    fn empty_and_oversized_values_are_refused() {
+        let mut scanner = scanner();
+        assert!(scanner.first_scan(ScanValue::Bytes(vec![])).is_err());
+        assert!(scanner
+            .first_scan(ScanValue::Bytes(vec![0; CHUNK_SIZE + 1]))
+            .is_err());
+        assert!(scanner.next_scan(NextScan::Changed).is_err());
+    }
+
+    #[test]
+    fn scans_narrow_down_to_a_changing_value() {
+        // A value unlikely to be anywhere else in the process
+        let mut value = Box::new(0x1a2b_3c4d_i32);
+        let address = &*value as *const i32 as usize;
+
+        let mut scanner = scanner();
+        assert!(scanner.first_scan(ScanValue::I32(*value)).unwrap() >= 1);
+        assert!(scanner.addresses().any(|a| a == address));
+
+        *value += 1;
+        std::hint::black_box(&mut value);
+        scanner.next_scan(NextScan::Increased).unwrap();
+        assert!(scanner.addresses().any(|a| a == address));
+
+        *value -= 5;
+        std::hint::black_box(&mut value);
+        scanner
+            .next_scan(NextScan::Equal(ScanValue::I32(*value)))
+            .unwrap();
+        let result = scanner.results().find(|r| r.address == address).unwrap();
+        assert_eq!(result.value, ScanValue::I32(0x1a2b_3c49));
+
+        scanner.next_scan(NextScan::Unchanged).unwrap();
+        assert!(scanner.addresses().any(|a| a == address));
+        scanner.next_scan(NextScan::Changed).unwrap();
+        assert!(!scanner.addresses().any(|a| a == address));
+    }
+
+    #[test]
+    fn strings_are_found_unaligned_and_only_compared_for_equality() {
+        let text = Box::new(*b"xvalue-scan-test-markerx");
+        let address = text.as_ptr() as usize + 1;
+
+        let mut scanner = scanner();
+        let marker = ScanValue::String("value-scan-test-marker".to_owned());
+        scanner.first_scan(marker.clone()).unwrap();
+        assert!(scanner.addresses().any(|a| a == address));
+        assert!(scanner.next_scan(NextScan::Increased).is_err());
+        assert!(scanner
+            .next_scan(NextScan::Equal(ScanValue::I32(0)))
+            .is_err());
+
+        scanner.next_scan(NextScan::Equal(marker.clone())).unwrap();
+        let result = scanner.results().find(|r| r.address == address).unwrap();
+        assert_eq!(result.value, marker);
+        std::hint::black_box(&text);
+    }
+}
@@ -1,11 +1,26 @@
-pub trait DetourBinder {
+use std::{
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+use crate::{instructions, thread_relocation::relocate_threads};
+
+/// The number of bytes `retour` writes over the start of a detoured function.
+const DETOUR_PATCH_LEN: usize = 5;
+
+pub trait DetourBinder: Send + Sync {
     fn enable(&self) -> anyhow::Result<()>;
     fn disable(&self) -> anyhow::Result<()>;
+    /// The counter of calls that are currently inside the detour, if they are being tracked.
+    fn in_flight(&self) -> Option<&'static InFlight> {
+        None
+    }
 }
 
 pub struct CompiletimeDetourBinder {
     pub enable: &'static (dyn Send + Sync + Fn() -> anyhow::Result<()>),
     pub disable: &'static (dyn Send + Sync + Fn() -> anyhow::Result<()>),
+    pub in_flight: Option<&'static InFlight>,
 }
 impl DetourBinder for CompiletimeDetourBinder {
     fn enable(&self) -> anyhow::Result<()> {
@@ -14,6 +29,9 @@ impl DetourBinder for CompiletimeDetourBinder {
     fn disable(&self) -> anyhow::Result<()> {
         (self.disable)()
     }
+    fn in_flight(&self) -> Option<&'static InFlight> {
+        self.in_flight
+    }
 }
 
 pub struct RuntimeDetourBinder {
@@ -28,3 +46,61 @@ impl DetourBinder for RuntimeDetourBinder {
         (self.disable)()
     }
 }
+
+/// Counts the calls that are currently executing a detour, so that it can be waited on to become
+/// unused before the code it runs is unloaded. `#[detour(track_calls)]` maintains one of these.
+pub struct InFlight(AtomicUsize);
+impl InFlight {
+    pub const fn new() -> InFlight {
+        InFlight(AtomicUsize::new(0))
+    }
+
+    /// Records a call entering the detour, until the returned guard is dropped.
+    pub fn enter(&self) -> InFlightGuard<'_> {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        InFlightGuard(self)
+    }
+
+    /// The number of calls currently inside the detour.
+    pub fn count(&self) -> usize {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Waits until no calls are inside the detour, returning whether that happened before
+    /// `timeout` elapsed. Calls that started before the detour was disabled can still be
+    /// running, so this should be waited on after disabling it.
+    pub fn wait_until_idle(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while self.count() != 0 {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        true
+    }
+}
+impl Default for InFlight {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Marks a call as being inside a detour until it is dropped. See [`InFlight::enter`].
+pub struct InFlightGuard<'a>(&'a InFlight);
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        self.0 .0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Waits for any suspended thread that is partway through the start of the function at
+/// `target` to move on, so that a detour can be safely enabled or disabled. Does nothing if
+/// no threads are suspended with a [`crate::ThreadSuspender`].
+///
+/// # Safety
+/// `target` must be the start of an instruction in executable memory.
+pub unsafe fn vacate_detour_target(target: usize) -> anyhow::Result<()> {
+    let len = instructions::boundary_after(target, DETOUR_PATCH_LEN)?;
+    relocate_threads(target + 1..target + len, |_| None)
+}
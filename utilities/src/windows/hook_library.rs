@@ -1,15 +1,27 @@
-use super::{
-    detour_binder::{DetourBinder, RuntimeDetourBinder},
-    patcher::Patcher,
-};
+use std::time::{Duration, Instant};
+
+use super::detour_binder::{DetourBinder, InFlight, RuntimeDetourBinder};
+use crate::Patcher;
 
 use anyhow::Context;
 
+/// How long disabling a library waits for calls to leave its detours by default.
+const DEFAULT_IN_FLIGHT_TIMEOUT: Duration = Duration::from_secs(5);
+
+enum LibraryPatch {
+    Exact(Vec<u8>),
+    Code(Vec<u8>),
+    Padded(Vec<u8>),
+    Nop(usize),
+}
+
 #[allow(clippy::type_complexity)]
 pub struct HookLibrary {
     static_binders: Vec<&'static dyn DetourBinder>,
     runtime_binders: Vec<Box<dyn DetourBinder>>,
-    patches: Vec<(usize, Vec<u8>)>,
+    patches: Vec<(usize, LibraryPatch)>,
+    in_flight: Vec<&'static InFlight>,
+    in_flight_timeout: Duration,
 }
 impl HookLibrary {
     // builder functions
@@ -18,6 +30,8 @@ impl HookLibrary {
             static_binders: vec![],
             runtime_binders: vec![],
             patches: vec![],
+            in_flight: vec![],
+            in_flight_timeout: DEFAULT_IN_FLIGHT_TIMEOUT,
         }
     }
     pub fn with_static_binder(mut self, binder: &'static dyn DetourBinder) -> Self {
@@ -47,6 +61,15 @@ impl HookLibrary {
             }),
         }))
     }
+    #[cfg(target_arch = "x86_64")]
+    pub fn with_mid_hook(self, hook: crate::mid_hook::MidHook) -> Self {
+        let hook = std::sync::Arc::new(hook);
+        let disable_hook = hook.clone();
+        self.with_runtime_binder(Box::new(RuntimeDetourBinder {
+            enable: Box::new(move || unsafe { hook.enable() }),
+            disable: Box::new(move || unsafe { disable_hook.disable() }),
+        }))
+    }
     pub fn with_callbacks(
         self,
         enable: impl Fn() -> anyhow::Result<()> + Send + Sync + 'static,
@@ -57,8 +80,43 @@ impl HookLibrary {
             disable: Box::new(disable),
         }))
     }
+    /// Waits for the calls counted by `in_flight` to finish when the library is disabled, as is
+    /// done for detours declared with `#[detour(track_calls)]`.
+    pub fn with_in_flight(mut self, in_flight: &'static InFlight) -> Self {
+        self.in_flight.push(in_flight);
+        self
+    }
+    /// Sets how long disabling the library waits for calls to leave its detours, which is five
+    /// seconds by default.
+    pub fn with_in_flight_timeout(mut self, timeout: Duration) -> Self {
+        self.in_flight_timeout = timeout;
+        self
+    }
     pub fn with_patch(mut self, address: usize, bytes: &[u8]) -> Self {
-        self.patches.push((address, bytes.to_owned()));
+        self.patches
+            .push((address, LibraryPatch::Exact(bytes.to_owned())));
+        self
+    }
+    /// Like [`HookLibrary::with_patch`], but enabling the library fails if the patch would end
+    /// partway through an instruction.
+    pub fn with_code_patch(mut self, address: usize, bytes: &[u8]) -> Self {
+        self.patches
+            .push((address, LibraryPatch::Code(bytes.to_owned())));
+        self
+    }
+    /// Like [`HookLibrary::with_patch`], but pads `bytes` with NOPs up to the end of the last
+    /// instruction they touch, as [`Patcher::patch_padded`] does. `address` must be the start of an
+    /// instruction; enabling the library fails if the code there cannot be read or decoded.
+    pub fn with_padded_patch(mut self, address: usize, bytes: &[u8]) -> Self {
+        self.patches
+            .push((address, LibraryPatch::Padded(bytes.to_owned())));
+        self
+    }
+    /// Replaces `count` whole instructions at `address` with NOPs when the library is enabled, as
+    /// [`Patcher::nop_instructions`] does. `address` must be the start of an instruction; enabling
+    /// the library fails if the code there cannot be read or decoded.
+    pub fn with_nop_instructions(mut self, address: usize, count: usize) -> Self {
+        self.patches.push((address, LibraryPatch::Nop(count)));
         self
     }
 
@@ -69,7 +127,17 @@ impl HookLibrary {
             }
             for (address, patch) in &self.patches {
                 unsafe {
-                    patcher.patch(*address, patch);
+                    match patch {
+                        LibraryPatch::Exact(bytes) => patcher.patch(*address, bytes),
+                        LibraryPatch::Code(bytes) => patcher.patch_code(*address, bytes),
+                        LibraryPatch::Padded(bytes) => {
+                            patcher.patch_padded(*address, bytes).map(|_| ())
+                        }
+                        LibraryPatch::Nop(count) => {
+                            patcher.nop_instructions(*address, *count).map(|_| ())
+                        }
+                    }
+                    .context("failed to patch")?;
                 }
             }
         } else {
@@ -81,6 +149,7 @@ impl HookLibrary {
             for binder in self.binders() {
                 binder.disable()?;
             }
+            self.wait_for_calls()?;
         }
         Ok(())
     }
@@ -92,6 +161,27 @@ impl HookLibrary {
             .map(|b| *b as &dyn DetourBinder)
             .chain(self.runtime_binders.iter().map(|b| b.as_ref()))
     }
+
+    /// Waits for calls that entered the library's tracked detours before they were disabled to
+    /// return, for at most the library's timeout in total. This will time out if it is called
+    /// from within one of them.
+    fn wait_for_calls(&self) -> anyhow::Result<()> {
+        let deadline = Instant::now() + self.in_flight_timeout;
+        let counters = self
+            .binders()
+            .filter_map(|binder| binder.in_flight())
+            .chain(self.in_flight.iter().copied());
+        for counter in counters {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if !counter.wait_until_idle(remaining) {
+                anyhow::bail!(
+                    "timed out waiting for {} calls to leave a detour",
+                    counter.count()
+                );
+            }
+        }
+        Ok(())
+    }
 }
 impl Default for HookLibrary {
     fn default() -> Self {
@@ -103,6 +193,7 @@ impl Drop for HookLibrary {
         for binder in self.binders() {
             let _ = binder.disable();
         }
+        let _ = self.wait_for_calls();
     }
 }
 
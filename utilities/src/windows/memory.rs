@@ -0,0 +1,272 @@
+use std::{
+    ffi::{c_void, OsString},
+    mem,
+    ops::Range,
+    os::windows::ffi::OsStringExt,
+    path::PathBuf,
+};
+
+use anyhow::Context;
+use windows::Win32::{
+    Foundation::HMODULE,
+    System::{
+        Diagnostics::Debug::{FlushInstructionCache, ReadProcessMemory, WriteProcessMemory},
+        LibraryLoader::GetModuleFileNameW,
+        Memory::{
+            VirtualAlloc, VirtualFree, VirtualProtect, VirtualQuery, MEMORY_BASIC_INFORMATION,
+            MEM_COMMIT, MEM_FREE, MEM_IMAGE, MEM_MAPPED, MEM_RELEASE, MEM_RESERVE, PAGE_EXECUTE,
+            PAGE_EXECUTE_READ, PAGE_EXECUTE_READWRITE, PAGE_EXECUTE_WRITECOPY, PAGE_GUARD,
+            PAGE_PROTECTION_FLAGS, PAGE_READONLY, PAGE_READWRITE, PAGE_WRITECOPY,
+        },
+        SystemInformation::{GetSystemInfo, SYSTEM_INFO},
+        Threading::GetCurrentProcess,
+    },
+};
+
+use crate::memory::{self, Protection, Region, RegionKind, RegionState, NEAR_RANGE};
+
+/// Allocates `size` bytes of executable memory within [`NEAR_RANGE`] of `target`.
+pub fn allocate_near(target: usize, size: usize) -> anyhow::Result<usize> {
+    let mut info = SYSTEM_INFO::default();
+    unsafe { GetSystemInfo(&mut info) };
+    let granularity = info.dwAllocationGranularity as usize;
+    let min_address =
+        (info.lpMinimumApplicationAddress as usize).max(target.saturating_sub(NEAR_RANGE));
+    let max_address =
+        (info.lpMaximumApplicationAddress as usize).min(target.saturating_add(NEAR_RANGE));
+
+    // Search outwards from the target, alternating between lower and higher addresses, so that
+    // we end up with the closest free allocation.
+    let start = target & !(granularity - 1);
+    for distance in (0..NEAR_RANGE).step_by(granularity) {
+        let below = start.checked_sub(distance).filter(|a| *a >= min_address);
+        let above = start
+            .checked_add(distance)
+            .filter(|a| a.saturating_add(size) <= max_address);
+        if below.is_none() && above.is_none() {
+            break;
+        }
+
+        for candidate in below.into_iter().chain(above) {
+            if let Some(address) = unsafe { try_allocate(candidate, size) } {
+                return Ok(address);
+            }
+        }
+    }
+
+    anyhow::bail!("failed to find free memory within range of {target:#x}")
+}
+
+unsafe fn try_allocate(address: usize, size: usize) -> Option<usize> {
+    let mut info = MEMORY_BASIC_INFORMATION::default();
+    let info_size = mem::size_of::<MEMORY_BASIC_INFORMATION>();
+    if VirtualQuery(Some(address as _), &mut info, info_size) == 0 {
+        return None;
+    }
+    if info.State != MEM_FREE || (info.BaseAddress as usize + info.RegionSize) < address + size {
+        return None;
+    }
+
+    let ptr = VirtualAlloc(
+        Some(address as _),
+        size,
+        MEM_COMMIT | MEM_RESERVE,
+        PAGE_EXECUTE_READWRITE,
+    );
+    (!ptr.is_null()).then_some(ptr as usize)
+}
+
+/// # Safety
+/// `address` must have been returned by [`allocate_near`], and nothing may still be using it.
+pub unsafe fn free(address: usize, _size: usize) -> anyhow::Result<()> {
+    VirtualFree(address as _, 0, MEM_RELEASE)?;
+    Ok(())
+}
+
+/// Writes `bytes` to `ptr`, temporarily making the memory writable if it isn't already.
+///
+/// # Safety
+/// `ptr` must point to `bytes.len()` bytes of mapped memory that nothing else is writing to.
+pub unsafe fn safe_write(ptr: *mut u8, bytes: &[u8]) -> anyhow::Result<()> {
+    let mut old = PAGE_PROTECTION_FLAGS::default();
+    let range = ptr as usize..ptr as usize + bytes.len();
+
+    // `VirtualProtect` only reports the protection of the first page, so look up every region
+    // we touch to put each one back the way it was.
+    let regions = memory::regions_in(range.clone())?;
+    VirtualProtect(ptr as _, bytes.len(), PAGE_EXECUTE_READWRITE, &mut old)?;
+    std::slice::from_raw_parts_mut(ptr, bytes.len()).copy_from_slice(bytes);
+    for region in regions {
+        let start = region.base.max(range.start);
+        let end = region.range().end.min(range.end);
+        VirtualProtect(
+            start as _,
+            end - start,
+            PAGE_PROTECTION_FLAGS(region.native_protection),
+            &mut old,
+        )?;
+    }
+    FlushInstructionCache(GetCurrentProcess(), Some(ptr as _), bytes.len())?;
+    Ok(())
+}
+
+/// Returns the region containing `address`.
+pub fn query(address: usize) -> anyhow::Result<Region> {
+    let mut info = MEMORY_BASIC_INFORMATION::default();
+    let info_size = mem::size_of::<MEMORY_BASIC_INFORMATION>();
+    if unsafe { VirtualQuery(Some(address as _), &mut info, info_size) } == 0 {
+        return Err(windows::core::Error::from_win32())
+            .with_context(|| format!("failed to query memory at {address:#x}"));
+    }
+    Ok(region_from_info(&info, |module| {
+        let mut buf = [0u16; 1024];
+        let size = unsafe { GetModuleFileNameW(module, &mut buf) } as usize;
+        (size > 0).then(|| PathBuf::from(OsString::from_wide(&buf[..size])))
+    }))
+}
+
+/// Returns the regions of the process that are committed or reserved, in address order.
+pub fn regions() -> anyhow::Result<impl Iterator<Item = Region>> {
+    let mut info = SYSTEM_INFO::default();
+    unsafe { GetSystemInfo(&mut info) };
+    let mut address = info.lpMinimumApplicationAddress as usize;
+    let max_address = info.lpMaximumApplicationAddress as usize;
+
+    let regions = std::iter::from_fn(move || {
+        while address < max_address {
+            let region = query(address).ok()?;
+            address = region.range().end;
+            if region.state != RegionState::Free {
+                return Some(region);
+            }
+        }
+        None
+    });
+    Ok(regions)
+}
+
+/// Describes the region in `info`, using `module_path` to look up the path of the module that
+/// owns it, if any.
+pub(crate) fn region_from_info(
+    info: &MEMORY_BASIC_INFORMATION,
+    module_path: impl FnOnce(HMODULE) -> Option<PathBuf>,
+) -> Region {
+    let state = if info.State == MEM_COMMIT {
+        RegionState::Committed
+    } else if info.State == MEM_RESERVE {
+        RegionState::Reserved
+    } else {
+        RegionState::Free
+    };
+    let kind = (state == RegionState::Committed).then(|| {
+        if info.Type == MEM_IMAGE {
+            RegionKind::Image
+        } else if info.Type == MEM_MAPPED {
+            RegionKind::Mapped
+        } else {
+            RegionKind::Private
+        }
+    });
+
+    // The modifiers, such as `PAGE_GUARD`, sit above the low byte.
+    let protect = info.Protect;
+    let base = PAGE_PROTECTION_FLAGS(protect.0 & 0xFF);
+    let is_any = |flags: &[PAGE_PROTECTION_FLAGS]| flags.contains(&base);
+    let protection = Protection {
+        read: is_any(&[
+            PAGE_READONLY,
+            PAGE_READWRITE,
+            PAGE_WRITECOPY,
+            PAGE_EXECUTE_READ,
+            PAGE_EXECUTE_READWRITE,
+            PAGE_EXECUTE_WRITECOPY,
+        ]),
+        write: is_any(&[
+            PAGE_READWRITE,
+            PAGE_WRITECOPY,
+            PAGE_EXECUTE_READWRITE,
+            PAGE_EXECUTE_WRITECOPY,
+        ]),
+        execute: is_any(&[
+            PAGE_EXECUTE,
+            PAGE_EXECUTE_READ,
+            PAGE_EXECUTE_READWRITE,
+            PAGE_EXECUTE_WRITECOPY,
+        ]),
+        guard: (protect & PAGE_GUARD).0 != 0,
+    };
+
+    let module = (info.Type == MEM_IMAGE)
+        .then(|| module_path(HMODULE(info.AllocationBase)))
+        .flatten();
+
+    Region {
+        base: info.BaseAddress as usize,
+        size: info.RegionSize,
+        state,
+        kind,
+        protection,
+        module,
+        native_protection: protect.0,
+    }
+}
+
+/// Copies `buffer.len()` bytes from `address` into `buffer`, failing if any of them are not
+/// committed and readable.
+pub fn read(address: usize, buffer: &mut [u8]) -> anyhow::Result<()> {
+    // Going through the kernel turns a fault into an error, even if the memory is freed by
+    // another thread while it is being read.
+    unsafe {
+        ReadProcessMemory(
+            GetCurrentProcess(),
+            address as *const c_void,
+            buffer.as_mut_ptr() as *mut c_void,
+            buffer.len(),
+            None,
+        )
+    }
+    .with_context(|| {
+        format!(
+            "{:#x}..{:#x} is not readable",
+            address,
+            address + buffer.len()
+        )
+    })
+}
+
+/// Copies `bytes` to `address`, failing if any of the destination is not committed and
+/// writable.
+pub fn write(address: usize, bytes: &[u8]) -> anyhow::Result<()> {
+    // `WriteProcessMemory` would unprotect read-only pages by itself, which is left to
+    // `safe_write`
+    check_writable(address..address + bytes.len())?;
+    unsafe {
+        WriteProcessMemory(
+            GetCurrentProcess(),
+            address as *const c_void,
+            bytes.as_ptr() as *const c_void,
+            bytes.len(),
+            None,
+        )
+    }
+    .with_context(|| {
+        format!(
+            "{:#x}..{:#x} is not writable",
+            address,
+            address + bytes.len()
+        )
+    })
+}
+
+fn check_writable(range: Range<usize>) -> anyhow::Result<()> {
+    for region in memory::regions_in(range.clone())? {
+        let address = region.base.max(range.start);
+        if region.state != RegionState::Committed {
+            anyhow::bail!("{address:#x} is not committed");
+        }
+        if !region.is_writable() {
+            anyhow::bail!("{address:#x} is not writable");
+        }
+    }
+    Ok(())
+}
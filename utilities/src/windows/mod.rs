@@ -2,8 +2,8 @@ pub mod detour_binder;
 pub mod hook_library;
 pub mod module;
 
-mod patcher;
+pub(crate) mod memory;
+pub(crate) mod process;
 mod thread_suspender;
 
-pub use patcher::Patcher;
-pub use thread_suspender::ThreadSuspender;
+pub use thread_suspender::{SuspendedThread, ThreadSuspender};
@@ -1,5 +1,7 @@
 use std::{collections, ffi::OsString, io, mem, os::windows::ffi::OsStringExt, path::Path, slice};
 
+use crate::scan::{self, CacheKey};
+
 use windows::Win32::{
     Foundation::HMODULE,
     System::{
@@ -11,13 +13,6 @@ use windows::Win32::{
 
 use anyhow::anyhow;
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
-enum CacheKey {
-    Regular(String),
-    RelativeCallsite(String),
-    AfterPtr(String, usize),
-}
-
 #[allow(dead_code)]
 struct SerializedCache {
     hash: u64,
@@ -138,8 +133,7 @@ impl Module {
         let offset = if let Some(offset) = self.cache.get(&CacheKey::Regular(pattern.to_owned())) {
             *offset
         } else {
-            patternscan::scan_first_match(io::Cursor::new(self.as_bytes()), pattern)?
-                .ok_or_else(|| anyhow!("failed to scan"))?
+            scan::find_first(self.as_bytes(), pattern)?
         };
 
         self.cache
@@ -159,8 +153,7 @@ impl Module {
         {
             *offset
         } else {
-            let offset = patternscan::scan_first_match(io::Cursor::new(self.as_bytes()), pattern)?
-                .ok_or_else(|| anyhow!("failed to scan"))?;
+            let offset = scan::find_first(self.as_bytes(), pattern)?;
             let base = self.rel_to_abs_addr(offset + addr_offset);
             let call = unsafe { slice::from_raw_parts(base as *const u8, 4) };
             let offset = i32::from_ne_bytes(call.try_into()?) + 4;
@@ -184,8 +177,7 @@ impl Module {
         } else {
             let slice = &self.as_bytes()[base_offset..];
 
-            let offset_from_base = patternscan::scan_first_match(io::Cursor::new(slice), pattern)?
-                .ok_or_else(|| anyhow!("failed to scan"))?;
+            let offset_from_base = scan::find_first(slice, pattern)?;
 
             base_offset + offset_from_base
         };
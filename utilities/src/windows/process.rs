@@ -0,0 +1,218 @@
+use std::{ffi::c_void, mem, path::PathBuf, sync::Arc};
+
+use anyhow::Context;
+use windows::Win32::{
+    Foundation::{CloseHandle, HANDLE, HMODULE},
+    System::{
+        Diagnostics::Debug::{ReadProcessMemory, WriteProcessMemory},
+        Memory::{VirtualQueryEx, MEMORY_BASIC_INFORMATION},
+        ProcessStatus::{
+            K32EnumProcessModulesEx, K32GetModuleFileNameExW, K32GetModuleInformation,
+            LIST_MODULES_ALL, MODULEINFO,
+        },
+        SystemInformation::{GetSystemInfo, SYSTEM_INFO},
+        Threading::{
+            GetCurrentProcess, GetCurrentProcessId, OpenProcess, PROCESS_QUERY_INFORMATION,
+            PROCESS_VM_OPERATION, PROCESS_VM_READ, PROCESS_VM_WRITE,
+        },
+    },
+};
+
+use crate::{
+    memory::{Region, RegionState},
+    process::ModuleInfo,
+    windows::memory,
+};
+
+struct Handle {
+    handle: HANDLE,
+    // The pseudo-handle for the current process does not need to be closed.
+    owned: bool,
+}
+// Process handles can be used from any thread.
+unsafe impl Send for Handle {}
+unsafe impl Sync for Handle {}
+impl Drop for Handle {
+    fn drop(&mut self) {
+        if self.owned {
+            unsafe {
+                let _ = CloseHandle(self.handle);
+            }
+        }
+    }
+}
+
+/// A process whose memory can be read and written, which may be the current process.
+///
+/// Cloning a `Process` shares the underlying handle.
+#[derive(Clone)]
+pub struct Process {
+    pid: u32,
+    handle: Arc<Handle>,
+}
+impl Process {
+    /// Opens the process with the given ID for reading and writing memory.
+    pub fn open(pid: u32) -> anyhow::Result<Process> {
+        let handle = unsafe {
+            OpenProcess(
+                PROCESS_VM_READ
+                    | PROCESS_VM_WRITE
+                    | PROCESS_VM_OPERATION
+                    | PROCESS_QUERY_INFORMATION,
+                false,
+                pid,
+            )
+            .with_context(|| format!("failed to open process {pid}"))?
+        };
+        Ok(Process {
+            pid,
+            handle: Arc::new(Handle {
+                handle,
+                owned: true,
+            }),
+        })
+    }
+
+    /// Returns the current process. This never fails on Windows, as the pseudo-handle is used,
+    /// but returns a `Result` to match Linux.
+    pub fn current() -> anyhow::Result<Process> {
+        Ok(Process {
+            pid: unsafe { GetCurrentProcessId() },
+            handle: Arc::new(Handle {
+                handle: unsafe { GetCurrentProcess() },
+                owned: false,
+            }),
+        })
+    }
+
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+
+    pub fn handle(&self) -> HANDLE {
+        self.handle.handle
+    }
+
+    /// Fills `buffer` with the bytes at `address`.
+    pub fn read(&self, address: usize, buffer: &mut [u8]) -> anyhow::Result<()> {
+        let mut read = 0;
+        unsafe {
+            ReadProcessMemory(
+                self.handle(),
+                address as *const c_void,
+                buffer.as_mut_ptr() as *mut c_void,
+                buffer.len(),
+                Some(&mut read),
+            )
+        }
+        .with_context(|| format!("failed to read {} bytes at {address:#x}", buffer.len()))?;
+        if read != buffer.len() {
+            anyhow::bail!("only read {read} of {} bytes at {address:#x}", buffer.len());
+        }
+        Ok(())
+    }
+
+    /// Writes `bytes` to `address`.
+    pub fn write(&self, address: usize, bytes: &[u8]) -> anyhow::Result<()> {
+        let mut written = 0;
+        unsafe {
+            WriteProcessMemory(
+                self.handle(),
+                address as *const c_void,
+                bytes.as_ptr() as *const c_void,
+                bytes.len(),
+                Some(&mut written),
+            )
+        }
+        .with_context(|| format!("failed to write {} bytes at {address:#x}", bytes.len()))?;
+        if written != bytes.len() {
+            anyhow::bail!(
+                "only wrote {written} of {} bytes at {address:#x}",
+                bytes.len()
+            );
+        }
+        Ok(())
+    }
+
+    /// Returns the regions of the process that are committed or reserved, in address order.
+    pub fn regions(&self) -> anyhow::Result<Vec<Region>> {
+        let mut system_info = SYSTEM_INFO::default();
+        unsafe { GetSystemInfo(&mut system_info) };
+        let mut address = system_info.lpMinimumApplicationAddress as usize;
+        let max_address = system_info.lpMaximumApplicationAddress as usize;
+
+        let mut regions = vec![];
+        while address < max_address {
+            let mut info = MEMORY_BASIC_INFORMATION::default();
+            let info_size = mem::size_of::<MEMORY_BASIC_INFORMATION>();
+            if unsafe { VirtualQueryEx(self.handle(), Some(address as _), &mut info, info_size) }
+                == 0
+            {
+                break;
+            }
+            let region = memory::region_from_info(&info, |module| {
+                let mut buf = [0u16; 1024];
+                let size =
+                    unsafe { K32GetModuleFileNameExW(self.handle(), module, &mut buf) } as usize;
+                (size > 0).then(|| PathBuf::from(String::from_utf16_lossy(&buf[..size])))
+            });
+            address = region.range().end;
+            if region.state != RegionState::Free {
+                regions.push(region);
+            }
+        }
+        Ok(regions)
+    }
+
+    pub(crate) fn module_infos(&self) -> anyhow::Result<Vec<ModuleInfo>> {
+        let process = self.handle();
+        let hmodule_size = mem::size_of::<HMODULE>() as u32;
+
+        // The module list can grow between calls, so keep going until it fits.
+        let mut modules = vec![];
+        loop {
+            let mut needed = 0;
+            unsafe {
+                K32EnumProcessModulesEx(
+                    process,
+                    modules.as_mut_ptr(),
+                    hmodule_size * modules.len() as u32,
+                    &mut needed,
+                    LIST_MODULES_ALL.0,
+                )
+                .ok()
+                .with_context(|| format!("failed to enumerate modules of process {}", self.pid))?;
+            }
+            let count = (needed / hmodule_size) as usize;
+            if count <= modules.len() {
+                modules.truncate(count);
+                break;
+            }
+            modules.resize(count, HMODULE::default());
+        }
+
+        modules
+            .into_iter()
+            .map(|module| {
+                let mut info = MODULEINFO::default();
+                unsafe {
+                    K32GetModuleInformation(
+                        process,
+                        module,
+                        &mut info,
+                        mem::size_of::<MODULEINFO>() as u32,
+                    )
+                    .ok()
+                    .context("failed to get module information")?;
+                }
+                let mut buf = [0u16; 1024];
+                let size = unsafe { K32GetModuleFileNameExW(process, module, &mut buf) } as usize;
+                Ok(ModuleInfo {
+                    path: String::from_utf16_lossy(&buf[..size]),
+                    base: info.lpBaseOfDll as usize,
+                    size: info.SizeOfImage as usize,
+                })
+            })
+            .collect()
+    }
+}
@@ -1,24 +1,115 @@
-use std::mem;
+use std::{
+    mem,
+    sync::{Mutex, MutexGuard},
+    time::Duration,
+};
 
 use anyhow::Context;
 use windows::Win32::{
     Foundation::{CloseHandle, HANDLE},
     System::{
-        Diagnostics::ToolHelp::{
-            CreateToolhelp32Snapshot, Thread32First, Thread32Next, TH32CS_SNAPTHREAD,
+        Diagnostics::{
+            Debug::{GetThreadContext, SetThreadContext, CONTEXT},
+            ToolHelp::{CreateToolhelp32Snapshot, Thread32First, Thread32Next, TH32CS_SNAPTHREAD},
         },
         Threading::{
-            GetCurrentProcessId, GetCurrentThreadId, OpenThread, ResumeThread, SuspendThread,
-            THREAD_ALL_ACCESS,
+            GetCurrentProcessId, GetCurrentThreadId, OpenThread, ResumeThread, Sleep,
+            SuspendThread, THREAD_ALL_ACCESS,
         },
     },
 };
 
+#[cfg(target_arch = "x86_64")]
+use windows::Win32::System::Diagnostics::Debug::CONTEXT_CONTROL_AMD64 as CONTEXT_CONTROL;
+#[cfg(target_arch = "x86")]
+use windows::Win32::System::Diagnostics::Debug::CONTEXT_CONTROL_X86 as CONTEXT_CONTROL;
+
+// `GetThreadContext` requires the context to be 16-byte aligned on x86-64.
+#[repr(C, align(16))]
+struct AlignedContext(CONTEXT);
+
+/// A thread that has been suspended by a [`ThreadSuspender`], along with its register state.
+pub struct SuspendedThread {
+    id: u32,
+    handle: HANDLE,
+    context: Box<AlignedContext>,
+}
+// Thread handles can be used from any thread.
+unsafe impl Send for SuspendedThread {}
+impl SuspendedThread {
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn handle(&self) -> HANDLE {
+        self.handle
+    }
+
+    /// The thread's control registers, as of when it was suspended.
+    pub fn context(&self) -> &CONTEXT {
+        &self.context.0
+    }
+
+    pub fn instruction_pointer(&self) -> usize {
+        #[cfg(target_arch = "x86_64")]
+        return self.context.0.Rip as usize;
+        #[cfg(target_arch = "x86")]
+        return self.context.0.Eip as usize;
+    }
+
+    /// Moves the thread to `address`; it will continue from there when resumed.
+    pub fn set_instruction_pointer(&mut self, address: usize) -> anyhow::Result<()> {
+        #[cfg(target_arch = "x86_64")]
+        {
+            self.context.0.Rip = address as u64;
+        }
+        #[cfg(target_arch = "x86")]
+        {
+            self.context.0.Eip = address as u32;
+        }
+        unsafe { SetThreadContext(self.handle, &self.context.0) }
+            .with_context(|| format!("failed to set context of thread {}", self.id))
+    }
+
+    fn capture_context(&mut self) -> anyhow::Result<()> {
+        self.context.0.ContextFlags = CONTEXT_CONTROL;
+        unsafe { GetThreadContext(self.handle, &mut self.context.0) }
+            .with_context(|| format!("failed to get context of thread {}", self.id))
+    }
+}
+
+struct Suspension {
+    threads: Vec<SuspendedThread>,
+    depth: usize,
+}
+
+// The threads are tracked globally so that code rewriting memory can check whether a
+// suspended thread is in the way, without needing to be handed the suspender.
+static SUSPENSION: Mutex<Option<Suspension>> = Mutex::new(None);
+
+fn suspension() -> MutexGuard<'static, Option<Suspension>> {
+    SUSPENSION.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Suspends every other thread in the process until dropped.
+///
+/// Suspenders can be nested; the threads are resumed when the outermost one is dropped.
 pub struct ThreadSuspender {
-    threads: Vec<HANDLE>,
+    _private: (),
 }
 impl ThreadSuspender {
     pub fn new() -> anyhow::Result<Self> {
+        let mut suspension = suspension();
+        match suspension.as_mut() {
+            Some(suspension) => suspension.depth += 1,
+            None => {
+                let threads = Self::suspend(Self::open_threads()?);
+                *suspension = Some(Suspension { threads, depth: 1 });
+            }
+        }
+        Ok(Self { _private: () })
+    }
+    fn open_threads() -> anyhow::Result<Vec<SuspendedThread>> {
         let process_id = unsafe { GetCurrentProcessId() };
         let handle = unsafe {
             CreateToolhelp32Snapshot(TH32CS_SNAPTHREAD, process_id)
@@ -46,46 +137,94 @@ impl ThreadSuspender {
         }
 
         let thread_id = unsafe { GetCurrentThreadId() };
-        let threads: Vec<HANDLE> = from_snapshot(handle, Thread32First, Thread32Next)
+        let threads = from_snapshot(handle, Thread32First, Thread32Next)
             .iter()
             .filter(|thread| {
                 thread.th32OwnerProcessID == process_id && thread.th32ThreadID != thread_id
             })
-            .map(|thread| unsafe { OpenThread(THREAD_ALL_ACCESS, false, thread.th32ThreadID) })
-            .collect::<Result<Vec<_>, _>>()?;
+            .map(|thread| {
+                Ok(SuspendedThread {
+                    id: thread.th32ThreadID,
+                    handle: unsafe { OpenThread(THREAD_ALL_ACCESS, false, thread.th32ThreadID)? },
+                    context: Box::new(AlignedContext(CONTEXT::default())),
+                })
+            })
+            .collect::<windows::core::Result<Vec<_>>>();
+        unsafe { CloseHandle(handle)? };
 
-        Self::suspend(&threads);
-        Ok(Self { threads })
+        Ok(threads?)
     }
-    fn suspend(threads: &[HANDLE]) {
+    fn suspend(threads: Vec<SuspendedThread>) -> Vec<SuspendedThread> {
         #[cfg(feature = "debug-console")]
         println!("Suspended {} threads", threads.len());
-        for handle in threads {
-            unsafe { SuspendThread(*handle) };
+        let mut suspended = Vec::with_capacity(threads.len());
+        for mut thread in threads {
+            unsafe { SuspendThread(thread.handle) };
+            // Getting the context also waits for the suspension to take effect. If it fails,
+            // the thread has most likely exited.
+            if thread.capture_context().is_ok() {
+                suspended.push(thread);
+            } else {
+                Self::resume(std::slice::from_ref(&thread));
+                Self::close(std::slice::from_ref(&thread));
+            }
         }
+        suspended
     }
-    fn resume(threads: &[HANDLE]) {
+    fn resume(threads: &[SuspendedThread]) {
         #[cfg(feature = "debug-console")]
         println!("Resumed {} threads", threads.len());
-        for handle in threads {
-            unsafe { ResumeThread(*handle) };
+        for thread in threads {
+            unsafe { ResumeThread(thread.handle) };
         }
     }
-    fn close(threads: &[HANDLE]) {
+    fn close(threads: &[SuspendedThread]) {
         #[cfg(feature = "debug-console")]
         println!("Closed {} threads", threads.len());
-        for handle in threads {
-            unsafe { CloseHandle(*handle).unwrap() };
+        for thread in threads {
+            unsafe { CloseHandle(thread.handle).unwrap() };
         }
     }
     pub fn for_block<T>(mut f: impl FnMut() -> anyhow::Result<T>) -> anyhow::Result<T> {
         let _suspender = ThreadSuspender::new()?;
         f()
     }
+
+    /// Calls `f` with the threads that are currently suspended.
+    pub fn with_threads<T>(&self, f: impl FnOnce(&mut [SuspendedThread]) -> T) -> T {
+        Self::with_suspended(f).expect("threads should be suspended")
+    }
+
+    /// Calls `f` with the threads that are currently suspended, if there are any.
+    pub(crate) fn with_suspended<T>(f: impl FnOnce(&mut [SuspendedThread]) -> T) -> Option<T> {
+        suspension()
+            .as_mut()
+            .map(|suspension| f(&mut suspension.threads))
+    }
+
+    /// Resumes the suspended threads for `duration` so that they can make progress, then
+    /// suspends them again.
+    pub(crate) fn let_threads_run(duration: Duration) {
+        let mut suspension = suspension();
+        let Some(suspension) = suspension.as_mut() else {
+            return;
+        };
+        Self::resume(&suspension.threads);
+        unsafe { Sleep(duration.as_millis() as u32) };
+        suspension.threads = Self::suspend(mem::take(&mut suspension.threads));
+    }
 }
 impl Drop for ThreadSuspender {
     fn drop(&mut self) {
-        Self::resume(&self.threads);
-        Self::close(&self.threads);
+        let mut suspension = suspension();
+        let Some(state) = suspension.as_mut() else {
+            return;
+        };
+        state.depth -= 1;
+        if state.depth == 0 {
+            let state = suspension.take().unwrap();
+            Self::resume(&state.threads);
+            Self::close(&state.threads);
+        }
     }
 }